@@ -0,0 +1,149 @@
+//! This decides which client addresses may talk to us
+//!
+//! Rules either allow or deny a network (in CIDR notation, IPv4 or IPv6) or
+//! everyone (`all`), and are checked in order, with the first that matches
+//! the client deciding. Clients no rule matches are allowed.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Addresses a rule applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Network {
+    All,
+    Cidr { addr: IpAddr, prefix_len: u8 },
+}
+
+impl FromStr for Network {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Self::All);
+        }
+
+        // A single address is a network of its own
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => bits,
+            len => len.parse().ok().filter(|len| *len <= bits).ok_or(())?,
+        };
+
+        Ok(Self::Cidr { addr, prefix_len })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let Self::Cidr { addr, prefix_len } = *self else {
+            return true;
+        };
+
+        // IPv4 clients of an IPv6 socket show up as IPv4-mapped addresses
+        let (net, ip, bits) = match (addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        prefix_len == 0 || (net ^ ip) >> (bits - prefix_len) == 0
+    }
+}
+
+/// Allows or denies clients in a network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rule {
+    pub allow: bool,
+    pub network: Network,
+}
+
+/// Parses the whitespace-separated networks of an `allow` or `deny` setting into rules.
+pub fn parse_rules(networks: &str, allow: bool) -> Result<Vec<Rule>, ()> {
+    let rules = networks
+        .split_whitespace()
+        .map(|network| {
+            Ok(Rule {
+                allow,
+                network: network.parse()?,
+            })
+        })
+        .collect::<Result<Vec<_>, ()>>()?;
+
+    if rules.is_empty() {
+        return Err(());
+    }
+    Ok(rules)
+}
+
+/// Parses an `allow` or `deny` setting in a path section, like `deny 10.0.0.0/8`.
+pub fn parse_path_rules(value: &str) -> Result<Vec<Rule>, ()> {
+    let (action, networks) = value.trim().split_once(char::is_whitespace).ok_or(())?;
+    match action {
+        "allow" => parse_rules(networks, true),
+        "deny" => parse_rules(networks, false),
+        _ => Err(()),
+    }
+}
+
+/// Returns true if `rules` let the client at `ip` in.
+pub fn permits(rules: &[Rule], ip: IpAddr) -> bool {
+    rules
+        .iter()
+        .find(|rule| rule.network.contains(ip))
+        .is_none_or(|rule| rule.allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network() {
+        let network = |s: &str| s.parse::<Network>().unwrap();
+
+        assert!(network("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(network("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!network("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(network("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(network("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(!network("10.0.0.0/8").contains(ip("::1")));
+        assert!(network("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(!network("fd00::/8").contains(ip("fe80::1")));
+        assert!(network("all").contains(ip("::1")));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("fd00::/129".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("everyone".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_permits() {
+        let mut rules = parse_rules("10.0.0.0/8 fd00::/8", true).unwrap();
+        rules.extend(parse_path_rules("deny all").unwrap());
+
+        assert!(permits(&rules, ip("10.20.30.40")));
+        assert!(permits(&rules, ip("fd00::1")));
+        assert!(!permits(&rules, ip("203.0.113.9")));
+        assert!(permits(&[], ip("203.0.113.9")));
+
+        // First match wins
+        let rules = [
+            parse_path_rules("deny 10.0.0.1").unwrap(),
+            parse_path_rules("allow 10.0.0.0/8").unwrap(),
+        ]
+        .concat();
+        assert!(!permits(&rules, ip("10.0.0.1")));
+        assert!(permits(&rules, ip("10.0.0.2")));
+
+        assert!(parse_rules("", true).is_err());
+        assert!(parse_path_rules("permit all").is_err());
+        assert!(parse_path_rules("allow").is_err());
+    }
+}
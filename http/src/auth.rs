@@ -0,0 +1,441 @@
+//! This authenticates users for protected paths (RFC 7617 and RFC 7616)
+//!
+//! Users are kept in an htpasswd-style file (`auth_file`). Basic authentication
+//! checks `user:hash` lines, where the hash is bcrypt or argon2, and is only
+//! accepted over HTTPS since the password is sent as it is. Digest authentication
+//! never sends the password, but can't work with those hashes either, so it
+//! checks `user:realm:hash` lines instead, where the hash is the SHA-256 (in hex)
+//! of `user:realm:password`.
+
+use crate::config::{AuthScheme, Config};
+use crate::connection::ConnectionInfo;
+use crate::http::{HttpMessage, HttpStatusCode};
+use crate::log::{error, info};
+use crate::response::create_error_response;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a Digest nonce is good for, in seconds.
+const NONCE_LIFETIME: u64 = 300;
+
+/// Key nonces are signed with, so we know they're ours without keeping track of them.
+static NONCE_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| {
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).expect("No randomness for the nonce key");
+    key
+});
+
+/// A user who logged in.
+#[derive(Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub scheme: AuthScheme,
+}
+
+impl User {
+    /// Returns the name of the scheme the user logged in with, as passed to CGI scripts.
+    pub fn auth_type(&self) -> &'static str {
+        match self.scheme {
+            AuthScheme::Digest => "Digest",
+            _ => "Basic",
+        }
+    }
+}
+
+fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    Sha256::digest(data)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Compares secrets in a time that doesn't depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Creates a nonce for the time it was made at, signed with our key.
+fn new_nonce(time: u64) -> String {
+    let time = format!("{time:016x}");
+    let signature = sha256_hex([&NONCE_KEY[..], time.as_bytes()].concat());
+    format!("{time}{signature}")
+}
+
+/// Returns how old (in seconds) a nonce we made is, or `None` if we didn't make it.
+fn nonce_age(nonce: &str) -> Option<u64> {
+    let time = u64::from_str_radix(nonce.get(..16)?, 16).ok()?;
+    constant_time_eq(new_nonce(time).as_bytes(), nonce.as_bytes())
+        .then(|| now().saturating_sub(time))
+}
+
+/// Returns the hash of `user` that `scheme` checks against, from the contents of an auth file.
+fn find_hash<'a>(users: &'a str, scheme: AuthScheme, user: &str, realm: &str) -> Option<&'a str> {
+    users
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            let fields: Vec<_> = line.split(':').collect();
+            match (scheme, &fields[..]) {
+                (AuthScheme::Basic, [name, hash]) if *name == user => Some(*hash),
+                (AuthScheme::Digest, [name, r, hash]) if *name == user && *r == realm => {
+                    Some(*hash)
+                }
+                _ => None,
+            }
+        })
+}
+
+/// Checks a password against a bcrypt or argon2 hash.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        error!("Unsupported password hash (only bcrypt and argon2 are)");
+        false
+    }
+}
+
+/// Returns the user whose Basic credentials (base64 `user:password`) are right.
+async fn check_basic(users: &str, credentials: &str) -> Option<String> {
+    let credentials = STANDARD.decode(credentials).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (name, password) = credentials.split_once(':')?;
+    let hash = find_hash(users, AuthScheme::Basic, name, "")?.to_string();
+
+    // Hashes are meant to be slow, so don't hold up other connections checking them
+    let password = password.to_string();
+    let verified = tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await;
+    if !verified.unwrap_or(false) {
+        info!("Wrong password for user {name}");
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Parses the comma-separated `name=value` parameters of Digest credentials,
+/// where values may be quoted.
+fn parse_params(credentials: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut rest = credentials.trim();
+    while !rest.is_empty() {
+        let (name, value) = rest.split_once('=')?;
+        let name = name.trim().to_lowercase();
+        let value = value.trim_start();
+
+        let (value, after) = if let Some(quoted) = value.strip_prefix('"') {
+            let mut unquoted = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => unquoted.push(chars.next()?.1),
+                    (_, c) => unquoted.push(c),
+                }
+            };
+            (unquoted, &quoted[end + 1..])
+        } else {
+            let end = value.find(',').unwrap_or(value.len());
+            (value[..end].trim().to_string(), &value[end..])
+        };
+
+        params.insert(name, value);
+        let after = after.trim_start();
+        rest = match after.strip_prefix(',') {
+            Some(after) => after.trim_start(),
+            None if after.is_empty() => after,
+            None => return None,
+        };
+    }
+
+    Some(params)
+}
+
+/// What came of checking Digest credentials.
+#[derive(Debug, PartialEq)]
+enum DigestResult {
+    Valid(String),
+
+    /// Right credentials, but for a nonce that's gone stale, so the client
+    /// may simply try again with a new one.
+    Stale,
+    Invalid,
+}
+
+/// Checks Digest credentials for a request with `method` and `target`.
+fn check_digest(
+    users: &str,
+    realm: &str,
+    method: &str,
+    target: &str,
+    credentials: &str,
+) -> DigestResult {
+    let Some(params) = parse_params(credentials) else {
+        return DigestResult::Invalid;
+    };
+    let param = |name| params.get(name).map_or("", String::as_str);
+
+    // We only offer SHA-256 with qop=auth, for this very request
+    if !param("algorithm").eq_ignore_ascii_case("SHA-256")
+        || param("qop") != "auth"
+        || param("realm") != realm
+        || param("uri") != target
+        || param("userhash") == "true"
+    {
+        return DigestResult::Invalid;
+    }
+    let Some(age) = nonce_age(param("nonce")) else {
+        return DigestResult::Invalid;
+    };
+
+    let name = param("username");
+    let Some(ha1) = find_hash(users, AuthScheme::Digest, name, realm) else {
+        return DigestResult::Invalid;
+    };
+    let ha2 = sha256_hex(format!("{method}:{target}"));
+    let expected = sha256_hex(format!(
+        "{}:{}:{}:{}:auth:{ha2}",
+        ha1.to_lowercase(),
+        param("nonce"),
+        param("nc"),
+        param("cnonce")
+    ));
+    if !constant_time_eq(
+        expected.as_bytes(),
+        param("response").to_lowercase().as_bytes(),
+    ) {
+        info!("Wrong digest for user {name}");
+        return DigestResult::Invalid;
+    }
+
+    if age > NONCE_LIFETIME {
+        DigestResult::Stale
+    } else {
+        DigestResult::Valid(name.to_string())
+    }
+}
+
+/// Creates a 401 response asking the client to log in with `scheme`.
+async fn create_challenge(config: &Config, scheme: AuthScheme, stale: bool) -> HttpMessage {
+    let realm = config.auth_realm.replace('\\', "\\\\").replace('"', "\\\"");
+    let challenge = match scheme {
+        AuthScheme::Digest => format!(
+            "Digest realm=\"{realm}\", qop=\"auth\", algorithm=SHA-256, nonce=\"{}\"{}",
+            new_nonce(now()),
+            if stale { ", stale=true" } else { "" }
+        ),
+        _ => format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+    };
+
+    let mut response = create_error_response(config, HttpStatusCode::Unauthorized).await;
+    response
+        .header
+        .field_lines
+        .insert(String::from("WWW-Authenticate"), challenge);
+    response
+}
+
+/// Makes sure the client is logged in if `path` calls for it, returning who they are,
+/// or the response to send instead if they aren't.
+pub async fn authenticate(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &HttpMessage,
+    path: &str,
+) -> Result<Option<User>, HttpMessage> {
+    let scheme = config.auth_for(path);
+    let scheme_name = match scheme {
+        AuthScheme::Off => return Ok(None),
+
+        // Passwords aren't for sending in the clear
+        AuthScheme::Basic if !conn.https => {
+            return Err(create_error_response(config, HttpStatusCode::Forbidden).await);
+        }
+        AuthScheme::Basic => "basic",
+        AuthScheme::Digest => "digest",
+    };
+
+    let users = match tokio::fs::read_to_string(&config.auth_file).await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Error reading auth file {}: {e}", config.auth_file);
+            return Err(create_error_response(config, HttpStatusCode::InternalServorError).await);
+        }
+    };
+
+    let credentials = request
+        .header
+        .field_lines
+        .get("authorization")
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(name, _)| name.eq_ignore_ascii_case(scheme_name))
+        .map(|(_, credentials)| credentials.trim());
+    let Some(credentials) = credentials else {
+        return Err(create_challenge(config, scheme, false).await);
+    };
+
+    let name = if scheme == AuthScheme::Basic {
+        check_basic(&users, credentials).await
+    } else {
+        let request_line = request.header.request_line();
+        let method = request_line.method.to_string();
+        match check_digest(
+            &users,
+            &config.auth_realm,
+            &method,
+            &request_line.target,
+            credentials,
+        ) {
+            DigestResult::Valid(name) => Some(name),
+            DigestResult::Stale => return Err(create_challenge(config, scheme, true).await),
+            DigestResult::Invalid => None,
+        }
+    };
+
+    match name {
+        Some(name) => Ok(Some(User { name, scheme })),
+        None => Err(create_challenge(config, scheme, false).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    #[test]
+    fn test_parse_params() {
+        let params = parse_params(
+            "username=\"Mufasa\", realm=\"a \\\"quoted\\\", realm\",\
+             nc=00000001,qop=auth , uri=\"/dir/index.html\"",
+        )
+        .unwrap();
+        assert_eq!(params["username"], "Mufasa");
+        assert_eq!(params["realm"], "a \"quoted\", realm");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+        assert_eq!(params["uri"], "/dir/index.html");
+
+        assert_eq!(parse_params(""), Some(HashMap::new()));
+        assert_eq!(parse_params("realm=\"unterminated"), None);
+        assert_eq!(parse_params("realm=\"a\" nc=1"), None);
+    }
+
+    #[test]
+    fn test_find_hash() {
+        let users = "# Basic\n\
+                     alice:$2y$05$abcdefghijklmnopqrstuu\n\
+                     bob:$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA\n\
+                     # Digest\n\
+                     alice:Helios:0123abcd\n";
+
+        let find = |scheme, user, realm| find_hash(users, scheme, user, realm);
+        assert_eq!(
+            find(AuthScheme::Basic, "alice", ""),
+            Some("$2y$05$abcdefghijklmnopqrstuu")
+        );
+        assert!(find(AuthScheme::Basic, "bob", "").is_some());
+        assert_eq!(
+            find(AuthScheme::Digest, "alice", "Helios"),
+            Some("0123abcd")
+        );
+        assert_eq!(find(AuthScheme::Digest, "alice", "Other"), None);
+        assert_eq!(find(AuthScheme::Digest, "bob", "Helios"), None);
+        assert_eq!(find(AuthScheme::Basic, "carol", ""), None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("wrong", &hash));
+
+        let salt = SaltString::from_b64("c29tZXNhbHQ").unwrap();
+        let hash = Argon2::default().hash_password(b"secret", &salt).unwrap();
+        assert!(verify_password("secret", &hash.to_string()));
+        assert!(!verify_password("wrong", &hash.to_string()));
+
+        assert!(!verify_password(
+            "secret",
+            "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="
+        ));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let nonce = new_nonce(now() - 10);
+        assert!(nonce_age(&nonce).is_some_and(|age| (10..=11).contains(&age)));
+
+        // Changing its time breaks the signature
+        let forged = format!("{:016x}{}", now(), &nonce[16..]);
+        assert_eq!(nonce_age(&forged), None);
+        assert_eq!(nonce_age("short"), None);
+    }
+
+    #[test]
+    fn test_check_digest() {
+        // Example from RFC 7616 (section 3.9.1), apart from the nonce, which has to be ours
+        let realm = "http-auth@example.org";
+        let ha1 = sha256_hex(format!("Mufasa:{realm}:Circle of Life"));
+        let users = format!("Mufasa:{realm}:{ha1}\n");
+        let credentials = |nonce: &str, uri: &str| {
+            let ha2 = sha256_hex(format!("GET:{uri}"));
+            let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+            let response = sha256_hex(format!("{ha1}:{nonce}:00000001:{cnonce}:auth:{ha2}"));
+            format!(
+                "username=\"Mufasa\", realm=\"{realm}\", uri=\"{uri}\", \
+                 algorithm=SHA-256, nonce=\"{nonce}\", nc=00000001, cnonce=\"{cnonce}\", \
+                 qop=auth, response=\"{response}\", opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\""
+            )
+        };
+        let check =
+            |credentials: &str| check_digest(&users, realm, "GET", "/dir/index.html", credentials);
+
+        let nonce = new_nonce(now());
+        assert_eq!(
+            check(&credentials(&nonce, "/dir/index.html")),
+            DigestResult::Valid(String::from("Mufasa"))
+        );
+        assert_eq!(
+            check(&credentials(&nonce, "/other.html")),
+            DigestResult::Invalid
+        );
+        assert_eq!(
+            check(&credentials(
+                &new_nonce(now() - NONCE_LIFETIME - 1),
+                "/dir/index.html"
+            )),
+            DigestResult::Stale
+        );
+        assert_eq!(
+            check(&credentials(
+                "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+                "/dir/index.html"
+            )),
+            DigestResult::Invalid
+        );
+        assert_eq!(
+            check(&credentials(&nonce, "/dir/index.html").replace("response=\"", "response=\"0")),
+            DigestResult::Invalid
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! This generates listings of directories without an index file
+//!
+//! Listings are HTML by default, or JSON with `format=json` in the query.
+//! They can be sorted with `sort=name|size|modified` and `order=asc|desc`,
+//! though directories always come first.
+
+use crate::config::Config;
+use crate::http::{encode_path, HttpMessage, HttpStatusCode};
+use crate::log::json_string;
+use crate::mime;
+use crate::response::create_response;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortBy {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortBy {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// How a listing is asked for in the query string.
+#[derive(Debug, PartialEq)]
+struct Options {
+    sort: SortBy,
+    descending: bool,
+    json: bool,
+}
+
+impl Options {
+    fn from_query(query_str: &str) -> Self {
+        let mut options = Self {
+            sort: SortBy::Name,
+            descending: false,
+            json: false,
+        };
+
+        // Anything we don't understand is ignored
+        for (name, value) in query_str.split('&').filter_map(|pair| pair.split_once('=')) {
+            match (name, value) {
+                ("sort", "name") => options.sort = SortBy::Name,
+                ("sort", "size") => options.sort = SortBy::Size,
+                ("sort", "modified") => options.sort = SortBy::Modified,
+                ("order", "asc") => options.descending = false,
+                ("order", "desc") => options.descending = true,
+                ("format", "html") => options.json = false,
+                ("format", "json") => options.json = true,
+                _ => (),
+            }
+        }
+
+        options
+    }
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+/// Sorts entries as asked, directories first.
+fn sort_entries(entries: &mut [Entry], options: &Options) {
+    entries.sort_by(|a, b| {
+        let ordering = match options.sort {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+
+        let ordering = if options.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+async fn read_entries(config: &Config, dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') && !config.autoindex_hidden {
+            continue;
+        }
+
+        // Follow symlinks, skipping broken ones
+        let Ok(metadata) = tokio::fs::metadata(entry.path()).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok().map(DateTime::from),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Escapes text for HTML (or XML).
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Renders the listing of the directory at `url_path` (with a trailing slash) as HTML.
+fn render_html(url_path: &str, entries: &[Entry], options: &Options) -> String {
+    let title = escape_html(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n\
+         <table>\n<tr>"
+    );
+
+    // Clicking the column a listing is sorted by flips the order
+    for (sort, label) in [
+        (SortBy::Name, "Name"),
+        (SortBy::Size, "Size"),
+        (SortBy::Modified, "Last modified"),
+    ] {
+        let order = if sort == options.sort && !options.descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let _ = write!(
+            html,
+            "<th><a href=\"?sort={}&amp;order={order}\">{label}</a></th>",
+            sort.name()
+        );
+    }
+    html.push_str("</tr>\n");
+
+    if let Some(parent) = url_path.trim_end_matches('/').rsplit_once('/') {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>",
+            encode_path(parent.0)
+        );
+    }
+
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            String::from("-")
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map_or(String::new(), |time| {
+            time.format("%Y-%m-%d %H:%M").to_string()
+        });
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            encode_path(&format!("{url_path}{}", entry.name)),
+            escape_html(&entry.name),
+        );
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Renders the listing as a JSON array, with sizes and times (RFC 3339) of files.
+fn render_json(entries: &[Entry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let (kind, size) = if entry.is_dir {
+                ("directory", String::from("null"))
+            } else {
+                ("file", entry.size.to_string())
+            };
+            let modified = entry.modified.map_or(String::from("null"), |time| {
+                json_string(&time.to_rfc3339_opts(SecondsFormat::Secs, true))
+            });
+            format!(
+                "{{\"name\":{},\"type\":\"{kind}\",\"size\":{size},\"modified\":{modified}}}",
+                json_string(&entry.name)
+            )
+        })
+        .collect();
+
+    format!("[{}]\n", entries.join(","))
+}
+
+/// Creates a response listing the contents of `dir`, which is found at `url_path`.
+pub async fn create_listing(
+    config: &Config,
+    dir: &Path,
+    url_path: &str,
+    query_str: &str,
+    send_body: bool,
+) -> std::io::Result<HttpMessage> {
+    let options = Options::from_query(query_str);
+    let mut entries = read_entries(config, dir).await?;
+    sort_entries(&mut entries, &options);
+
+    let (content_type, body) = if options.json {
+        (String::from("application/json"), render_json(&entries))
+    } else {
+        let url_path = format!("{}/", url_path.trim_end_matches('/'));
+        (
+            mime::with_charset(config, "text/html"),
+            render_html(&url_path, &entries, &options),
+        )
+    };
+
+    Ok(create_response(
+        HttpStatusCode::Ok,
+        &[("Content-Type", &content_type)],
+        Some(body.into_bytes().into()),
+        send_body,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(name: &str, is_dir: bool, size: u64, day: u32) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(Utc.with_ymd_and_hms(2026, 10, day, 0, 0, 0).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 1),
+            entry("z", true, 0, 2),
+            entry("a.txt", false, 30, 3),
+            entry("c.txt", false, 20, 2),
+        ];
+        let names = |entries: &[Entry]| -> Vec<String> {
+            entries.iter().map(|entry| entry.name.clone()).collect()
+        };
+
+        sort_entries(&mut entries, &Options::from_query(""));
+        assert_eq!(names(&entries), ["z", "a.txt", "b.txt", "c.txt"]);
+
+        sort_entries(&mut entries, &Options::from_query("sort=size&order=desc"));
+        assert_eq!(names(&entries), ["z", "a.txt", "c.txt", "b.txt"]);
+
+        sort_entries(&mut entries, &Options::from_query("sort=modified&bogus"));
+        assert_eq!(names(&entries), ["z", "b.txt", "c.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let entries = [
+            entry("sub dir", true, 0, 1),
+            entry("<a>&b.txt", false, 42, 17),
+        ];
+        let options = Options::from_query("sort=name");
+
+        let html = render_html("/files/", &entries, &options);
+        assert!(html.contains("<h1>Index of /files/</h1>"));
+        assert!(html.contains("<a href=\"?sort=name&amp;order=desc\">Name</a>"));
+        assert!(html.contains("<a href=\"/\">../</a>"));
+        assert!(html.contains("<a href=\"/files/sub%20dir/\">sub dir/</a></td><td>-</td>"));
+        assert!(html.contains(
+            "<a href=\"/files/%3Ca%3E%26b.txt\">&lt;a&gt;&amp;b.txt</a></td><td>42</td>\
+             <td>2026-10-17 00:00</td>"
+        ));
+        assert!(!render_html("/", &entries, &options).contains("../"));
+
+        assert_eq!(
+            render_json(&entries[1..]),
+            "[{\"name\":\"<a>&b.txt\",\"type\":\"file\",\"size\":42,\
+             \"modified\":\"2026-10-17T00:00:00Z\"}]\n"
+        );
+    }
+}
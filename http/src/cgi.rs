@@ -1,68 +1,530 @@
-//! This handles CGI scripts (in our case, basically only PHP)
+//! This handles CGI scripts (RFC 3875)
 //!
-//! This is very simplistic and does the bare basics, but
-//! php-cgi is a pain in the ass and don't feel like doing more with it.
+//! Scripts are either run through the interpreter configured for their
+//! extension (e.g. `php-cgi` for `.php`), executed directly if they
+//! live under the configured `cgi_bin` folder, or handed off to the
+//! FastCGI backend configured for their extension.
 //!
-//! Only supports processing form data, and not octet-streams.
+//! Local redirects (a Location header with just a path) are not followed
+//! internally, but sent to the client as a 302 like any other redirect.
 
-use crate::http::{HttpMessage, HttpMethod, HttpStatusCode};
-use crate::response::create_response;
-use std::path::Path;
+use crate::auth::User;
+use crate::config::Config;
+use crate::connection::ConnectionInfo;
+use crate::fastcgi;
+use crate::http::{host_without_port, HttpBody, HttpField, HttpMessage, HttpStatusCode, Target};
+use crate::log::error;
+use crate::mime;
+use crate::response::{create_response, SERVER_SOFTWARE};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::Stdio;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStdout};
+use tokio::time::{timeout, Duration};
 
-fn php_cgi(method: HttpMethod, path: &Path, query_str: &str) -> tokio::process::Command {
-    let mut cmd = tokio::process::Command::new("php-cgi");
-    cmd.env("REDIRECT_STATUS", "true")
-        .env("SERVER_NAME", "Helios")
-        .env("SCRIPT_FILENAME", path.to_str().unwrap())
-        .env("REQUEST_METHOD", method.to_string())
-        .env("QUERY_STRING", query_str);
-    cmd
+/// How a CGI script is run.
+pub enum Handler {
+    /// Executed directly.
+    Exec,
+
+    /// Run through the given interpreter command line.
+    Interpreter(String),
+
+    /// Handed off to the FastCGI backend at the given address.
+    FastCgi(String),
+}
+
+/// A CGI script found for a request.
+pub struct Script {
+    /// Location of the script on disk.
+    pub path: PathBuf,
+
+    /// URL path of the script.
+    pub name: String,
+
+    /// Whatever remained of the URL path after the script.
+    pub path_info: String,
+
+    pub handler: Handler,
+}
+
+/// Returns how to run the script at `path` (with URL path `name`)
+/// if it is a CGI script at all.
+fn handler_for(config: &Config, path: &Path, name: &str) -> Option<Handler> {
+    let cgi_bin = config.cgi_bin.trim_matches('/');
+    if !cgi_bin.is_empty() && name.starts_with(&format!("/{cgi_bin}/")) {
+        return Some(Handler::Exec);
+    }
+
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    if let Some(address) = config.fastcgi.get(&ext) {
+        Some(Handler::FastCgi(address.clone()))
+    } else {
+        config.cgi.get(&ext).cloned().map(Handler::Interpreter)
+    }
+}
+
+/// Looks for a CGI script along `path` (somewhere within the public folder),
+/// which may be followed by extra path info (e.g. `/cgi-bin/app.py/users/42`).
+pub fn find_script(config: &Config, path: &Path) -> Option<Script> {
+    let public = PathBuf::from(format!("{}/public", config.server_root));
+    let mut components = path.strip_prefix(&public).ok()?.components();
+
+    let mut script = public.clone();
+    while let Some(component) = components.next() {
+        script.push(component);
+
+        if script.is_file() {
+            let name = format!("/{}", script.strip_prefix(&public).ok()?.to_str()?);
+            let path_info = components
+                .map(|c| c.as_os_str().to_str().map(|c| format!("/{c}")))
+                .collect::<Option<String>>()?;
+            let handler = handler_for(config, &script, &name)?;
+
+            return Some(Script {
+                path: script,
+                name,
+                path_info,
+                handler,
+            });
+        } else if !script.is_dir() {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// Converts a field name to the name of its HTTP_* meta-variable,
+/// or `None` if it shouldn't be passed to scripts.
+fn meta_variable_name(field_name: &str) -> Option<String> {
+    let field_name = field_name.to_lowercase();
+
+    // Already passed as CONTENT_* or deliberately withheld (Proxy because of "httpoxy")
+    if matches!(
+        field_name.as_str(),
+        "content-length" | "content-type" | "authorization" | "proxy-authorization" | "proxy"
+    ) || !field_name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+
+    Some(format!(
+        "HTTP_{}",
+        field_name.to_uppercase().replace('-', "_")
+    ))
+}
+
+/// Returns the meta-variables describing the request to the script.
+pub fn meta_variables(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &HttpMessage,
+    target: &Target,
+    script: &Script,
+    user: Option<&User>,
+) -> Result<Vec<(String, String)>, ()> {
+    let request_line = request.header.request_line();
+    let public = format!("{}/public", config.server_root);
+    let script_filename = script.path.to_str().ok_or(())?;
+
+    // Host header without the port, if any
+    let server_name = request
+        .header
+        .field_lines
+        .get("host")
+        .map_or(config.ip.as_str(), |host| host_without_port(host));
+    let server_port = if conn.https {
+        config.port_https
+    } else {
+        config.port_http
+    };
+
+    let mut vars: Vec<(&str, String)> = vec![
+        ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+        ("SERVER_SOFTWARE", String::from(SERVER_SOFTWARE)),
+        ("SERVER_NAME", String::from(server_name)),
+        ("SERVER_PORT", server_port.to_string()),
+        ("SERVER_PROTOCOL", request_line.http_version.to_string()),
+        ("REQUEST_METHOD", request_line.method.to_string()),
+        ("REQUEST_URI", request_line.target.clone()),
+        ("QUERY_STRING", target.query_str.clone()),
+        ("DOCUMENT_ROOT", public.clone()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("SCRIPT_FILENAME", String::from(script_filename)),
+        ("REMOTE_ADDR", conn.addr.ip().to_string()),
+        ("REMOTE_PORT", conn.addr.port().to_string()),
+        // php-cgi refuses to run without this
+        ("REDIRECT_STATUS", String::from("200")),
+    ];
+
+    if !script.path_info.is_empty() {
+        vars.push(("PATH_INFO", script.path_info.clone()));
+        vars.push(("PATH_TRANSLATED", format!("{public}{}", script.path_info)));
+    }
+
+    if conn.https {
+        vars.push(("HTTPS", String::from("on")));
+    }
+
+    if let Some(user) = user {
+        vars.push(("AUTH_TYPE", String::from(user.auth_type())));
+        vars.push(("REMOTE_USER", user.name.clone()));
+    }
+
+    if let Some(body) = &request.body {
+        vars.push(("CONTENT_LENGTH", body.len().unwrap_or(0).to_string()));
+    }
+    if let Some(content_type) = request.header.field_lines.get("content-type") {
+        vars.push(("CONTENT_TYPE", content_type.clone()));
+    }
+
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(name, value)| (String::from(name), value))
+        .collect();
+
+    // Client certificate, named like mod_ssl does
+    if conn.https {
+        let verify = match &conn.client_cert {
+            None => "NONE",
+            Some(cert) if cert.verified => "SUCCESS",
+            Some(_) => "FAILED",
+        };
+        vars.push((String::from("SSL_CLIENT_VERIFY"), String::from(verify)));
+    }
+    if let Some(cert) = conn.client_cert.as_ref().filter(|cert| cert.verified) {
+        let info = &cert.info;
+        vars.push((String::from("SSL_CLIENT_S_DN"), info.subject.clone()));
+        vars.push((String::from("SSL_CLIENT_I_DN"), info.issuer.clone()));
+        vars.push((String::from("SSL_CLIENT_M_SERIAL"), info.serial.clone()));
+        if let Some(common_name) = &info.common_name {
+            vars.push((String::from("SSL_CLIENT_S_DN_CN"), common_name.clone()));
+        }
+        for (i, name) in info.dns_names.iter().enumerate() {
+            vars.push((format!("SSL_CLIENT_SAN_DNS_{i}"), name.clone()));
+        }
+        for (i, email) in info.emails.iter().enumerate() {
+            vars.push((format!("SSL_CLIENT_SAN_Email_{i}"), email.clone()));
+        }
+    }
+
+    // Every other field as HTTP_*, with repeated fields combined
+    let mut meta_variables: HashMap<String, String> = HashMap::new();
+    for (name, value) in request.header.field_lines.iter() {
+        if let Some(name) = meta_variable_name(name) {
+            meta_variables
+                .entry(name)
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(value);
+                })
+                .or_insert_with(|| value.clone());
+        }
+    }
+    vars.extend(meta_variables);
+
+    Ok(vars)
+}
+
+fn cgi_command(
+    script: &Script,
+    vars: Vec<(String, String)>,
+) -> Result<tokio::process::Command, ()> {
+    let script_filename = script.path.to_str().ok_or(())?;
+
+    let mut cmd = match &script.handler {
+        Handler::Interpreter(interpreter) => {
+            let mut args = interpreter.split_whitespace();
+            let mut cmd = tokio::process::Command::new(args.next().ok_or(())?);
+            cmd.args(args).arg(script_filename);
+            cmd
+        }
+        _ => tokio::process::Command::new(script_filename),
+    };
+
+    // Scripts get a clean environment, but still need to find their tools
+    cmd.env_clear();
+    if let Ok(path) = std::env::var("PATH") {
+        cmd.env("PATH", path);
+    }
+    cmd.envs(vars);
+
+    Ok(cmd)
+}
+
+/// Output of a running script, which keeps the script around until it's dropped.
+struct Output {
+    stdout: BufReader<ChildStdout>,
+    _child: Child,
+}
+
+impl AsyncRead for Output {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for Output {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().stdout).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.stdout).consume(amt)
+    }
+}
+
+pub async fn handle_cgi(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &HttpMessage,
+    target: &Target,
+    script: &Script,
+    user: Option<&User>,
+    send_body: bool,
+) -> Result<HttpMessage, HttpStatusCode> {
+    let vars = meta_variables(config, conn, request, target, script, user)
+        .map_err(|_| HttpStatusCode::InternalServorError)?;
+    if let Handler::FastCgi(address) = &script.handler {
+        return fastcgi::handle_fastcgi(config, request, &vars, address, send_body)
+            .await
+            .map_err(|_| HttpStatusCode::InternalServorError);
+    }
+
+    let mut cmd = cgi_command(script, vars).map_err(|_| HttpStatusCode::InternalServorError)?;
+
+    // Scripts are run from their own directory
+    if let Some(dir) = script.path.parent() {
+        cmd.current_dir(dir);
+    }
+
+    let body = request.body.as_ref().and_then(HttpBody::bytes);
+    let mut child = cmd
+        .stdin(if body.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Scripts don't outlive the request, whether it's answered or abandoned
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            error!("Error running CGI script {}: {e}", script.path.display());
+            HttpStatusCode::InternalServorError
+        })?;
+
+    /* Scripts receive the request body on stdin. We feed it in the background,
+     * as a script is free to start writing output before it has read all of it,
+     * in which case we'd otherwise both be stuck waiting on the other.
+     * Dropping stdin once done lets the script see EOF.
+     */
+    if let Some(data) = body {
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or(HttpStatusCode::InternalServorError)?;
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            match stdin.write_all(&data).await {
+                // Script didn't care about the body
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+                Err(e) => error!("Error writing to CGI script: {e}"),
+                Ok(()) => (),
+            }
+        });
+    }
+
+    // Whatever scripts complain about goes to the error log
+    if let Some(stderr) = child.stderr.take() {
+        let path = script.path.display().to_string();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                error!("CGI script {path}: {line}");
+            }
+        });
+    }
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or(HttpStatusCode::InternalServorError)?;
+    let output = Output {
+        stdout: BufReader::new(stdout),
+        _child: child,
+    };
+
+    // Script has as long to start answering as a client has to start asking
+    let read_timeout = Duration::from_secs(config.max_timeout);
+    match timeout(read_timeout, parse_response(config, output, send_body)).await {
+        Ok(response) => response.map_err(|_| HttpStatusCode::InternalServorError),
+        Err(_) => {
+            error!("CGI script {} timed out", script.path.display());
+            Err(HttpStatusCode::GatewayTimeout)
+        }
+    }
 }
 
-pub async fn handle_php(
-    path: &Path,
-    query_str: &str,
-    post_data: &Option<Vec<u8>>,
+/// Creates a response from a script's output, honouring the fields in its header.
+pub async fn parse_response(
+    config: &Config,
+    mut stdout: impl AsyncBufRead + Send + Sync + Unpin + 'static,
     send_body: bool,
 ) -> Result<HttpMessage, ()> {
-    let cmd = if let Some(data) = post_data {
-        /* We use a blocking process here because unfortunately tokio
-         * does not implement piping stdout to stdin...
-         *
-         * And the whole reason we need this is because this is how
-         * php-cgi receives POST data.
-         */
-        let mut echo = std::process::Command::new("echo")
-            .arg(std::str::from_utf8(data).map_err(|_| ())?)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|_| ())?;
-
-        php_cgi(HttpMethod::Post, path, query_str)
-            .env("CONTENT_TYPE", "application/x-www-form-urlencoded")
-            .env("CONTENT_LENGTH", data.len().to_string())
-            .stdin(echo.stdout.take().ok_or(())?)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|_| ())?
-    } else {
-        php_cgi(HttpMethod::Get, path, query_str)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|_| ())?
+    // Read the CGI header, which ends with an empty line
+    let mut status_code = None;
+    let mut reason_phrase = None;
+    let mut content_type = None;
+    let mut content_len = None;
+    let mut field_lines: Vec<HttpField> = Vec::new();
+    let mut header_len = 0;
+    loop {
+        let mut line = String::new();
+        let len = stdout.read_line(&mut line).await.map_err(|_| ())?;
+        if len == 0 {
+            return Err(());
+        }
+
+        header_len += len;
+        if header_len > config.max_header_len {
+            return Err(());
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+
+        let field = line.parse::<HttpField>().map_err(|_| ())?;
+        match field.name.to_lowercase().as_str() {
+            "status" => {
+                let (code, reason) = field.value.split_once(' ').unwrap_or((&field.value, ""));
+                let code = code.parse().map_err(|_| ())?;
+                status_code = Some(HttpStatusCode::from_u16_or_other(code).map_err(|_| ())?);
+                reason_phrase = Some(reason.to_string()).filter(|reason| !reason.is_empty());
+            }
+            "content-type" => content_type = Some(field.value),
+            "content-length" => content_len = Some(field.value.parse().map_err(|_| ())?),
+
+            // These are about our connection with the client, which is our business
+            "connection" | "keep-alive" | "transfer-encoding" | "server" | "date" => (),
+
+            _ => field_lines.push(field),
+        }
+    }
+
+    // A Location without a Status is a redirect
+    let is_redirect = field_lines
+        .iter()
+        .any(|field| field.name.eq_ignore_ascii_case("location"));
+    let status_code = match status_code {
+        Some(status_code) => status_code,
+        None if is_redirect => HttpStatusCode::Found,
+        None => HttpStatusCode::Ok,
     };
 
-    let result = cmd.wait_with_output().await.map_err(|_| ())?;
-    if result.status.success() {
-        let (_header, body) = std::str::from_utf8(&result.stdout)
-            .map_err(|_| ())?
-            .split_once("\r\n\r\n")
-            .ok_or(())?;
+    let content_type = mime::with_charset(config, content_type.as_deref().unwrap_or("text/html"));
+    let field_lines: Vec<_> = [("Content-Type", content_type.as_str())]
+        .into_iter()
+        .chain(
+            field_lines
+                .iter()
+                .map(|field| (field.name.as_str(), field.value.as_str())),
+        )
+        .collect();
 
-        let body = body.as_bytes().to_vec();
-        Ok(create_response(HttpStatusCode::Ok, Some(body), send_body))
-    } else {
-        Err(())
+    // The rest of the output is the body, which we stream as the script produces it
+    let body = HttpBody::Stream {
+        reader: Box::new(stdout),
+        len: content_len,
+    };
+    let mut response = create_response(status_code, &field_lines, Some(body), send_body);
+    response.header.status_line_mut().reason_phrase = reason_phrase;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta_variable_name() {
+        assert_eq!(
+            meta_variable_name("User-Agent").as_deref(),
+            Some("HTTP_USER_AGENT")
+        );
+        assert_eq!(meta_variable_name("host").as_deref(), Some("HTTP_HOST"));
+
+        // Withheld
+        assert_eq!(meta_variable_name("Content-Type"), None);
+        assert_eq!(meta_variable_name("Authorization"), None);
+        assert_eq!(meta_variable_name("Proxy"), None);
+
+        // Would make for a weird environment variable
+        assert_eq!(meta_variable_name("X_Forwarded_For"), None);
+        assert_eq!(meta_variable_name("foo.bar"), None);
+    }
+
+    #[tokio::test]
+    async fn test_handle_cgi() {
+        let dir = std::env::temp_dir().join(format!("helios-cgi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            max_timeout: 1,
+            ..Config::default()
+        };
+        let conn = ConnectionInfo {
+            addr: "192.0.2.7:4242".parse().unwrap(),
+            https: false,
+            client_cert: None,
+        };
+        let request = HttpMessage {
+            header: "GET /script HTTP/1.1\r\nHost: localhost\r\n\r\n"
+                .parse()
+                .unwrap(),
+            body: None,
+        };
+        let target: Target = "/script".parse().unwrap();
+        let run = |source: &str| {
+            let path = dir.join("script");
+            std::fs::write(&path, source).unwrap();
+            let script = Script {
+                path,
+                name: String::from("/script"),
+                path_info: String::new(),
+                handler: Handler::Interpreter(String::from("sh")),
+            };
+            let (config, conn, request, target) = (&config, &conn, &request, &target);
+            async move { handle_cgi(config, conn, request, target, &script, None, true).await }
+        };
+
+        // Complaints go to the error log rather than the response
+        let response = run("echo oops >&2; printf 'Status: 201\\r\\n\\r\\nhi'")
+            .await
+            .unwrap();
+        assert_eq!(
+            response.header.status_line().status_code,
+            HttpStatusCode::Created
+        );
+
+        // Scripts that don't answer in time are given up on
+        let started = std::time::Instant::now();
+        let response = run("sleep 10").await;
+        assert!(matches!(response, Err(HttpStatusCode::GatewayTimeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,434 @@
+//! This handles the chunked transfer coding (RFC 9112, section 7.1)
+//!
+//! When decoding, chunk extensions are parsed but ignored, as are trailer
+//! fields (we don't understand any field that would be allowed to be merged
+//! into the header anyway). When encoding, neither are ever sent.
+
+use crate::http::HttpField;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
+
+/// Longest chunk-size or trailer line we are willing to buffer.
+const MAX_LINE_LEN: u64 = 4096;
+
+/// Maximum combined length of all trailer field lines.
+const MAX_TRAILER_LEN: usize = 8 * 1024;
+
+/// Largest chunk we will send.
+const CHUNK_LEN: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Closed,
+    Malformed,
+    TooLarge,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::Closed
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Closed => std::io::ErrorKind::UnexpectedEof.into(),
+            Error::Malformed | Error::TooLarge => std::io::ErrorKind::InvalidData.into(),
+        }
+    }
+}
+
+/// Reads a single CRLF terminated line, with the CRLF stripped.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let len = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if len == 0 {
+        return Err(Error::Closed);
+    }
+
+    // Either the line was too long, the client hung up mid-line, or used a bare LF
+    if !line.ends_with(b"\r\n") {
+        return Err(Error::Malformed);
+    }
+    line.truncate(line.len() - 2);
+
+    String::from_utf8(line).map_err(|_| Error::Malformed)
+}
+
+/// Parses a chunk-size line, discarding any chunk extensions.
+fn parse_chunk_size(line: &str) -> Result<usize, Error> {
+    let size = line
+        .split_once(';')
+        .map_or(line, |(size, _extensions)| size)
+        .trim_end_matches([' ', '\t']);
+
+    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Malformed);
+    }
+
+    // Anything not fitting in a usize is certainly too large for us
+    usize::from_str_radix(size, 16).map_err(|_| Error::TooLarge)
+}
+
+/// Reads and decodes a chunked body, failing if the decoded body
+/// would grow beyond `max_len` bytes.
+pub async fn read_body(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    max_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+
+    loop {
+        let size = parse_chunk_size(&read_line(reader).await?)?;
+        if size == 0 {
+            break;
+        }
+
+        if size > max_len - body.len() {
+            return Err(Error::TooLarge);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+
+        // Chunk data must be followed by CRLF
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(Error::Malformed);
+        }
+    }
+
+    // The trailer section ends with an empty line
+    let mut trailer_len = 0;
+    loop {
+        let line = read_line(reader).await?;
+        if line.is_empty() {
+            break;
+        }
+
+        trailer_len += line.len();
+        if trailer_len > MAX_TRAILER_LEN {
+            return Err(Error::TooLarge);
+        }
+
+        line.parse::<HttpField>().map_err(|_| Error::Malformed)?;
+    }
+
+    Ok(body)
+}
+
+enum State {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Decodes a chunked body as it is read, for bodies we pass along
+/// without holding them in memory (such as those of upstream servers).
+pub struct Decoder<R> {
+    reader: R,
+    state: State,
+    line: Vec<u8>,
+    trailer_len: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            state: State::Size,
+            line: Vec::new(),
+            trailer_len: 0,
+        }
+    }
+
+    /// Polls for a single CRLF terminated line, with the CRLF stripped.
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<Result<String, Error>> {
+        loop {
+            let available = ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Err(Error::Closed));
+            }
+
+            let (len, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), false),
+            };
+            self.line.extend(&available[..len]);
+            Pin::new(&mut self.reader).consume(len);
+
+            if self.line.len() as u64 > MAX_LINE_LEN {
+                return Poll::Ready(Err(Error::Malformed));
+            }
+
+            if done {
+                let mut line = std::mem::take(&mut self.line);
+                if !line.ends_with(b"\r\n") {
+                    return Poll::Ready(Err(Error::Malformed));
+                }
+                line.truncate(line.len() - 2);
+
+                return Poll::Ready(String::from_utf8(line).map_err(|_| Error::Malformed));
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for Decoder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            match this.state {
+                State::Done => return Poll::Ready(Ok(())),
+                State::Data(remaining) => {
+                    let available = ready!(Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+                    if available.is_empty() {
+                        return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    let len = available
+                        .len()
+                        .min(buf.remaining())
+                        .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+                    buf.put_slice(&available[..len]);
+                    Pin::new(&mut this.reader).consume(len);
+
+                    this.state = match remaining - len as u64 {
+                        0 => State::DataEnd,
+                        remaining => State::Data(remaining),
+                    };
+                    return Poll::Ready(Ok(()));
+                }
+                State::Size => {
+                    let line = ready!(this.poll_line(cx))?;
+                    this.state = match parse_chunk_size(&line)? {
+                        0 => State::Trailer,
+                        size => State::Data(size as u64),
+                    };
+                }
+
+                // Chunk data must be followed by CRLF
+                State::DataEnd => {
+                    if !ready!(this.poll_line(cx))?.is_empty() {
+                        return Poll::Ready(Err(Error::Malformed.into()));
+                    }
+                    this.state = State::Size;
+                }
+
+                // The trailer section ends with an empty line
+                State::Trailer => {
+                    let line = ready!(this.poll_line(cx))?;
+                    if line.is_empty() {
+                        this.state = State::Done;
+                        continue;
+                    }
+
+                    this.trailer_len += line.len();
+                    if this.trailer_len > MAX_TRAILER_LEN {
+                        return Poll::Ready(Err(Error::TooLarge.into()));
+                    }
+
+                    line.parse::<HttpField>().map_err(|_| Error::Malformed)?;
+                }
+            }
+        }
+    }
+}
+
+/// Copies everything from `reader` to `writer` as a chunked body,
+/// returning the number of (unencoded) bytes copied.
+pub async fn write_body(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<u64> {
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut total = 0;
+
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+
+        writer.write_all(format!("{len:X}\r\n").as_bytes()).await?;
+        writer.write_all(&buf[..len]).await?;
+        writer.write_all(b"\r\n").await?;
+        total += len as u64;
+    }
+
+    // Last chunk with an empty trailer section
+    writer.write_all(b"0\r\n\r\n").await?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn decode(mut data: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+        read_body(&mut data, max_len).await
+    }
+
+    #[tokio::test]
+    async fn test_read_body_valid() {
+        // Simple body
+        let body = decode(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n", 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, b"Wikipedia");
+
+        // Empty body
+        let body = decode(b"0\r\n\r\n", 1024).await.unwrap();
+        assert!(body.is_empty());
+
+        // Uppercase hex, extensions and trailers
+        let body = decode(
+            b"A;name=value\r\n0123456789\r\n1 ; foo\r\n!\r\n0\r\nExpires: never\r\n\r\n",
+            1024,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, b"0123456789!");
+
+        // Body exactly at limit
+        let body = decode(b"4\r\nWiki\r\n0\r\n\r\n", 4).await.unwrap();
+        assert_eq!(body, b"Wiki");
+
+        // Data after the body is left for the next request
+        let mut data: &[u8] = b"1\r\nx\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+        let body = read_body(&mut data, 1024).await.unwrap();
+        assert_eq!(body, b"x");
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_body_invalid() {
+        // Invalid (exceeds limit across chunks)
+        assert!(matches!(
+            decode(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n", 8).await,
+            Err(Error::TooLarge)
+        ));
+
+        // Invalid (size overflows)
+        assert!(matches!(
+            decode(b"ffffffffffffffffffff\r\n", 1024).await,
+            Err(Error::TooLarge)
+        ));
+
+        // Invalid (not hex)
+        assert!(matches!(
+            decode(b"zz\r\n", 1024).await,
+            Err(Error::Malformed)
+        ));
+
+        // Invalid (sign prefix)
+        assert!(matches!(
+            decode(b"+4\r\nWiki\r\n0\r\n\r\n", 1024).await,
+            Err(Error::Malformed)
+        ));
+
+        // Invalid (chunk data longer than size)
+        assert!(matches!(
+            decode(b"2\r\nWiki\r\n0\r\n\r\n", 1024).await,
+            Err(Error::Malformed)
+        ));
+
+        // Invalid (bare LF)
+        assert!(matches!(
+            decode(b"4\nWiki\r\n0\r\n\r\n", 1024).await,
+            Err(Error::Malformed)
+        ));
+
+        // Invalid (malformed trailer)
+        assert!(matches!(
+            decode(b"0\r\nHack the planet!\r\n\r\n", 1024).await,
+            Err(Error::Malformed)
+        ));
+
+        // Invalid (connection closed mid-chunk)
+        assert!(matches!(decode(b"4\r\nWi", 1024).await, Err(Error::Closed)));
+
+        // Invalid (connection closed before trailer section ends)
+        assert!(matches!(decode(b"0\r\n", 1024).await, Err(Error::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_decoder() {
+        async fn decode_stream(data: &[u8]) -> std::io::Result<Vec<u8>> {
+            // Tiny buffer so lines and chunks arrive in pieces
+            let reader = tokio::io::BufReader::with_capacity(3, data);
+            let mut body = Vec::new();
+            Decoder::new(reader).read_to_end(&mut body).await?;
+            Ok(body)
+        }
+
+        // Valid
+        let body = decode_stream(b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(body, b"Wikipedia");
+
+        let body = decode_stream(b"A;name=value\r\n0123456789\r\n0\r\nExpires: never\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(body, b"0123456789");
+
+        // Data after the body is left alone
+        let mut data: &[u8] = b"1\r\nx\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n";
+        let mut body = Vec::new();
+        Decoder::new(&mut data)
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, b"x");
+        assert_eq!(data, b"HTTP/1.1 200 OK\r\n");
+
+        // Invalid
+        assert!(decode_stream(b"zz\r\n").await.is_err());
+        assert!(decode_stream(b"2\r\nWiki\r\n0\r\n\r\n").await.is_err());
+        assert!(decode_stream(b"4\nWiki\r\n0\r\n\r\n").await.is_err());
+
+        // Invalid (connection closed mid-chunk)
+        let e = decode_stream(b"4\r\nWi").await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_write_body() {
+        let mut encoded = Vec::new();
+        let len = write_body(&mut &b"Hack the planet!"[..], &mut encoded)
+            .await
+            .unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(encoded, b"10\r\nHack the planet!\r\n0\r\n\r\n");
+
+        // Round trip (spanning multiple chunks)
+        let data: Vec<u8> = (0..CHUNK_LEN * 2 + 42).map(|i| i as u8).collect();
+        let mut encoded = Vec::new();
+        write_body(&mut &data[..], &mut encoded).await.unwrap();
+        assert_eq!(decode(&encoded, data.len()).await.unwrap(), data);
+    }
+}
@@ -0,0 +1,243 @@
+//! This compresses responses for clients that accept it (RFC 9110, section 8.4)
+//!
+//! Responses are compressed on the fly with gzip, brotli or zstd if their media
+//! type is textual enough to be worth it. Static files may also come with
+//! precompressed siblings (`app.js.br`, `app.js.zst`, `app.js.gz`), which are
+//! sent as they are instead.
+
+use crate::config::Config;
+use crate::http::{HttpBody, HttpMessage, HttpStatusCode};
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::io::BufReader;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// All encodings, in the order we prefer them when the client likes several equally.
+    pub const ALL: [Self; 3] = [Self::Brotli, Self::Zstd, Self::Gzip];
+
+    /// Returns the content coding, as used in Accept-Encoding and Content-Encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Returns the extension of precompressed files.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+}
+
+/// Parses an Accept-Encoding value into content codings (lowercase) and their weights.
+/// Codings with invalid weights are left out.
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().filter(|coding| !coding.is_empty())?;
+
+            let mut weight = 1.0;
+            for param in params {
+                if let Some(q) = param.strip_prefix("q=").or(param.strip_prefix("Q=")) {
+                    weight = q.parse().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+
+            Some((coding.to_lowercase(), weight))
+        })
+        .collect()
+}
+
+/// Picks the encoding of `available` that the client likes best according to its
+/// Accept-Encoding field, or `None` if it would rather have the response as it is.
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let codings = parse_accept_encoding(accept_encoding?);
+    let find = |name: &str| {
+        codings
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .map(|(_, weight)| *weight)
+    };
+    let weight = |name: &str| find(name).or_else(|| find("*"));
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in available {
+        let q = match encoding {
+            Encoding::Gzip => weight("gzip").or_else(|| weight("x-gzip")),
+            _ => weight(encoding.name()),
+        }
+        .unwrap_or(0.0);
+
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+
+    // Unless the client would explicitly rather have it uncompressed
+    best.filter(|(_, q)| find("identity").is_none_or(|identity| *q >= identity))
+        .map(|(encoding, _)| encoding)
+}
+
+/// Returns true if responses of the media type are worth compressing.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime_type = content_type.split(';').next().unwrap_or("").trim();
+    let mime_type = mime_type.to_lowercase();
+
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || matches!(
+            mime_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "application/vnd.apple.mpegurl"
+        )
+}
+
+/// Returns the path of the file precompressed with `encoding` next to `path`.
+pub fn sibling(path: &Path, encoding: Encoding) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.extension());
+    PathBuf::from(sibling)
+}
+
+/// Returns the encodings the file at `path` has precompressed siblings for.
+pub fn precompressed(path: &Path) -> Vec<Encoding> {
+    Encoding::ALL
+        .into_iter()
+        .filter(|&encoding| sibling(path, encoding).is_file())
+        .collect()
+}
+
+fn varies_on(name: &str) -> bool {
+    name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+}
+
+/// Adds Accept-Encoding to the Vary field of `response`, if not already there.
+fn vary(response: &mut HttpMessage) {
+    let field_lines = &mut response.header.field_lines;
+    let vary = match field_lines.get("vary") {
+        Some(vary) if vary.split(',').any(|name| varies_on(name.trim())) => return,
+        Some(vary) => format!("{vary}, Accept-Encoding"),
+        None => String::from("Accept-Encoding"),
+    };
+    field_lines.insert(String::from("Vary"), vary);
+}
+
+/// Compresses a response on the fly if it is worth it and the client accepts it.
+pub fn compress_response(config: &Config, request: &HttpMessage, response: &mut HttpMessage) {
+    if response.header.status_line().status_code != HttpStatusCode::Ok {
+        return;
+    }
+
+    // Already compressed (precompressed files, upstream servers), or not worth it
+    let field_lines = &response.header.field_lines;
+    if field_lines.contains_key("content-encoding")
+        || field_lines.contains_key("content-range")
+        || !field_lines
+            .get("content-type")
+            .is_some_and(|content_type| is_compressible(content_type))
+    {
+        return;
+    }
+    let len = field_lines
+        .get("content-length")
+        .and_then(|len| len.parse::<u64>().ok());
+    if len.is_some_and(|len| len < config.compression_min_len) {
+        return;
+    }
+
+    vary(response);
+    let accept_encoding = request.header.field_lines.get("accept-encoding");
+    let Some(encoding) = negotiate(accept_encoding.map(String::as_str), &Encoding::ALL) else {
+        return;
+    };
+
+    // The compressed length isn't known until it's sent, and neither are its byte offsets
+    let field_lines = &mut response.header.field_lines;
+    field_lines.remove("Content-Length");
+    field_lines.remove("Accept-Ranges");
+    field_lines.insert(String::from("Transfer-Encoding"), String::from("chunked"));
+    field_lines.insert(
+        String::from("Content-Encoding"),
+        encoding.name().to_string(),
+    );
+
+    // Nor is it byte for byte the same as the uncompressed representation
+    if let Some(etag) = field_lines
+        .get("etag")
+        .filter(|etag| !etag.starts_with("W/"))
+    {
+        let etag = format!("W/{etag}");
+        field_lines.insert(String::from("ETag"), etag);
+    }
+
+    response.body = response.body.take().map(|body| {
+        let reader = match body {
+            HttpBody::Full(data) => BufReader::new(Box::new(Cursor::new(data)) as _),
+            HttpBody::Stream { reader, .. } => BufReader::new(reader),
+        };
+        let reader: Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin> = match encoding {
+            Encoding::Brotli => Box::new(BrotliEncoder::with_quality(reader, Level::Precise(5))),
+            Encoding::Zstd => Box::new(ZstdEncoder::new(reader)),
+            Encoding::Gzip => Box::new(GzipEncoder::new(reader)),
+        };
+        HttpBody::Stream { reader, len: None }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = &Encoding::ALL;
+        let negotiate = |value| negotiate(Some(value), all);
+
+        assert_eq!(negotiate("gzip, deflate, br, zstd"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("deflate"), None);
+        assert_eq!(negotiate("gzip;q=0.5, identity"), None);
+        assert_eq!(negotiate("gzip;q=0.5, identity;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=2, zstd;q=0.1"), Some(Encoding::Zstd));
+        assert_eq!(negotiate(""), None);
+        assert_eq!(super::negotiate(None, all), None);
+
+        // Only what's there to choose from
+        let gzip = &[Encoding::Gzip];
+        assert_eq!(
+            super::negotiate(Some("br, gzip;q=0.1"), gzip),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+}
@@ -0,0 +1,201 @@
+//! This handles conditional requests (RFC 9110, section 13)
+//!
+//! Static files are validated with a strong entity tag made up of their
+//! inode, size and modification time, and with their modification time
+//! as Last-Modified.
+
+use crate::http::{HttpMessage, HttpMethod, HttpStatusCode};
+use crate::response::http_date;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Validators {
+    /// Strong entity tag, including the surrounding quotes.
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        let last_modified = metadata.modified().ok();
+        let mtime = last_modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+
+        Self {
+            etag: format!("\"{inode:x}-{:x}-{mtime:x}\"", metadata.len()),
+            last_modified,
+        }
+    }
+
+    /// Returns the Last-Modified time truncated to whole seconds,
+    /// as that is all an HTTP-date can represent.
+    fn last_modified_secs(&self) -> Option<i64> {
+        self.last_modified
+            .map(|m| DateTime::<Utc>::from(m).timestamp())
+    }
+}
+
+/// Parses an HTTP-date in any of the three formats recipients must accept.
+fn parse_http_date(s: &str) -> Option<i64> {
+    [
+        "%a, %d %b %Y %H:%M:%S GMT", // IMF-fixdate
+        "%A, %d-%b-%y %H:%M:%S GMT", // Obsolete RFC 850
+        "%a %b %e %H:%M:%S %Y",      // Obsolete asctime
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(s.trim(), format).ok())
+    .map(|date| date.and_utc().timestamp())
+}
+
+/// Splits a list of entity tags into (is weak, opaque tag) pairs.
+///
+/// Returns `None` if the list is malformed.
+fn parse_etags(s: &str) -> Option<Vec<(bool, &str)>> {
+    let mut etags = Vec::new();
+    let mut rest = s.trim_start_matches([' ', '\t', ',']);
+
+    while !rest.is_empty() {
+        let (weak, tag) = match rest.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, rest),
+        };
+
+        let tag = tag.strip_prefix('"')?;
+        let end = tag.find('"')?;
+        etags.push((weak, &tag[..end]));
+
+        rest = tag[end + 1..].trim_start_matches([' ', '\t']);
+        if !rest.is_empty() {
+            rest = rest.strip_prefix(',')?.trim_start_matches([' ', '\t', ',']);
+        }
+    }
+
+    Some(etags)
+}
+
+/// Returns true if `value` (an If-Match or If-None-Match field value)
+/// matches our entity tag, using strong comparison if `strong`.
+fn etag_matches(value: &str, etag: &str, strong: bool) -> bool {
+    if value.trim() == "*" {
+        return true;
+    }
+
+    let etag = etag.trim_matches('"');
+    parse_etags(value).is_some_and(|etags| {
+        etags
+            .iter()
+            .any(|&(weak, tag)| tag == etag && !(strong && weak))
+    })
+}
+
+/// Evaluates the request's preconditions in the order given by RFC 9110,
+/// section 13.2.2, returning the status code to respond with instead if
+/// any of them fail.
+///
+/// If-Range is not evaluated here, see [`if_range_matches`].
+pub fn evaluate_preconditions(
+    request: &HttpMessage,
+    validators: &Validators,
+) -> Option<HttpStatusCode> {
+    let fields = &request.header.field_lines;
+    let method = &request.header.request_line().method;
+    let modified = validators.last_modified_secs();
+
+    if let Some(value) = fields.get("if-match") {
+        if !etag_matches(value, &validators.etag, true) {
+            return Some(HttpStatusCode::PreconditionFailed);
+        }
+    } else if let Some(date) = fields
+        .get("if-unmodified-since")
+        .and_then(|v| parse_http_date(v))
+    {
+        if modified.is_none_or(|modified| modified > date) {
+            return Some(HttpStatusCode::PreconditionFailed);
+        }
+    }
+
+    let is_get_or_head = matches!(method, HttpMethod::Get | HttpMethod::Head);
+    if let Some(value) = fields.get("if-none-match") {
+        if etag_matches(value, &validators.etag, false) {
+            return Some(if is_get_or_head {
+                HttpStatusCode::NotModified
+            } else {
+                HttpStatusCode::PreconditionFailed
+            });
+        }
+    } else if let Some(date) = fields
+        .get("if-modified-since")
+        .and_then(|v| parse_http_date(v))
+    {
+        if is_get_or_head && modified.is_some_and(|modified| modified <= date) {
+            return Some(HttpStatusCode::NotModified);
+        }
+    }
+
+    None
+}
+
+/// Returns true if the request has no If-Range precondition or it holds,
+/// meaning it is fine to send only the requested ranges.
+pub fn if_range_matches(request: &HttpMessage, validators: &Validators) -> bool {
+    let Some(value) = request.header.field_lines.get("if-range") else {
+        return true;
+    };
+
+    // If-Range requires strong comparison, so weak tags never match
+    if value.starts_with('"') {
+        return *value == validators.etag;
+    } else if value.starts_with("W/") {
+        return false;
+    }
+
+    // Dates must match exactly
+    validators
+        .last_modified
+        .is_some_and(|modified| http_date(modified) == *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_date() {
+        let expected = Some(784111777);
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+
+        assert_eq!(parse_http_date("Hack the planet!"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = "\"abc\"";
+
+        // Strong comparison
+        assert!(etag_matches("\"abc\"", etag, true));
+        assert!(etag_matches("\"xyz\", \"abc\"", etag, true));
+        assert!(etag_matches("*", etag, true));
+        assert!(!etag_matches("W/\"abc\"", etag, true));
+        assert!(!etag_matches("\"xyz\"", etag, true));
+
+        // Weak comparison
+        assert!(etag_matches("W/\"abc\"", etag, false));
+        assert!(etag_matches("\"xyz\",W/\"abc\"", etag, false));
+
+        // Malformed
+        assert!(!etag_matches("abc", etag, false));
+        assert!(!etag_matches("\"abc", etag, false));
+        assert!(!etag_matches("\"xyz\" \"abc\"", etag, false));
+    }
+}
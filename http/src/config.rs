@@ -1,57 +1,757 @@
+use crate::acl::{self, Rule};
+use crate::http::{host_without_port, HttpStatusCode};
+use crate::limit::RateLimit;
+use crate::log::{Format, Level};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
 use std::path::Path;
+use std::str::FromStr;
 
+/// How requests are spread across the servers of an upstream pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+
+    /// Consistent hashing of the client's IP address,
+    /// so clients keep talking to the same server.
+    IpHash,
+}
+
+impl FromStr for Balance {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_conn" => Ok(Self::LeastConnections),
+            "ip_hash" => Ok(Self::IpHash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether requests need a client certificate (verified against `client_ca`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuth {
+    Off,
+
+    /// Clients may go without a certificate, but not with one that fails verification.
+    Optional,
+
+    Required,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "optional" => Ok(Self::Optional),
+            "required" => Ok(Self::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How clients prove who they are to get at a path, if they need to at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthScheme {
+    Off,
+
+    /// Username and password in the clear, so only accepted over HTTPS.
+    Basic,
+
+    /// Challenge and response with SHA-256 (RFC 7616).
+    Digest,
+}
+
+impl FromStr for AuthScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "basic" => Ok(Self::Basic),
+            "digest" => Ok(Self::Digest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A pool of upstream servers that proxied requests are balanced across.
+#[derive(Clone)]
+pub struct Upstream {
+    /// Addresses (`host:port`) of the servers.
+    pub servers: Vec<String>,
+    pub balance: Balance,
+
+    /// Errors in a row after which a server is considered down.
+    pub max_fails: u32,
+
+    /// Seconds a server is considered down for after `max_fails` errors.
+    pub fail_timeout: u64,
+
+    /// Path probed on each server to check its health. Disabled if empty.
+    pub health_check: String,
+
+    /// Seconds between health checks.
+    pub health_interval: u64,
+}
+
+impl Upstream {
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ()> {
+        match name {
+            "server" => self.servers.push(value.to_string()),
+            "balance" => self.balance = value.parse()?,
+            "max_fails" => self.max_fails = value.parse().map_err(|_| ())?,
+            "fail_timeout" => self.fail_timeout = value.parse().map_err(|_| ())?,
+            "health_check" => self.health_check = value.to_string(),
+            "health_interval" => self.health_interval = value.parse().map_err(|_| ())?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            balance: Balance::RoundRobin,
+            max_fails: 1,
+            fail_timeout: 10,
+            health_check: String::new(),
+            health_interval: 5,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub max_connections: usize,
+
+    /// Connections a single client (IP address) may have open at once. Unlimited if 0.
+    pub max_connections_per_ip: usize,
+
+    /// Requests a single client may make to the server, if limited.
+    pub rate_limit: Option<RateLimit>,
+
+    /// URL path prefix to request limit, overriding `rate_limit`
+    /// (with clients counted separately for each prefix).
+    pub rate_limits: HashMap<String, Option<RateLimit>>,
+
     pub max_header_len: usize,
     pub max_body_len: usize,
+
+    /// Largest file that may be uploaded over WebDAV, which is written to disk as
+    /// it arrives rather than held in memory (over HTTP/1.1, anyway).
+    pub max_upload_len: u64,
     pub max_timeout: u64,
     pub ip: String,
     pub port_http: u16,
     pub port_https: u16,
     pub https_enabled: bool,
+
+    /// Status (301 or 308) plain HTTP requests are redirected to HTTPS with, if any.
+    pub https_redirect: Option<HttpStatusCode>,
+
+    /// How long clients should stick to HTTPS (Strict-Transport-Security), in seconds.
+    /// Disabled if 0.
+    pub hsts_max_age: u64,
+    pub hsts_subdomains: bool,
+
+    /// File each request is logged to, in `access_log_format`. Disabled if empty.
+    pub access_log: String,
+    pub access_log_format: Format,
+
+    /// File errors (and anything else at or above `log_level`) are logged to.
+    /// Goes to stderr if empty.
+    pub error_log: String,
+    pub log_level: Level,
+
+    /// Folder of certificates for HTTPS, each in a subfolder named after its host.
+    /// Disabled if empty.
+    pub cert_dir: String,
+
+    /// CA bundle client certificates are verified against.
+    /// Clients aren't asked for certificates if empty.
+    pub client_ca: String,
+
+    /// Client certificate requirement for the whole server.
+    pub client_auth: ClientAuth,
+
+    /// URL path prefix to client certificate requirement, overriding `client_auth`.
+    pub client_auth_paths: HashMap<String, ClientAuth>,
+
+    /// Rules (checked in order) for which client addresses are let in. The top-level
+    /// ones are checked as soon as a client connects, and those of a server (its own,
+    /// followed by the top-level ones) for each request to it.
+    pub acl: Vec<Rule>,
+
+    /// URL path prefix to address rules, overriding `acl`.
+    pub acl_paths: HashMap<String, Vec<Rule>>,
+
+    /// URL path prefix to the authentication scheme users there must log in with.
+    pub auth: HashMap<String, AuthScheme>,
+
+    /// htpasswd-style file of users, with bcrypt or argon2 hashes for Basic
+    /// authentication and `user:realm:hash` lines (SHA-256) for Digest.
+    pub auth_file: String,
+
+    /// Realm users are asked to log in to, which Digest hashes are tied to.
+    pub auth_realm: String,
+
+    /// URL path prefix to whether files there are managed over WebDAV.
+    pub webdav: HashMap<String, bool>,
+
     pub server_root: String,
+    pub charset: String,
+
+    /// Files looked for (in order) when a directory is requested.
+    pub index: Vec<String>,
+
+    /// Whether directories without an index file are listed, rather than a 404.
+    pub autoindex: bool,
+
+    /// Whether listings include hidden files (those starting with a dot).
+    pub autoindex_hidden: bool,
+
+    /// Whether responses are compressed for clients that accept it,
+    /// and precompressed files are sent in place of the originals.
+    pub compression: bool,
+
+    /// Responses shorter than this many bytes aren't worth compressing on the fly.
+    pub compression_min_len: u64,
+
+    /// Extension (lowercase, without dot) to media type overrides.
+    pub mime_types: HashMap<String, String>,
+
+    /// Extension (lowercase, without dot) to CGI interpreter command line.
+    pub cgi: HashMap<String, String>,
+
+    /// Extension (lowercase, without dot) to FastCGI backend address,
+    /// either `host:port` or `unix:/path/to/socket`.
+    pub fastcgi: HashMap<String, String>,
+
+    /// URL path prefix to the upstream requests are forwarded to,
+    /// either the name of a pool in `upstreams` or a single `host:port`.
+    pub proxy: HashMap<String, String>,
+
+    /// Upstream pools by name.
+    pub upstreams: HashMap<String, Upstream>,
+
+    /// Folder within `public` whose files are executed directly as CGI scripts.
+    /// Disabled if empty.
+    pub cgi_bin: String,
+
+    /// Host names this server answers to, which may start with `*.`
+    /// or end with `.*` to match any subdomain or top-level domain.
+    pub server_names: Vec<String>,
+
+    /// Virtual hosts, with settings overriding the top-level ones
+    /// for requests whose Host matches their `server_names`.
+    pub servers: Vec<Config>,
 }
 
 impl Config {
     pub fn from_file(path: &Path) -> Result<Self, ()> {
         let file = File::open(path).map_err(|_| ())?;
+        Self::parse(BufReader::new(file).lines().map_while(Result::ok))
+    }
 
+    fn parse(lines: impl Iterator<Item = String>) -> Result<Self, ()> {
         let mut config = Self::default();
-        for line in BufReader::new(file).lines().map_while(Result::ok) {
-            if let Some((name, value)) = line.split_once('=') {
-                match name {
-                    "max_connections" => config.max_connections = value.parse().map_err(|_| ())?,
-                    "max_header_len" => config.max_header_len = value.parse().map_err(|_| ())?,
-                    "max_body_len" => config.max_body_len = value.parse().map_err(|_| ())?,
-                    "max_timeout" => config.max_timeout = value.parse().map_err(|_| ())?,
-                    "ip" => config.ip = value.to_string(),
-                    "port_http" => config.port_http = value.parse().map_err(|_| ())?,
-                    "port_https" => config.port_https = value.parse().map_err(|_| ())?,
-                    "https_enabled" => config.https_enabled = value.parse().map_err(|_| ())?,
-                    "server_root" => config.server_root = value.to_string(),
-                    _ => (),
+        let mut servers: Vec<Config> = Vec::new();
+        let mut section = String::new();
+        for line in lines {
+            // Lines like [name] start a new section
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.to_string();
+
+                // Each [server] starts out with the top-level settings (address rules
+                // being added after its own, once it has them all), and any sections
+                // following it (until the next one) are its own
+                if section == "server" {
+                    servers.push(Config {
+                        acl: Vec::new(),
+                        ..config.clone()
+                    });
+                }
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            // Lines like [upstream name] define a pool of upstream servers
+            if let Some(pool) = section.strip_prefix("upstream ") {
+                config
+                    .upstreams
+                    .entry(pool.trim().to_string())
+                    .or_default()
+                    .set(name, value)?;
+                continue;
+            }
+
+            let target = servers.last_mut().unwrap_or(&mut config);
+            match section.as_str() {
+                "" | "server" => target.set(name, value)?,
+                "mime_types" => {
+                    let ext = name.trim_start_matches('.').to_lowercase();
+                    target.mime_types.insert(ext, value.to_string());
+                }
+                "cgi" | "fastcgi" => {
+                    let handlers = if section == "cgi" {
+                        &mut target.cgi
+                    } else {
+                        &mut target.fastcgi
+                    };
+
+                    // An empty value disables the handler for that extension
+                    let ext = name.trim_start_matches('.').to_lowercase();
+                    if value.is_empty() {
+                        handlers.remove(&ext);
+                    } else {
+                        handlers.insert(ext, value.to_string());
+                    }
+                }
+                "proxy" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    target.proxy.insert(prefix, value.trim().to_string());
+                }
+                "client_auth" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    target
+                        .client_auth_paths
+                        .insert(prefix, value.trim().parse()?);
+                }
+                "access" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    let rules = acl::parse_path_rules(value)?;
+                    target.acl_paths.entry(prefix).or_default().extend(rules);
                 }
+                "rate_limit" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    target.rate_limits.insert(prefix, parse_rate_limit(value)?);
+                }
+                "auth" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    target.auth.insert(prefix, value.trim().parse()?);
+                }
+                "webdav" => {
+                    let prefix = format!("/{}", name.trim().trim_matches('/'));
+                    let enabled = value.trim().parse().map_err(|_| ())?;
+                    target.webdav.insert(prefix, enabled);
+                }
+                _ => (),
             }
         }
 
+        // Pools are shared by all servers, wherever they were defined
+        for server in &mut servers {
+            server.upstreams = config.upstreams.clone();
+            server.acl.extend(config.acl.iter().cloned());
+        }
+        config.servers = servers;
+
         Ok(config)
     }
+
+    /// Returns the client certificate requirement for requests to `path`.
+    pub fn client_auth_for(&self, path: &str) -> ClientAuth {
+        match_prefix(&self.client_auth_paths, path).map_or(self.client_auth, |(_, auth)| *auth)
+    }
+
+    /// Returns true if the client at `ip` may make requests to `path`.
+    pub fn acl_permits(&self, path: &str, ip: IpAddr) -> bool {
+        let rules = match_prefix(&self.acl_paths, path).map_or(&self.acl, |(_, rules)| rules);
+        acl::permits(rules, ip)
+    }
+
+    /// Returns the request limit for `path`, along with the path prefix it's for
+    /// (empty if it's for the whole server).
+    pub fn rate_limit_for(&self, path: &str) -> Option<(&str, RateLimit)> {
+        match match_prefix(&self.rate_limits, path) {
+            Some((prefix, limit)) => limit.map(|limit| (prefix.as_str(), limit)),
+            None => self.rate_limit.map(|limit| ("", limit)),
+        }
+    }
+
+    /// Returns the authentication scheme required for requests to `path`.
+    pub fn auth_for(&self, path: &str) -> AuthScheme {
+        match_prefix(&self.auth, path).map_or(AuthScheme::Off, |(_, scheme)| *scheme)
+    }
+
+    /// Returns the path prefix of the WebDAV share `path` is in, if any.
+    pub fn webdav_root(&self, path: &str) -> Option<&str> {
+        match_prefix(&self.webdav, path)
+            .filter(|(_, enabled)| **enabled)
+            .map(|(prefix, _)| prefix.as_str())
+    }
+
+    /// Returns the settings of the server whose `server_name` matches `host`
+    /// (the value of a Host field), or the top-level settings if none do.
+    ///
+    /// Exact names are preferred over wildcards, and among wildcards the
+    /// longest leading wildcard (`*.example.com`) wins over the longest
+    /// trailing wildcard (`example.*`).
+    pub fn virtual_host(&self, host: Option<&str>) -> &Config {
+        let Some(host) = host else {
+            return self;
+        };
+        let host = host_without_port(host).trim_end_matches('.').to_lowercase();
+
+        let names = || {
+            self.servers
+                .iter()
+                .flat_map(|server| server.server_names.iter().map(move |name| (name, server)))
+        };
+
+        let exact = names().find(|(name, _)| **name == host);
+        let leading = || {
+            names()
+                .filter(|(name, _)| {
+                    name.strip_prefix('*')
+                        .is_some_and(|suffix| suffix.starts_with('.') && host.ends_with(suffix))
+                })
+                .max_by_key(|(name, _)| name.len())
+        };
+        let trailing = || {
+            names()
+                .filter(|(name, _)| {
+                    name.strip_suffix('*')
+                        .is_some_and(|prefix| prefix.ends_with('.') && host.starts_with(prefix))
+                })
+                .max_by_key(|(name, _)| name.len())
+        };
+
+        exact
+            .or_else(leading)
+            .or_else(trailing)
+            .map_or(self, |(_, server)| server)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ()> {
+        match name {
+            "max_connections" => self.max_connections = value.parse().map_err(|_| ())?,
+            "max_connections_per_ip" => {
+                self.max_connections_per_ip = value.parse().map_err(|_| ())?;
+            }
+            "rate_limit" => self.rate_limit = parse_rate_limit(value)?,
+            "max_header_len" => self.max_header_len = value.parse().map_err(|_| ())?,
+            "max_body_len" => self.max_body_len = value.parse().map_err(|_| ())?,
+            "max_upload_len" => self.max_upload_len = value.parse().map_err(|_| ())?,
+            "max_timeout" => self.max_timeout = value.parse().map_err(|_| ())?,
+            "ip" => self.ip = value.to_string(),
+            "port_http" => self.port_http = value.parse().map_err(|_| ())?,
+            "port_https" => self.port_https = value.parse().map_err(|_| ())?,
+            "https_enabled" => self.https_enabled = value.parse().map_err(|_| ())?,
+            "https_redirect" => {
+                self.https_redirect = match value {
+                    "off" => None,
+                    "301" => Some(HttpStatusCode::MovedPermanently),
+                    "308" => Some(HttpStatusCode::PermanentRedirect),
+                    _ => return Err(()),
+                }
+            }
+            "hsts_max_age" => self.hsts_max_age = value.parse().map_err(|_| ())?,
+            "hsts_subdomains" => self.hsts_subdomains = value.parse().map_err(|_| ())?,
+            "access_log" => self.access_log = value.to_string(),
+            "access_log_format" => self.access_log_format = value.parse()?,
+            "error_log" => self.error_log = value.to_string(),
+            "log_level" => self.log_level = value.parse()?,
+            "cert_dir" => self.cert_dir = value.to_string(),
+            "client_ca" => self.client_ca = value.to_string(),
+            "client_auth" => self.client_auth = value.parse()?,
+            "allow" => self.acl.extend(acl::parse_rules(value, true)?),
+            "deny" => self.acl.extend(acl::parse_rules(value, false)?),
+            "auth_file" => self.auth_file = value.to_string(),
+            "auth_realm" => self.auth_realm = value.to_string(),
+            "server_root" => self.server_root = value.to_string(),
+            "charset" => self.charset = value.to_string(),
+            "index" => self.index = value.split_whitespace().map(String::from).collect(),
+            "autoindex" => self.autoindex = value.parse().map_err(|_| ())?,
+            "autoindex_hidden" => self.autoindex_hidden = value.parse().map_err(|_| ())?,
+            "compression" => self.compression = value.parse().map_err(|_| ())?,
+            "compression_min_len" => {
+                self.compression_min_len = value.parse().map_err(|_| ())?;
+            }
+            "cgi_bin" => self.cgi_bin = value.to_string(),
+            "server_name" => {
+                self.server_names = value
+                    .split_whitespace()
+                    .map(|name| name.trim_end_matches('.').to_lowercase())
+                    .collect();
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a request limit like `10/s`, or `off` for none.
+fn parse_rate_limit(value: &str) -> Result<Option<RateLimit>, ()> {
+    match value.trim() {
+        "off" => Ok(None),
+        value => value.parse().map(Some),
+    }
+}
+
+/// Returns the entry of `map` with the longest key that is a prefix of `path`,
+/// matching whole path segments only.
+pub fn match_prefix<'a, T>(map: &'a HashMap<String, T>, path: &str) -> Option<(&'a String, &'a T)> {
+    map.iter()
+        .filter(|(prefix, _)| {
+            path.strip_prefix(prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             max_connections: 10,
+            max_connections_per_ip: 0,
+            rate_limit: None,
+            rate_limits: HashMap::new(),
             max_header_len: 8 * 1024,
             max_body_len: 1024 * 1024,
+            max_upload_len: 1024 * 1024 * 1024,
             max_timeout: 5,
             ip: String::from("127.0.0.1"),
             port_http: 1337,
             port_https: 31337,
             https_enabled: true,
+            https_redirect: None,
+            hsts_max_age: 0,
+            hsts_subdomains: false,
+            access_log: String::new(),
+            access_log_format: Format::Combined,
+            error_log: String::new(),
+            log_level: Level::Info,
+            cert_dir: String::new(),
+            client_ca: String::new(),
+            client_auth: ClientAuth::Off,
+            client_auth_paths: HashMap::new(),
+            acl: Vec::new(),
+            acl_paths: HashMap::new(),
+            auth: HashMap::new(),
+            auth_file: String::new(),
+            auth_realm: String::from("Helios"),
+            webdav: HashMap::new(),
             server_root: String::from("/var/www"),
+            charset: String::from("utf-8"),
+            index: ["index.html", "index.htm", "index.php"]
+                .map(String::from)
+                .to_vec(),
+            autoindex: false,
+            autoindex_hidden: false,
+            compression: false,
+            compression_min_len: 1024,
+            mime_types: HashMap::new(),
+            cgi: HashMap::from([(String::from("php"), String::from("php-cgi"))]),
+            fastcgi: HashMap::new(),
+            proxy: HashMap::new(),
+            upstreams: HashMap::new(),
+            cgi_bin: String::new(),
+            server_names: Vec::new(),
+            servers: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Config {
+        Config::parse(config.lines().map(String::from)).unwrap()
+    }
+
+    #[test]
+    fn test_servers() {
+        let config = parse(
+            "server_root=/var/www\n\
+             charset=utf-8\n\
+             [cgi]\n\
+             py=python3\n\
+             [server]\n\
+             server_name=example.com www.example.com\n\
+             server_root=/var/www/example\n\
+             [cgi]\n\
+             php=\n\
+             [server]\n\
+             server_name=Blog.Example.COM.\n\
+             server_root=/var/www/blog\n\
+             charset=latin1\n\
+             [upstream backend]\n\
+             server=127.0.0.1:8080",
+        );
+
+        assert_eq!(config.server_root, "/var/www");
+        assert_eq!(config.servers.len(), 2);
+
+        // Inherits top-level settings, and sections apply to the last server
+        let example = &config.servers[0];
+        assert_eq!(example.server_root, "/var/www/example");
+        assert_eq!(example.charset, "utf-8");
+        assert!(example.cgi.contains_key("py"));
+        assert!(!example.cgi.contains_key("php"));
+        assert!(config.cgi.contains_key("php"));
+
+        let blog = &config.servers[1];
+        assert_eq!(blog.server_names, ["blog.example.com"]);
+        assert_eq!(blog.charset, "latin1");
+        assert!(blog.upstreams.contains_key("backend"));
+        assert!(example.upstreams.contains_key("backend"));
+    }
+
+    #[test]
+    fn test_virtual_host() {
+        let config = parse(
+            "server_root=default\n\
+             [server]\n\
+             server_name=example.com\n\
+             server_root=exact\n\
+             [server]\n\
+             server_name=*.example.com\n\
+             server_root=leading\n\
+             [server]\n\
+             server_name=*.blog.example.com\n\
+             server_root=longer\n\
+             [server]\n\
+             server_name=example.* [::1]\n\
+             server_root=trailing",
+        );
+        let root = |host| &config.virtual_host(host).server_root;
+
+        assert_eq!(root(Some("example.com")), "exact");
+        assert_eq!(root(Some("EXAMPLE.com.:8080")), "exact");
+        assert_eq!(root(Some("www.example.com")), "leading");
+        assert_eq!(root(Some("me.blog.example.com")), "longer");
+        assert_eq!(root(Some("example.org")), "trailing");
+        assert_eq!(root(Some("[::1]:1337")), "trailing");
+
+        // Default
+        assert_eq!(root(Some("example")), "default");
+        assert_eq!(root(Some("notexample.com")), "default");
+        assert_eq!(root(None), "default");
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let config = parse(
+            "client_auth=optional\n\
+             [client_auth]\n\
+             /admin/=required\n\
+             /admin/public=off",
+        );
+
+        assert_eq!(config.client_auth_for("/"), ClientAuth::Optional);
+        assert_eq!(config.client_auth_for("/admin"), ClientAuth::Required);
+        assert_eq!(config.client_auth_for("/admin/users"), ClientAuth::Required);
+        assert_eq!(config.client_auth_for("/admin/public/x"), ClientAuth::Off);
+        assert_eq!(
+            config.client_auth_for("/administrator"),
+            ClientAuth::Optional
+        );
+
+        assert!(Config::parse(["client_auth=maybe".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_auth() {
+        let config = parse(
+            "[auth]\n\
+             /admin=digest\n\
+             /private/=basic\n\
+             /private/public=off",
+        );
+
+        assert_eq!(config.auth_for("/"), AuthScheme::Off);
+        assert_eq!(config.auth_for("/admin/users"), AuthScheme::Digest);
+        assert_eq!(config.auth_for("/private"), AuthScheme::Basic);
+        assert_eq!(config.auth_for("/private/public/x"), AuthScheme::Off);
+        assert_eq!(config.auth_realm, "Helios");
+
+        assert!(Config::parse(["[auth]".to_string(), "/=ntlm".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let config = parse(
+            "allow=192.0.2.0/24\n\
+             [access]\n\
+             /admin=allow 10.0.0.0/8 fd00::/8\n\
+             /admin=deny all\n\
+             [server]\n\
+             deny=all",
+        );
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert!(config.acl_permits("/", ip("203.0.113.9")));
+        assert!(config.acl_permits("/admin/users", ip("10.0.0.1")));
+        assert!(config.acl_permits("/admin", ip("fd00::1")));
+        assert!(!config.acl_permits("/admin", ip("192.0.2.1")));
+
+        // Servers have rules of their own, checked before the top-level ones
+        let server = &config.servers[0];
+        assert!(!server.acl_permits("/", ip("192.0.2.1")));
+        assert!(server.acl_permits("/admin", ip("10.0.0.1")));
+
+        assert!(Config::parse(["deny=10.0.0.0/42".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_acl_inherited() {
+        let config = parse(
+            "deny=203.0.113.0/24\n\
+             [server]\n\
+             server_name=a.test\n\
+             [server]\n\
+             server_name=b.test\n\
+             allow=203.0.113.7\n\
+             deny=198.51.100.0/24",
+        );
+        let ip = |s: &str| s.parse().unwrap();
+
+        // Servers without rules of their own keep clients out like the top level does
+        let a = &config.servers[0];
+        assert!(!a.acl_permits("/", ip("203.0.113.9")));
+        assert!(a.acl_permits("/", ip("198.51.100.1")));
+
+        let b = &config.servers[1];
+        assert!(b.acl_permits("/", ip("203.0.113.7")));
+        assert!(!b.acl_permits("/", ip("203.0.113.9")));
+        assert!(!b.acl_permits("/", ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = parse(
+            "rate_limit=10/s 20\n\
+             [rate_limit]\n\
+             /login=5/m\n\
+             /static=off",
+        );
+        let limit = |path| {
+            config
+                .rate_limit_for(path)
+                .map(|(prefix, limit)| (prefix, limit.burst))
+        };
+
+        assert_eq!(limit("/"), Some(("", 20.0)));
+        assert_eq!(limit("/login"), Some(("/login", 5.0)));
+        assert_eq!(limit("/static/app.js"), None);
+
+        assert!(Config::parse(["rate_limit=fast".to_string()].into_iter()).is_err());
+    }
+}
@@ -1,43 +1,121 @@
+use crate::acl;
+use crate::chunked;
 use crate::config::Config;
 use crate::http::*;
+use crate::http2;
+use crate::limit;
+use crate::log::{self, debug, error, info, warning};
 use crate::response::*;
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use crate::tls::{ClientCert, Tls};
+use crate::upstream;
+use crate::webdav;
+use chrono::{DateTime, Local};
 use std::future::pending;
-use std::net::SocketAddr;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Semaphore};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    time::{timeout, Duration},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    time::{timeout, Duration, Instant},
 };
-use tokio_rustls::{rustls, TlsAcceptor};
 use tokio_util::either::Either;
 
+/// Size of the pieces streamed request bodies are read in.
+const UPLOAD_CHUNK_LEN: usize = 64 * 1024;
+
+/// Pieces of a streamed request body read ahead of whoever handles it.
+const UPLOAD_BUFFER: usize = 4;
+
+/// What we know about the client on the other end of a connection.
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub https: bool,
+
+    /// Certificate the client presented during the TLS handshake, if asked for one.
+    pub client_cert: Option<ClientCert>,
+}
+
+/// Sends a response, returning how many bytes of body were sent.
 async fn send_response(
     stream: &mut BufReader<impl AsyncWriteExt + AsyncReadExt + Unpin>,
     response: HttpMessage,
-) -> std::io::Result<()> {
-    stream.write_all(&Vec::from(response)).await.map_err(|e| {
-        eprintln!("Error writing to stream: {e}");
+) -> std::io::Result<u64> {
+    let chunked = response.is_chunked();
+    let HttpMessage { header, body } = response;
+
+    let result = async {
+        stream.write_all(header.to_string().as_bytes()).await?;
+        let sent = match body {
+            Some(HttpBody::Full(data)) => {
+                stream.write_all(&data).await?;
+                data.len() as u64
+            }
+            Some(HttpBody::Stream { mut reader, .. }) if chunked => {
+                chunked::write_body(&mut reader, stream).await?
+            }
+            Some(HttpBody::Stream { reader, len }) => {
+                let len = len.unwrap_or(u64::MAX);
+                let sent = tokio::io::copy(&mut reader.take(len), stream).await?;
+
+                // Body got shorter since we sent the header, so the client will be left hanging
+                if len != u64::MAX && sent != len {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                sent
+            }
+            None => 0,
+        };
+        stream.flush().await?;
+        Ok(sent)
+    }
+    .await;
+
+    result.map_err(|e| {
+        error!("Error writing to stream: {e}");
         e
     })
 }
 
-async fn create_and_send_err_response(
+/// Answers a request that goes no further with an error, logging it like any other.
+/// `header` is None if the request didn't get as far as being parsed.
+async fn send_error_response(
     config: &Config,
     stream: &mut BufReader<impl AsyncWriteExt + AsyncReadExt + Unpin>,
     status_code: HttpStatusCode,
-) -> std::io::Result<()> {
-    let response = create_error_response(config, status_code).await;
-    send_response(stream, response).await
+    addr: IpAddr,
+    header: Option<&HttpHeader>,
+    time: DateTime<Local>,
+    started: Instant,
+) {
+    let mut response = create_error_response(config, status_code).await;
+    if status_code == HttpStatusCode::TooManyRequests {
+        response
+            .header
+            .field_lines
+            .insert(String::from("Retry-After"), config.max_timeout.to_string());
+    }
+
+    let sent = send_response(stream, response).await;
+    log::access(&log::Access {
+        addr,
+        header,
+        status: u16::from(status_code),
+        bytes: sent.unwrap_or(0),
+        time,
+        duration: started.elapsed(),
+    });
 }
 
+/// Reads a request header, failing with the status to answer with,
+/// or with None if the client is gone.
 async fn read_header(
     config: &Config,
     stream: &mut BufReader<impl AsyncWriteExt + AsyncReadExt + Unpin>,
-) -> Result<String, ()> {
+) -> Result<String, Option<HttpStatusCode>> {
     let mut header = String::new();
     let read_timeout = Duration::from_secs(config.max_timeout);
 
@@ -45,220 +123,534 @@ async fn read_header(
     while !header.ends_with("\r\n\r\n") {
         match timeout(read_timeout, stream.read_line(&mut header)).await {
             Ok(Ok(0)) => {
-                println!("Connection closed by client...");
-                return Err(());
+                debug!("Connection closed by client...");
+                return Err(None);
             }
             Ok(Err(e)) => {
-                eprintln!("Error reading from stream: {e}");
-                let _ = create_and_send_err_response(
-                    config,
-                    stream,
-                    HttpStatusCode::InternalServorError,
-                )
-                .await;
-                return Err(());
+                error!("Error reading from stream: {e}");
+                return Err(Some(HttpStatusCode::InternalServorError));
             }
             Err(_) => {
-                println!("Timeout, closing connection...");
-                let _ =
-                    create_and_send_err_response(config, stream, HttpStatusCode::RequestTimeout)
-                        .await;
-                return Err(());
+                debug!("Timeout, closing connection...");
+                return Err(Some(HttpStatusCode::RequestTimeout));
             }
             _ => (),
         }
 
         if header.len() > config.max_header_len {
-            let _ =
-                create_and_send_err_response(config, stream, HttpStatusCode::ContentTooLarge).await;
-            return Err(());
+            return Err(Some(HttpStatusCode::ContentTooLarge));
         }
     }
 
     Ok(header)
 }
 
+/// Reads a request body of `length` bytes, failing like `read_header`.
 async fn read_body(
     config: &Config,
     stream: &mut BufReader<impl AsyncWriteExt + AsyncReadExt + Unpin>,
     length: usize,
-) -> Result<Vec<u8>, ()> {
+) -> Result<Vec<u8>, Option<HttpStatusCode>> {
     let read_timeout = Duration::from_secs(config.max_timeout);
     let mut body = vec![0; length];
+    if length == 0 {
+        return Ok(body);
+    }
 
     match timeout(read_timeout, stream.read_exact(&mut body)).await {
         Ok(Ok(0)) => {
-            println!("Connection closed by client...");
-            Err(())
+            debug!("Connection closed by client...");
+            Err(None)
         }
         Ok(Err(e)) => {
-            eprintln!("Error reading from stream: {e}");
-            let _ =
-                create_and_send_err_response(config, stream, HttpStatusCode::InternalServorError)
-                    .await;
-            Err(())
+            error!("Error reading from stream: {e}");
+            Err(Some(HttpStatusCode::InternalServorError))
         }
         Err(_) => {
-            println!("Timeout, closing connection...");
-            let _ =
-                create_and_send_err_response(config, stream, HttpStatusCode::RequestTimeout).await;
-            Err(())
+            debug!("Timeout, closing connection...");
+            Err(Some(HttpStatusCode::RequestTimeout))
         }
         _ => Ok(body),
     }
 }
 
-async fn handle_connection(
+/// Reads a chunked request body, failing like `read_header`.
+async fn read_chunked_body(
+    config: &Config,
+    stream: &mut BufReader<impl AsyncWriteExt + AsyncReadExt + Unpin>,
+) -> Result<Vec<u8>, Option<HttpStatusCode>> {
+    let read_timeout = Duration::from_secs(config.max_timeout);
+
+    let status_code = match timeout(
+        read_timeout,
+        chunked::read_body(stream, config.max_body_len),
+    )
+    .await
+    {
+        Ok(Ok(body)) => return Ok(body),
+        Ok(Err(chunked::Error::Closed)) => {
+            debug!("Connection closed by client...");
+            return Err(None);
+        }
+        Ok(Err(chunked::Error::Io(e))) => {
+            error!("Error reading from stream: {e}");
+            HttpStatusCode::InternalServorError
+        }
+        Ok(Err(chunked::Error::Malformed)) => HttpStatusCode::BadRequest,
+        Ok(Err(chunked::Error::TooLarge)) => HttpStatusCode::ContentTooLarge,
+        Err(_) => {
+            debug!("Timeout, closing connection...");
+            HttpStatusCode::RequestTimeout
+        }
+    };
+
+    Err(Some(status_code))
+}
+
+/// Reads a streamed request body of `len` bytes (or until it ends, if chunked) into
+/// `chunks` for whoever handles the request, failing it if it's larger than `max_len`.
+/// Returns true if the whole body was read and passed on.
+async fn stream_body(
     config: &Config,
+    mut body: impl AsyncRead + Unpin,
+    len: Option<u64>,
+    max_len: u64,
+    chunks: mpsc::Sender<std::io::Result<Vec<u8>>>,
+) -> bool {
+    let read_timeout = Duration::from_secs(config.max_timeout);
+    let mut read = 0;
+
+    loop {
+        let mut chunk = vec![0; UPLOAD_CHUNK_LEN];
+        let result = match timeout(read_timeout, body.read(&mut chunk)).await {
+            Ok(Ok(0)) if len.is_some_and(|len| read < len) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(0)) => return true,
+            Ok(Ok(n)) if read + n as u64 > max_len => Err(ErrorKind::FileTooLarge.into()),
+            Ok(Ok(n)) => {
+                read += n as u64;
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                debug!("Timeout reading request body...");
+                Err(ErrorKind::TimedOut.into())
+            }
+        };
+
+        let failed = result.is_err();
+        if chunks.send(result).await.is_err() || failed {
+            return false;
+        }
+    }
+}
+
+/// Returns true if the client at `addr` may connect at all. Those that aren't welcome
+/// don't get a word out of us, not even a TLS handshake.
+fn admits(config: &Config, addr: SocketAddr) -> bool {
+    let permitted = acl::permits(&config.acl, addr.ip());
+    if !permitted {
+        info!("Dropping connection from {addr}, which isn't allowed");
+    }
+    permitted
+}
+
+async fn handle_connection(
+    config: Arc<Config>,
     stream: impl AsyncWriteExt + AsyncReadExt + Unpin,
     addr: SocketAddr,
+    https: bool,
+    client_cert: Option<ClientCert>,
+    h2: bool,
     conn_sem: Arc<Semaphore>,
 ) {
     let mut stream = BufReader::new(stream);
-    if conn_sem.try_acquire().is_err() {
-        println!("Server overloaded, ignoring connection.");
-        let _ =
-            create_and_send_err_response(config, &mut stream, HttpStatusCode::ServiceUnavailable)
-                .await;
+
+    // No client may take up all of our connections either
+    let Some(_connection) = limit::open_connection(addr.ip(), config.max_connections_per_ip) else {
+        info!(
+            "Too many connections from {}, ignoring connection.",
+            addr.ip()
+        );
+        let status_code = HttpStatusCode::TooManyRequests;
+        let (time, started) = (Local::now(), Instant::now());
+        send_error_response(
+            &config,
+            &mut stream,
+            status_code,
+            addr.ip(),
+            None,
+            time,
+            started,
+        )
+        .await;
         return;
-    }
+    };
+
+    // The permit is held until the connection is closed
+    let Ok(_permit) = conn_sem.try_acquire() else {
+        warning!("Server overloaded, ignoring connection.");
+        let status_code = HttpStatusCode::ServiceUnavailable;
+        let (time, started) = (Local::now(), Instant::now());
+        send_error_response(
+            &config,
+            &mut stream,
+            status_code,
+            addr.ip(),
+            None,
+            time,
+            started,
+        )
+        .await;
+        return;
+    };
 
-    println!("Handling connection from {addr}...");
+    debug!("Handling connection from {addr}...");
+    let conn = ConnectionInfo {
+        addr,
+        https,
+        client_cert,
+    };
+
+    // Clients that know we speak HTTP/2 may skip straight to it on the plain port (h2c)
+    let read_timeout = Duration::from_secs(config.max_timeout);
+    let prior_knowledge = !https
+        && timeout(read_timeout, stream.fill_buf())
+            .await
+            .is_ok_and(|buf| buf.is_ok_and(|buf| buf.starts_with(b"PRI ")));
+    if h2 || prior_knowledge {
+        http2::handle_connection(config, stream, conn).await;
+        return;
+    }
+    let config = &*config;
+    let ip = conn.addr.ip();
 
     // Loop until timeout or EOF (unless keep-alive is disabled)
     'connection: loop {
         // Read and parse header
-        let Ok(header) = read_header(config, &mut stream).await else {
-            let _ = create_and_send_err_response(
-                config,
-                &mut stream,
-                HttpStatusCode::InternalServorError,
-            )
-            .await;
-            break 'connection;
+        // Failing that, the time spent waiting on the header is what gets logged
+        let (time, started) = (Local::now(), Instant::now());
+        let header = match read_header(config, &mut stream).await {
+            Ok(header) => header,
+            Err(status_code) => {
+                if let Some(status_code) = status_code {
+                    send_error_response(config, &mut stream, status_code, ip, None, time, started)
+                        .await;
+                }
+                break 'connection;
+            }
         };
+        let (time, started) = (Local::now(), Instant::now());
 
-        // If header malformed, send error response and close connection
+        // Malformed headers (or responses instead of requests) get an error and the connection closed
         let header = match header.parse::<HttpHeader>() {
-            Ok(header) => header,
-            Err(e) => {
-                let status_code = match e {
-                    Error::UnsupportedMethod => HttpStatusCode::NotImplemented,
-                    Error::UnsupportedVersion => HttpStatusCode::HTTPVersionNotSupported,
+            Ok(header) if header.is_request() => header,
+            result => {
+                let status_code = match result {
+                    Err(Error::UnsupportedVersion) => HttpStatusCode::HTTPVersionNotSupported,
                     _ => HttpStatusCode::BadRequest,
                 };
 
-                let _ = create_and_send_err_response(config, &mut stream, status_code).await;
+                send_error_response(config, &mut stream, status_code, ip, None, time, started)
+                    .await;
                 break 'connection;
             }
         };
 
-        // Client sent us a response? Ignore.
-        if !header.is_request() {
-            let _ =
-                create_and_send_err_response(config, &mut stream, HttpStatusCode::BadRequest).await;
+        // HTTP/1.1 requests must say which host they are for, and only once
+        let hosts = header
+            .field_lines
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("host"))
+            .count();
+        if hosts > 1 || (hosts == 0 && header.request_line().http_version == HttpVersion::HTTP11) {
+            let status_code = HttpStatusCode::BadRequest;
+            send_error_response(
+                config,
+                &mut stream,
+                status_code,
+                ip,
+                Some(&header),
+                time,
+                started,
+            )
+            .await;
             break 'connection;
-        };
+        }
 
-        // If request contains body, read it
-        let body = if let Some(length) = header.field_lines.get("content-length") {
-            let Ok(length) = length.parse() else {
-                let _ =
-                    create_and_send_err_response(config, &mut stream, HttpStatusCode::BadRequest)
-                        .await;
-                break 'connection;
-            };
+        // Some clients aren't allowed on some servers or paths, and needn't send a body
+        if !permits_request(config, &header, ip) {
+            let status_code = HttpStatusCode::Forbidden;
+            send_error_response(
+                config,
+                &mut stream,
+                status_code,
+                ip,
+                Some(&header),
+                time,
+                started,
+            )
+            .await;
+            break 'connection;
+        }
 
-            if length > config.max_body_len {
-                let _ = create_and_send_err_response(
+        /* Transfer-Encoding takes precedence over Content-Length, but a request
+         * with both (or with Transfer-Encoding in HTTP/1.0) is a red flag for
+         * request smuggling, so we don't trust the connection afterwards.
+         */
+        let faulty_framing = header.field_lines.contains_key("transfer-encoding")
+            && (header.field_lines.contains_key("content-length")
+                || header.request_line().http_version == HttpVersion::HTTP10);
+
+        // How the body (if any) is delimited, with chunked the only transfer coding we understand
+        let chunked = match header.field_lines.get("transfer-encoding") {
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+            Some(_) => {
+                let status_code = HttpStatusCode::NotImplemented;
+                send_error_response(
                     config,
                     &mut stream,
-                    HttpStatusCode::ContentTooLarge,
+                    status_code,
+                    ip,
+                    Some(&header),
+                    time,
+                    started,
                 )
                 .await;
                 break 'connection;
             }
-            match read_body(config, &mut stream, length).await {
-                Ok(body) => Some(body),
-                Err(_) => break 'connection,
+            None => false,
+        };
+        let length = match header.content_length() {
+            _ if chunked => None,
+            Ok(length) => length,
+            Err(_) => {
+                let status_code = HttpStatusCode::BadRequest;
+                send_error_response(
+                    config,
+                    &mut stream,
+                    status_code,
+                    ip,
+                    Some(&header),
+                    time,
+                    started,
+                )
+                .await;
+                break 'connection;
             }
+        };
+
+        // WebDAV uploads go to disk as they arrive, so they may be larger than other bodies
+        let host = header.field_lines.get("host").map(String::as_str);
+        let streamed = (chunked || length.is_some())
+            && webdav::streams_body(config.virtual_host(host), &header);
+        let max_len = if streamed {
+            config.max_upload_len
         } else {
-            None
+            config.max_body_len as u64
         };
+        if length.is_some_and(|length| length > max_len) {
+            let status_code = HttpStatusCode::ContentTooLarge;
+            send_error_response(
+                config,
+                &mut stream,
+                status_code,
+                ip,
+                Some(&header),
+                time,
+                started,
+            )
+            .await;
+            break 'connection;
+        }
 
-        // Perform what is asked from request
-        let request = HttpMessage { header, body };
-        let response = process_request(config, &request).await;
-        if send_response(&mut stream, response).await.is_err() || !request.header.is_persistent() {
+        /* Clients uploading a body may wait for our go-ahead before sending it,
+         * which they only get once we know we'll take it (and can trust its framing).
+         */
+        let expects_continue = header
+            .field_lines
+            .get("expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        if expects_continue
+            && !faulty_framing
+            && header.request_line().http_version == HttpVersion::HTTP11
+            && stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .is_err()
+        {
             break 'connection;
         }
-    }
-}
 
-async fn init_https(config: &'static Config) -> Result<(TcpListener, TlsAcceptor), ()> {
-    let certs =
-        match CertificateDer::pem_file_iter(format!("{}/crypt/public.pem", config.server_root)) {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Error opening certificate file: {e}");
-                return Err(());
+        // Perform what is asked from request, reading its body first unless streamed
+        let mut request = HttpMessage { header, body: None };
+        let (mut response, body_read) = if streamed {
+            let (chunks, receiver) = mpsc::channel(UPLOAD_BUFFER);
+            request.body = Some(HttpBody::Stream {
+                reader: Box::new(ChannelReader::new(receiver)),
+                len: length,
+            });
+
+            let body = match length {
+                Some(length) => Either::Left((&mut stream).take(length)),
+                None => Either::Right(chunked::Decoder::new(&mut stream)),
+            };
+            let upload = stream_body(config, body, length, max_len, chunks);
+            let process = process_request(config, &conn, &mut request);
+            tokio::pin!(upload, process);
+
+            // Whatever of the body isn't read once the response is ready is left unread
+            let mut uploaded = false;
+            loop {
+                tokio::select! {
+                    response = &mut process => break (response, uploaded),
+                    done = &mut upload, if !uploaded => {
+                        if !done {
+                            // The handler has been told, and will answer accordingly
+                            break (process.await, false);
+                        }
+                        uploaded = true;
+                    }
+                }
             }
+        } else {
+            let body = match (chunked, length) {
+                (true, _) => Some(read_chunked_body(config, &mut stream).await),
+                (false, Some(length)) => {
+                    Some(read_body(config, &mut stream, length as usize).await)
+                }
+                (false, None) => None,
+            };
+            let body = match body.transpose() {
+                Ok(body) => body,
+                Err(status_code) => {
+                    if let Some(status_code) = status_code {
+                        let header = Some(&request.header);
+                        send_error_response(
+                            config,
+                            &mut stream,
+                            status_code,
+                            ip,
+                            header,
+                            time,
+                            started,
+                        )
+                        .await;
+                    }
+                    break 'connection;
+                }
+            };
+
+            request.body = body.map(HttpBody::Full);
+            (process_request(config, &conn, &mut request).await, true)
         };
-    let certs = match certs.collect::<Result<Vec<_>, _>>() {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("Error collecting certificates: {e}");
-            return Err(());
+
+        // HTTP/1.0 clients don't understand chunks, so the body just ends when we close
+        let close_delimited = response.is_chunked()
+            && request.header.request_line().http_version == HttpVersion::HTTP10;
+        if close_delimited {
+            let fields = &mut response.header.field_lines;
+            fields.remove("Transfer-Encoding");
+            fields.insert(String::from("Connection"), String::from("close"));
         }
-    };
 
-    let key =
-        match PrivateKeyDer::from_pem_file(format!("{}/crypt/private.pem", config.server_root)) {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Error opening private key file: {e}");
-                return Err(());
-            }
-        };
-    let config_s = match rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-    {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Error configuring TLS: {e}");
-            return Err(());
+        let status = u16::from(response.header.status_line().status_code);
+        let sent = send_response(&mut stream, response).await;
+        log::access(&log::Access {
+            addr: ip,
+            header: Some(&request.header),
+            status,
+            bytes: *sent.as_ref().unwrap_or(&0),
+            time,
+            duration: started.elapsed(),
+        });
+
+        if sent.is_err()
+            || !request.header.is_persistent()
+            || faulty_framing
+            || close_delimited
+            || !body_read
+        {
+            break 'connection;
         }
-    };
-    let acceptor = TlsAcceptor::from(Arc::new(config_s));
+    }
+}
+
+async fn init_https(config: &Config) -> Result<(TcpListener, Tls), ()> {
+    let tls = Tls::new(config)?;
     let addr = format!("{}:{}", config.ip, config.port_https);
     let listener = match TcpListener::bind(&addr).await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!("Error binding HTTPS to address: {e}");
+            error!("Error binding HTTPS to address: {e}");
             return Err(());
         }
     };
-    println!("Listening on {addr} for HTTPS...");
+    info!("Listening on {addr} for HTTPS...");
+
+    Ok((listener, tls))
+}
+
+/// Re-reads the config file at `path` (if any) and the certificates it points to,
+/// returning the new config and TLS settings if everything is valid.
+fn reload(path: Option<&Path>, https_enabled: bool) -> Result<(Config, Option<Tls>), ()> {
+    let config = match path {
+        Some(path) => Config::from_file(path).map_err(|_| {
+            error!(
+                "Error reloading configuration: Invalid config file {}",
+                path.display()
+            );
+        })?,
+        None => Config::default(),
+    };
+
+    // Listeners are only set up on startup, so their settings can't change
+    if config.https_enabled != https_enabled {
+        warning!("Enabling or disabling HTTPS requires a restart");
+    }
+
+    let tls = if https_enabled {
+        let tls = Tls::new(&config).map_err(|_| {
+            error!("Error reloading configuration: Invalid certificates");
+        })?;
+        Some(tls)
+    } else {
+        None
+    };
+
+    log::init(&config).map_err(|_| {
+        error!("Error reloading configuration: Invalid log files");
+    })?;
 
-    Ok((listener, acceptor))
+    Ok((config, tls))
 }
 
-pub async fn handle_connections(config: &'static Config, conn_sem: Arc<Semaphore>) {
+/// Accepts connections until an unrecoverable error occurs.
+///
+/// On SIGHUP, the config file at `config_path` is re-read along with any certificates,
+/// and used for new connections from then on (connections already open keep the
+/// config they started with). If anything about it is invalid, the current config
+/// is kept instead. On SIGUSR1, log files are opened again.
+pub async fn handle_connections(
+    config_path: Option<PathBuf>,
+    mut config: Arc<Config>,
+    conn_sem: Arc<Semaphore>,
+) {
     // Initialize HTTP
     let addr = format!("{}:{}", config.ip, config.port_http);
     let listener = match TcpListener::bind(&addr).await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!("Error binding HTTP to address: {e}");
+            error!("Error binding HTTP to address: {e}");
             return;
         }
     };
-    println!("Listening on {addr} for HTTP...");
+    info!("Listening on {addr} for HTTP...");
 
     // Initialize HTTPS if enabled
-    let https: Option<(TcpListener, TlsAcceptor)> = if config.https_enabled {
-        let Ok(s) = init_https(config).await else {
+    let https_enabled = config.https_enabled;
+    let mut https: Option<(TcpListener, Tls)> = if https_enabled {
+        let Ok(s) = init_https(&config).await else {
             return;
         };
         Some(s)
@@ -266,6 +658,21 @@ pub async fn handle_connections(config: &'static Config, conn_sem: Arc<Semaphore
         None
     };
 
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error installing SIGHUP handler: {e}");
+            return;
+        }
+    };
+    let mut user1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Error installing SIGUSR1 handler: {e}");
+            return;
+        }
+    };
+
     // Handle connections
     loop {
         tokio::select! {
@@ -274,20 +681,26 @@ pub async fn handle_connections(config: &'static Config, conn_sem: Arc<Semaphore
                 let (stream, addr) = match connection {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("Error handling incoming HTTP connection: {e}");
+                        error!("Error handling incoming HTTP connection: {e}");
                         continue;
                     }
                 };
+                if !admits(&config, addr) {
+                    continue;
+                }
                 tokio::spawn(handle_connection(
-                    config,
+                    Arc::clone(&config),
                     stream,
                     addr,
+                    false,
+                    None,
+                    false,
                     Arc::clone(&conn_sem),
                 ));
             }
 
             // Handle HTTPS connections if enabled, otherwise this future never returns
-            connection = if config.https_enabled {
+            connection = if https_enabled {
                 let listener = &https.as_ref().expect("Will not fail since https is enabled").0;
                 Either::Left(listener.accept())
             } else {
@@ -296,25 +709,98 @@ pub async fn handle_connections(config: &'static Config, conn_sem: Arc<Semaphore
                 let (stream, addr) = match connection {
                     Ok(c) => c,
                     Err(e) => {
-                        eprintln!("Error handling incoming HTTPS connection: {e}");
+                        error!("Error handling incoming HTTPS connection: {e}");
                         continue;
                     }
                 };
-                let acceptor = &https.as_ref().expect("Will never get here if https is disabled").1;
-                let stream = match acceptor.accept(stream).await {
+                if !admits(&config, addr) {
+                    continue;
+                }
+                let tls = &https.as_ref().expect("Will never get here if https is disabled").1;
+                let stream = match tls.acceptor.accept(stream).await {
                     Ok(s) => s,
                     Err(e) => {
-                        eprintln!("Error creating TLS stream: {e}");
+                        error!("Error creating TLS stream: {e}");
                         continue;
                     }
                 };
+                let (_, session) = stream.get_ref();
+                let client_cert = tls.client_cert(session.peer_certificates());
+                let h2 = session.alpn_protocol() == Some(b"h2");
                 tokio::spawn(handle_connection(
-                    config,
+                    Arc::clone(&config),
                     stream,
                     addr,
+                    true,
+                    client_cert,
+                    h2,
                     Arc::clone(&conn_sem),
                 ));
             }
+
+            // Reopen logs after they were rotated
+            _ = user1.recv() => {
+                log::reopen();
+                info!("Received SIGUSR1, reopened log files.");
+            }
+
+            // Reload config and certificates
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration...");
+                let Ok((new_config, tls)) = reload(config_path.as_deref(), https_enabled) else {
+                    warning!("Keeping the current configuration.");
+                    continue;
+                };
+
+                if (&new_config.ip, new_config.port_http, new_config.port_https, new_config.max_connections)
+                    != (&config.ip, config.port_http, config.port_https, config.max_connections)
+                {
+                    warning!("Changes to addresses, ports or max_connections require a restart");
+                }
+
+                if let (Some(https), Some(tls)) = (https.as_mut(), tls) {
+                    https.1 = tls;
+                }
+                upstream::reload(&new_config);
+                config = Arc::new(new_config);
+                info!("Configuration reloaded.");
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::ChannelReader;
+
+    async fn stream(
+        body: &[u8],
+        len: Option<u64>,
+        max_len: u64,
+    ) -> (bool, std::io::Result<Vec<u8>>) {
+        let (chunks, receiver) = mpsc::channel(UPLOAD_BUFFER);
+        let done = stream_body(&Config::default(), body, len, max_len, chunks).await;
+        let mut read = Vec::new();
+        let result = ChannelReader::new(receiver).read_to_end(&mut read).await;
+        (done, result.map(|_| read))
+    }
+
+    #[tokio::test]
+    async fn test_stream_body() {
+        let body = vec![b'x'; UPLOAD_CHUNK_LEN + 10];
+        let (done, read) = stream(&body, Some(body.len() as u64), 1 << 20).await;
+        assert!(done);
+        assert_eq!(read.unwrap(), body);
+
+        // Client hung up early
+        let (done, read) = stream(b"short", Some(10), 1 << 20).await;
+        assert!(!done);
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // Chunked bodies can't say how large they are up front
+        let (done, read) = stream(&body, None, 100).await;
+        assert!(!done);
+        assert_eq!(read.unwrap_err().kind(), ErrorKind::FileTooLarge);
+    }
+}
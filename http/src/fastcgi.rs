@@ -0,0 +1,562 @@
+//! This is a FastCGI client, for handing scripts off to backends like php-fpm
+//! (see https://fastcgi-archives.github.io/FastCGI_Specification.html)
+//!
+//! Connections to each backend are kept alive and reused. If a backend says it
+//! can multiplex (FCGI_MPXS_CONNS), requests share connections, otherwise each
+//! connection only carries one request at a time (which is how php-fpm works).
+//!
+//! Each connection has a task reading its records and passing stdout along to
+//! whichever request it belongs to.
+
+use crate::cgi;
+use crate::config::Config;
+use crate::http::{ChannelReader, HttpBody, HttpMessage};
+use crate::log::{error, warning};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+
+const VERSION: u8 = 1;
+
+// Record types
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+
+const RESPONDER: u8 = 1;
+const KEEP_CONN: u8 = 1;
+
+const MAX_CONTENT_LEN: usize = u16::MAX as usize;
+
+/// Most requests we will multiplex over a single connection.
+const MAX_REQUESTS_PER_CONN: usize = 16;
+
+/// Stdout records buffered per request before the connection waits for the client.
+const STDOUT_BUFFER: usize = 16;
+
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// Appends a single record, which must fit within `MAX_CONTENT_LEN`.
+fn encode_record(out: &mut Vec<u8>, kind: u8, id: u16, content: &[u8]) {
+    // Padding content to a multiple of 8 bytes is recommended
+    let padding = (8 - content.len() % 8) % 8;
+
+    out.extend([VERSION, kind]);
+    out.extend(id.to_be_bytes());
+    out.extend((content.len() as u16).to_be_bytes());
+    out.extend([padding as u8, 0]);
+    out.extend(content);
+    out.extend(std::iter::repeat_n(0, padding));
+}
+
+/// Appends a stream as many records as needed, terminated by an empty record.
+fn encode_stream(out: &mut Vec<u8>, kind: u8, id: u16, data: &[u8]) {
+    for chunk in data.chunks(MAX_CONTENT_LEN) {
+        encode_record(out, kind, id, chunk);
+    }
+    encode_record(out, kind, id, &[]);
+}
+
+async fn read_record(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Record> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).await?;
+
+    let [_version, kind, id_hi, id_lo, len_hi, len_lo, padding, _reserved] = header;
+    let mut content = vec![0; u16::from_be_bytes([len_hi, len_lo]) as usize + padding as usize];
+    reader.read_exact(&mut content).await?;
+    content.truncate(content.len() - padding as usize);
+
+    Ok(Record {
+        kind,
+        id: u16::from_be_bytes([id_hi, id_lo]),
+        content,
+    })
+}
+
+fn encode_pair_len(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend((len as u32 | 1 << 31).to_be_bytes());
+    }
+}
+
+/// Encodes name-value pairs, as sent in PARAMS and GET_VALUES records.
+fn encode_pairs<N: AsRef<str>, V: AsRef<str>>(pairs: &[(N, V)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in pairs {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        encode_pair_len(&mut out, name.len());
+        encode_pair_len(&mut out, value.len());
+        out.extend(name.as_bytes());
+        out.extend(value.as_bytes());
+    }
+    out
+}
+
+/// Decodes name-value pairs, returning `None` if malformed.
+fn decode_pairs(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn decode_len(data: &mut &[u8]) -> Option<usize> {
+        let first = *data.first()?;
+        if first < 128 {
+            *data = &data[1..];
+            Some(first as usize)
+        } else {
+            let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) & !(1 << 31);
+            *data = &data[4..];
+            Some(len as usize)
+        }
+    }
+
+    let mut pairs = Vec::new();
+    while !data.is_empty() {
+        let name_len = decode_len(&mut data)?;
+        let value_len = decode_len(&mut data)?;
+        let name = data.get(..name_len)?;
+        let value = data.get(name_len..name_len + value_len)?;
+        pairs.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+        data = &data[name_len + value_len..];
+    }
+
+    Some(pairs)
+}
+
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(Self::Unix(PathBuf::from(path)))
+        } else if s.contains(':') {
+            Ok(Self::Tcp(String::from(s)))
+        } else {
+            Err(())
+        }
+    }
+}
+
+trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+/// Where a request's stdout goes.
+type Output = mpsc::Sender<std::io::Result<Vec<u8>>>;
+
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<Box<dyn Socket>>>,
+
+    /// Requests in progress by ID.
+    requests: Mutex<HashMap<u16, Output>>,
+
+    /// Whether the backend handles several requests at once on this connection,
+    /// which we assume not until it answers our GET_VALUES query saying so.
+    multiplex: AtomicBool,
+
+    closed: AtomicBool,
+}
+
+impl Connection {
+    async fn open(address: &Address) -> std::io::Result<Arc<Self>> {
+        let socket: Box<dyn Socket> = match address {
+            Address::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            Address::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        };
+        let (reader, mut writer) = tokio::io::split(socket);
+
+        // Ask if we can multiplex, with the answer read along with everything else
+        let mut query = Vec::new();
+        encode_record(
+            &mut query,
+            GET_VALUES,
+            0,
+            &encode_pairs(&[("FCGI_MPXS_CONNS", "")]),
+        );
+        writer.write_all(&query).await?;
+
+        let conn = Arc::new(Self {
+            writer: tokio::sync::Mutex::new(writer),
+            requests: Mutex::new(HashMap::new()),
+            multiplex: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(Arc::clone(&conn).read_records(reader));
+
+        Ok(conn)
+    }
+
+    /// Passes records from the backend along to their requests until the connection closes.
+    async fn read_records(self: Arc<Self>, mut reader: ReadHalf<Box<dyn Socket>>) {
+        loop {
+            let record = match read_record(&mut reader).await {
+                Ok(record) => record,
+                Err(e) => {
+                    self.closed.store(true, Ordering::SeqCst);
+
+                    // Let anyone still waiting know their output got cut off
+                    let requests: Vec<_> = self.requests.lock().unwrap().drain().collect();
+                    for (_, output) in requests {
+                        let _ = output.try_send(Err(std::io::Error::new(e.kind(), e.to_string())));
+                    }
+                    return;
+                }
+            };
+
+            match record.kind {
+                STDOUT if !record.content.is_empty() => {
+                    let output = self.requests.lock().unwrap().get(&record.id).cloned();
+                    if let Some(output) = output {
+                        // Client might have gone away, in which case we just discard the rest
+                        let _ = output.send(Ok(record.content)).await;
+                    }
+                }
+                STDERR => warning!(
+                    "FastCGI backend error: {}",
+                    String::from_utf8_lossy(&record.content).trim_end()
+                ),
+
+                // Dropping the output ends the request's stdout
+                END_REQUEST => {
+                    self.requests.lock().unwrap().remove(&record.id);
+                }
+
+                GET_VALUES_RESULT => {
+                    let multiplex = decode_pairs(&record.content)
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|(name, value)| name == "FCGI_MPXS_CONNS" && value == "1");
+                    self.multiplex.store(multiplex, Ordering::SeqCst);
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    /// Registers a new request if the connection has room for it,
+    /// returning its ID and where its stdout will arrive.
+    fn register(&self) -> Option<(u16, ChannelReader)> {
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let mut requests = self.requests.lock().unwrap();
+        let capacity = if self.multiplex.load(Ordering::SeqCst) {
+            MAX_REQUESTS_PER_CONN
+        } else {
+            1
+        };
+        if requests.len() >= capacity {
+            return None;
+        }
+
+        // ID 0 is reserved for management records
+        let id = (1..=u16::MAX).find(|id| !requests.contains_key(id))?;
+        let (output, stdout) = mpsc::channel(STDOUT_BUFFER);
+        requests.insert(id, output);
+
+        Some((id, ChannelReader::new(stdout)))
+    }
+}
+
+/// Pool of connections to a single backend.
+struct Client {
+    address: Address,
+    conns: Mutex<Vec<Arc<Connection>>>,
+}
+
+impl Client {
+    /// Finds an existing connection with room for another request.
+    fn reuse(&self) -> Option<(Arc<Connection>, u16, ChannelReader)> {
+        let mut conns = self.conns.lock().unwrap();
+        conns.retain(|conn| !conn.closed.load(Ordering::SeqCst));
+        conns.iter().find_map(|conn| {
+            conn.register()
+                .map(|(id, stdout)| (Arc::clone(conn), id, stdout))
+        })
+    }
+
+    async fn connect(&self) -> std::io::Result<(Arc<Connection>, u16, ChannelReader)> {
+        let conn = Connection::open(&self.address).await?;
+        let (id, stdout) = conn.register().expect("New connections always have room");
+        self.conns.lock().unwrap().push(Arc::clone(&conn));
+        Ok((conn, id, stdout))
+    }
+
+    /// Sends a request to the backend, returning its stdout.
+    async fn request<N: AsRef<str>, V: AsRef<str>>(
+        &self,
+        params: &[(N, V)],
+        stdin: &[u8],
+    ) -> std::io::Result<ChannelReader> {
+        // A reused connection may have been closed by the backend in the meantime,
+        // in which case we try again once on a new one
+        if let Some((conn, id, stdout)) = self.reuse() {
+            if send(&conn, id, params, stdin).await.is_ok() {
+                return Ok(stdout);
+            }
+        }
+
+        let (conn, id, stdout) = self.connect().await?;
+        send(&conn, id, params, stdin).await?;
+        Ok(stdout)
+    }
+}
+
+/// Writes a whole request to the connection.
+async fn send<N: AsRef<str>, V: AsRef<str>>(
+    conn: &Connection,
+    id: u16,
+    params: &[(N, V)],
+    stdin: &[u8],
+) -> std::io::Result<()> {
+    let mut data = Vec::new();
+    encode_record(
+        &mut data,
+        BEGIN_REQUEST,
+        id,
+        &[0, RESPONDER, KEEP_CONN, 0, 0, 0, 0, 0],
+    );
+    encode_stream(&mut data, PARAMS, id, &encode_pairs(params));
+    encode_stream(&mut data, STDIN, id, stdin);
+
+    let result = conn.writer.lock().await.write_all(&data).await;
+    if result.is_err() {
+        conn.closed.store(true, Ordering::SeqCst);
+        conn.requests.lock().unwrap().remove(&id);
+    }
+    result
+}
+
+/// Clients for each backend address we have talked to.
+static CLIENTS: LazyLock<Mutex<HashMap<String, Arc<Client>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn client_for(address: &str) -> Result<Arc<Client>, ()> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(address) {
+        return Ok(Arc::clone(client));
+    }
+
+    let client = Arc::new(Client {
+        address: address.parse()?,
+        conns: Mutex::new(Vec::new()),
+    });
+    clients.insert(String::from(address), Arc::clone(&client));
+    Ok(client)
+}
+
+/// Has the FastCGI backend at `address` respond to a request, with `params`
+/// being its CGI meta-variables.
+pub async fn handle_fastcgi(
+    config: &Config,
+    request: &HttpMessage,
+    params: &[(String, String)],
+    address: &str,
+    send_body: bool,
+) -> Result<HttpMessage, ()> {
+    let stdin = request
+        .body
+        .as_ref()
+        .and_then(HttpBody::bytes)
+        .unwrap_or_default();
+
+    let stdout = client_for(address)?
+        .request(params, stdin)
+        .await
+        .map_err(|e| error!("Error talking to FastCGI backend {address}: {e}"))?;
+
+    cgi::parse_response(config, BufReader::new(stdout), send_body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    /// Stand-in for a FastCGI backend, which answers every request with
+    /// a text/plain body of its SCRIPT_FILENAME param followed by its stdin.
+    ///
+    /// Returns its address and the number of connections accepted so far.
+    async fn spawn_responder(multiplex: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(respond(stream, multiplex));
+            }
+        });
+
+        (address, accepted)
+    }
+
+    async fn respond(mut stream: TcpStream, multiplex: bool) {
+        let mut params: HashMap<u16, Vec<u8>> = HashMap::new();
+        let mut stdin: HashMap<u16, Vec<u8>> = HashMap::new();
+
+        while let Ok(record) = read_record(&mut stream).await {
+            let mut out = Vec::new();
+            match record.kind {
+                GET_VALUES => {
+                    let value = if multiplex { "1" } else { "0" };
+                    let pairs = encode_pairs(&[("FCGI_MPXS_CONNS", value)]);
+                    encode_record(&mut out, GET_VALUES_RESULT, 0, &pairs);
+                }
+                BEGIN_REQUEST => {
+                    params.insert(record.id, Vec::new());
+                    stdin.insert(record.id, Vec::new());
+                }
+                PARAMS => params.get_mut(&record.id).unwrap().extend(record.content),
+                STDIN if !record.content.is_empty() => {
+                    stdin.get_mut(&record.id).unwrap().extend(record.content);
+                }
+                STDIN => {
+                    let params = decode_pairs(&params.remove(&record.id).unwrap()).unwrap();
+                    let (_, script) = params
+                        .iter()
+                        .find(|(name, _)| name == "SCRIPT_FILENAME")
+                        .unwrap();
+
+                    let mut body =
+                        format!("Content-Type: text/plain\r\n\r\n{script}\n").into_bytes();
+                    body.extend(stdin.remove(&record.id).unwrap());
+                    encode_stream(&mut out, STDOUT, record.id, &body);
+                    encode_record(&mut out, END_REQUEST, record.id, &[0; 8]);
+                }
+                _ => (),
+            }
+            stream.write_all(&out).await.unwrap();
+        }
+    }
+
+    async fn request(client: &Client, script: &str, stdin: &[u8]) -> Vec<u8> {
+        let mut stdout = client
+            .request(&[("SCRIPT_FILENAME", script)], stdin)
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).await.unwrap();
+        output
+    }
+
+    #[test]
+    fn test_pairs() {
+        let long = "x".repeat(300);
+        let pairs = [
+            ("SCRIPT_FILENAME", "/index.php"),
+            ("LONG", &long),
+            ("EMPTY", ""),
+        ];
+        let decoded = decode_pairs(&encode_pairs(&pairs)).unwrap();
+
+        assert_eq!(decoded.len(), 3);
+        for ((name, value), (decoded_name, decoded_value)) in pairs.iter().zip(&decoded) {
+            assert_eq!(name, decoded_name);
+            assert_eq!(value, decoded_value);
+        }
+
+        // Invalid (truncated)
+        assert!(decode_pairs(&[5, 5, b'a']).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let (address, accepted) = spawn_responder(false).await;
+        let client = client_for(&address).unwrap();
+
+        // Large stdin spanning several records
+        let stdin = vec![b'!'; MAX_CONTENT_LEN * 2];
+        let output = request(&client, "/index.php", &stdin).await;
+        let expected = [
+            b"Content-Type: text/plain\r\n\r\n/index.php\n".as_slice(),
+            &stdin,
+        ]
+        .concat();
+        assert_eq!(output, expected);
+
+        // Connection is kept alive for the next request
+        let output = request(&client, "/other.php", b"").await;
+        assert_eq!(output, b"Content-Type: text/plain\r\n\r\n/other.php\n");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_get_values() {
+        // Backend taking its time to answer GET_VALUES, which mustn't throw us off
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(read_record(&mut stream).await.unwrap().kind, GET_VALUES);
+
+            let mut answer = Vec::new();
+            let pairs = encode_pairs(&[("FCGI_MPXS_CONNS", "1")]);
+            encode_record(&mut answer, GET_VALUES_RESULT, 0, &pairs);
+            stream.write_all(&answer[..4]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+            stream.write_all(&answer[4..]).await.unwrap();
+
+            respond(stream, true).await;
+        });
+
+        let client = client_for(&address).unwrap();
+        let output = request(&client, "/index.php", b"").await;
+        assert_eq!(output, b"Content-Type: text/plain\r\n\r\n/index.php\n");
+    }
+
+    #[tokio::test]
+    async fn test_request_concurrent() {
+        // Without multiplexing, concurrent requests need their own connections
+        let (address, accepted) = spawn_responder(false).await;
+        let client = client_for(&address).unwrap();
+        let (a, b) = tokio::join!(
+            request(&client, "/a.php", b"a"),
+            request(&client, "/b.php", b"b"),
+        );
+        assert!(a.ends_with(b"/a.php\na"));
+        assert!(b.ends_with(b"/b.php\nb"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // With multiplexing, they share the one already open
+        let (address, accepted) = spawn_responder(true).await;
+        let client = client_for(&address).unwrap();
+        request(&client, "/index.php", b"").await;
+        let (a, b, c) = tokio::join!(
+            request(&client, "/a.php", b"a"),
+            request(&client, "/b.php", b"b"),
+            request(&client, "/c.php", b"c"),
+        );
+        assert!(a.ends_with(b"/a.php\na"));
+        assert!(b.ends_with(b"/b.php\nb"));
+        assert!(c.ends_with(b"/c.php\nc"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,527 @@
+//! This compresses and decompresses HTTP/2 field blocks (RFC 7541)
+//!
+//! Fields we send are never added to the dynamic table, so clients can be
+//! decoded with whatever table size they like while encoding stays stateless.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// Fields every encoder and decoder knows by index (RFC 7541, appendix A).
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code and its length in bits of every byte, plus EOS (RFC 7541, appendix B).
+#[rustfmt::skip]
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28),
+    (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24),
+    (0x3ffffffc, 30), (0xfffffe9, 28), (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28),
+    (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28), (0xffffff4, 28),
+    (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10), (0xf9, 8),
+    (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6), (0x0, 5), (0x1, 5), (0x2, 5),
+    (0x19, 6), (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7),
+    (0xfb, 8), (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7), (0x63, 7), (0x64, 7),
+    (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7),
+    (0x6d, 7), (0x6e, 7), (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6), (0x7ffd, 15),
+    (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5),
+    (0x74, 7), (0x75, 7), (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7), (0x79, 7), (0x7a, 7), (0x7b, 7),
+    (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20),
+    (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20), (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22),
+    (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23),
+    (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23), (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22),
+    (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23),
+    (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23), (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23),
+    (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22),
+    (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21), (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22),
+    (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21),
+    (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21), (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23),
+    (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23),
+    (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23), (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20),
+    (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26),
+    (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27), (0x7ffffdf, 27), (0x3ffffe5, 26),
+    (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26),
+    (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28),
+    (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20),
+    (0x1fffe6, 21), (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22),
+    (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24),
+    (0x3ffffea, 26), (0x7ffff4, 23), (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26),
+    (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27), (0x7ffffee, 27),
+    (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+/// Symbol that may only appear (partially) as padding.
+const EOS: usize = 256;
+
+/// Size of the dynamic table unless the client says otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Every entry takes up this much on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Returns the Huffman code as a binary tree, where each node holds the index
+/// of its children, or a symbol (as `!symbol`) for leaves.
+fn huffman_tree() -> &'static [[i32; 2]] {
+    static TREE: OnceLock<Vec<[i32; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = (code >> i & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = !(symbol as i32);
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = tree.len() as i32 - 1;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, ()> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::new();
+    let mut node = 0;
+
+    // Bits since the last symbol, which must be a prefix of EOS (all ones) at the end
+    let mut pending = 0;
+    let mut all_ones = true;
+
+    for byte in data {
+        for i in (0..8).rev() {
+            let bit = byte >> i & 1;
+            match tree[node][bit as usize] {
+                0 => return Err(()),
+                next if next < 0 => {
+                    let symbol = !next as usize;
+                    if symbol == EOS {
+                        return Err(());
+                    }
+                    decoded.push(symbol as u8);
+                    node = 0;
+                    pending = 0;
+                    all_ones = true;
+                }
+                next => {
+                    node = next as usize;
+                    pending += 1;
+                    all_ones &= bit == 1;
+                }
+            }
+        }
+    }
+
+    if pending > 7 || !all_ones {
+        return Err(());
+    }
+    Ok(decoded)
+}
+
+/// Decodes an integer with an `n` bit prefix (RFC 7541, section 5.1),
+/// returning it along with the rest of `data`.
+fn decode_int(data: &[u8], n: u8) -> Result<(usize, &[u8]), ()> {
+    let (&first, mut rest) = data.split_first().ok_or(())?;
+    let max = (1 << n) - 1;
+    let mut value = (first & max) as usize;
+    if value < max as usize {
+        return Ok((value, rest));
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, after) = rest.split_first().ok_or(())?;
+        rest = after;
+
+        // Anything this large is an attack
+        if shift > 21 {
+            return Err(());
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok((value, rest));
+        }
+    }
+}
+
+fn encode_int(value: usize, n: u8, flags: u8, out: &mut Vec<u8>) {
+    let max = (1 << n) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+
+    out.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decodes a (possibly Huffman coded) string literal, returning it along with the rest of `data`.
+fn decode_string(data: &[u8]) -> Result<(String, &[u8]), ()> {
+    let huffman = data.first().ok_or(())? & 0x80 != 0;
+    let (len, rest) = decode_int(data, 7)?;
+    let (string, rest) = rest.split_at_checked(len).ok_or(())?;
+
+    let string = if huffman {
+        huffman_decode(string)?
+    } else {
+        string.to_vec()
+    };
+    Ok((String::from_utf8_lossy(&string).into_owned(), rest))
+}
+
+fn encode_string(string: &str, out: &mut Vec<u8>) {
+    encode_int(string.len(), 7, 0, out);
+    out.extend_from_slice(string.as_bytes());
+}
+
+/// Decodes the field blocks of a connection, keeping track of its dynamic table.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    /// Returns the field at `index`, counting from the static table into the dynamic one.
+    fn get(&self, index: usize) -> Result<(&str, &str), ()> {
+        match index {
+            0 => Err(()),
+            1..=61 => Ok(STATIC_TABLE[index - 1]),
+            _ => {
+                let (name, value) = self.table.get(index - 62).ok_or(())?;
+                Ok((name, value))
+            }
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        self.size += name.len() + value.len() + ENTRY_OVERHEAD;
+        self.table.push_front((name, value));
+        self.evict();
+    }
+
+    /// Drops the oldest entries until the table fits its maximum size.
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+
+    /// Decodes a complete field block into its fields, or `None` if they would take up
+    /// more than `max_size` (counted like entries in the table). Small blocks can refer
+    /// to large entries over and over, so fields aren't kept once over the limit, but
+    /// the rest of the block is still decoded to keep the dynamic table in sync.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_size: usize,
+    ) -> Result<Option<Vec<(String, String)>>, ()> {
+        let mut fields = Vec::new();
+        let mut decoded = 0;
+        let keep = |fields: &mut Vec<_>, decoded: &mut usize, name: &str, value: &str| {
+            *decoded += name.len() + value.len() + ENTRY_OVERHEAD;
+            if *decoded <= max_size {
+                fields.push((name.to_string(), value.to_string()));
+            }
+        };
+
+        while let Some(&first) = block.first() {
+            // Indexed field
+            if first & 0x80 != 0 {
+                let (index, rest) = decode_int(block, 7)?;
+                let (name, value) = self.get(index)?;
+                keep(&mut fields, &mut decoded, name, value);
+                block = rest;
+                continue;
+            }
+
+            // Dynamic table size update, which may only shrink it below what we allow
+            if first & 0xE0 == 0x20 {
+                let (size, rest) = decode_int(block, 5)?;
+                if size > DEFAULT_TABLE_SIZE || decoded > 0 {
+                    return Err(());
+                }
+                self.max_size = size;
+                self.evict();
+                block = rest;
+                continue;
+            }
+
+            // Literal field, possibly with an indexed name
+            let (indexing, prefix) = if first & 0x40 != 0 {
+                (true, 6)
+            } else {
+                (false, 4)
+            };
+            let (index, rest) = decode_int(block, prefix)?;
+            let (name, rest) = if index == 0 {
+                decode_string(rest)?
+            } else {
+                (self.get(index)?.0.to_string(), rest)
+            };
+            let (value, rest) = decode_string(rest)?;
+            block = rest;
+
+            keep(&mut fields, &mut decoded, &name, &value);
+            if indexing {
+                self.insert(name, value);
+            }
+        }
+
+        Ok(Some(fields).filter(|_| decoded <= max_size))
+    }
+}
+
+/// Encodes fields without touching the dynamic table.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        let indexed = STATIC_TABLE.iter().position(|&f| f == (name, value));
+        if let Some(i) = indexed {
+            encode_int(i + 1, 7, 0x80, &mut block);
+            continue;
+        }
+
+        // Literal without indexing, reusing the name if we can
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_int(i + 1, 4, 0, &mut block),
+            None => {
+                block.push(0);
+                encode_string(name, &mut block);
+            }
+        }
+        encode_string(value, &mut block);
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|&(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_int() {
+        // Examples from RFC 7541, appendix C.1
+        let mut out = Vec::new();
+        encode_int(1337, 5, 0, &mut out);
+        assert_eq!(out, [0x1F, 0x9A, 0x0A]);
+        assert_eq!(decode_int(&out, 5), Ok((1337, &[][..])));
+        assert_eq!(decode_int(&[0x0A, 0xFF], 5), Ok((10, &[0xFF][..])));
+        assert_eq!(decode_int(&[0x1F, 0x9A], 5), Err(()));
+        assert_eq!(
+            decode_int(&[0x1F, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F], 5),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        // Requests from RFC 7541, appendix C.4, which use Huffman coding and the dynamic table
+        let mut decoder = Decoder::new();
+        let first = decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), 4096)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            first,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let second = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), 4096)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            second,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+
+        let third = decoder
+            .decode(
+                &hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                4096,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            third,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+
+        // Index beyond the dynamic table
+        assert!(decoder.decode(&[0xC2], 4096).is_err());
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        // One large entry in the dynamic table, then referred to over and over
+        let mut decoder = Decoder::new();
+        let mut block = vec![0x40];
+        encode_string("x-bomb", &mut block);
+        encode_string(&"!".repeat(3000), &mut block);
+        assert_eq!(decoder.decode(&block, 4096).unwrap().unwrap().len(), 1);
+
+        let bomb = vec![0xBE; 1000];
+        assert_eq!(decoder.decode(&bomb, 1024 * 1024), Ok(None));
+        assert_eq!(decoder.decode(&bomb[..2], 8192).unwrap().unwrap().len(), 2);
+
+        // The table is still kept up to date past the limit
+        let mut block = vec![0xBE, 0x40];
+        encode_string("x-new", &mut block);
+        encode_string("yes", &mut block);
+        assert_eq!(decoder.decode(&block, 100), Ok(None));
+        let fields = decoder.decode(&[0xBE], 100).unwrap().unwrap();
+        assert_eq!(fields, [(String::from("x-new"), String::from("yes"))]);
+    }
+
+    #[test]
+    fn test_huffman_padding() {
+        // "a" is 00011, so padding with ones is fine but zeroes or a whole byte isn't
+        assert_eq!(huffman_decode(&[0x1F]), Ok(b"a".to_vec()));
+        assert!(huffman_decode(&[0x18]).is_err());
+        assert!(huffman_decode(&[0x1F, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let block = encode([
+            (":status", "200"),
+            ("content-type", "text/html"),
+            ("x-powered-by", "helios"),
+        ]);
+        assert_eq!(block[0], 0x88);
+
+        let decoded = Decoder::new().decode(&block, 4096).unwrap().unwrap();
+        assert_eq!(
+            decoded,
+            fields(&[
+                (":status", "200"),
+                ("content-type", "text/html"),
+                ("x-powered-by", "helios"),
+            ])
+        );
+    }
+}
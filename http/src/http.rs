@@ -1,14 +1,37 @@
-use percent_encoding::percent_decode_str;
-use std::collections::HashMap;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use std::fmt::{Display, Write};
+use std::ops::Index;
+use std::pin::Pin;
 use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
 use url::Url;
 
+/// Characters percent-encoded in paths we put in URLs, which also leaves them safe in HTML attributes.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Percent-encodes a (decoded) path for use in a URL.
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH_ENCODE_SET).to_string()
+}
+
 #[non_exhaustive]
 #[derive(Clone, Copy, Debug)]
 pub enum Error {
     Malformed,
-    UnsupportedMethod,
     UnsupportedVersion,
     UnsupportedStatusCode,
 }
@@ -18,6 +41,9 @@ pub enum Error {
 pub enum HttpVersion {
     HTTP10,
     HTTP11,
+
+    /// Never appears in a request line, since HTTP/2 has none.
+    HTTP2,
 }
 
 impl Display for HttpVersion {
@@ -25,6 +51,7 @@ impl Display for HttpVersion {
         match self {
             Self::HTTP10 => write!(f, "HTTP/1.0"),
             Self::HTTP11 => write!(f, "HTTP/1.1"),
+            Self::HTTP2 => write!(f, "HTTP/2"),
         }
     }
 }
@@ -45,28 +72,83 @@ impl TryFrom<&str> for HttpVersion {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HttpStatusCode {
     Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    MultiStatus,
+    MovedPermanently,
+    Found,
+    NotModified,
+    PermanentRedirect,
     BadRequest,
+    Unauthorized,
+    Forbidden,
     NotFound,
+    MethodNotAllowed,
     RequestTimeout,
+    Conflict,
+    PreconditionFailed,
     ContentTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    Locked,
+    TooManyRequests,
     InternalServorError,
     NotImplemented,
+    BadGateway,
     ServiceUnavailable,
+    GatewayTimeout,
     HTTPVersionNotSupported,
+    InsufficientStorage,
+
+    /// Any other code, which only upstream servers and CGI scripts might send.
+    Other(u16),
+}
+
+impl HttpStatusCode {
+    /// Like `try_from`, but keeps final codes we don't know as they are, so they
+    /// can be passed along to clients (who know what to make of them).
+    pub fn from_u16_or_other(code: u16) -> Result<Self, Error> {
+        match Self::try_from(code) {
+            Err(_) if (200..600).contains(&code) => Ok(Self::Other(code)),
+            result => result,
+        }
+    }
 }
 
 impl Display for HttpStatusCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Ok => write!(f, "OK"),
+            Self::Created => write!(f, "Created"),
+            Self::NoContent => write!(f, "No Content"),
+            Self::PartialContent => write!(f, "Partial Content"),
+            Self::MultiStatus => write!(f, "Multi-Status"),
+            Self::MovedPermanently => write!(f, "Moved Permanently"),
+            Self::Found => write!(f, "Found"),
+            Self::NotModified => write!(f, "Not Modified"),
+            Self::PermanentRedirect => write!(f, "Permanent Redirect"),
             Self::BadRequest => write!(f, "Bad Request"),
+            Self::Unauthorized => write!(f, "Unauthorized"),
+            Self::Forbidden => write!(f, "Forbidden"),
             Self::NotFound => write!(f, "Not Found"),
+            Self::MethodNotAllowed => write!(f, "Method Not Allowed"),
             Self::RequestTimeout => write!(f, "Request Timeout"),
+            Self::Conflict => write!(f, "Conflict"),
+            Self::PreconditionFailed => write!(f, "Precondition Failed"),
             Self::ContentTooLarge => write!(f, "Content Too Large"),
+            Self::UnsupportedMediaType => write!(f, "Unsupported Media Type"),
+            Self::RangeNotSatisfiable => write!(f, "Range Not Satisfiable"),
+            Self::Locked => write!(f, "Locked"),
+            Self::TooManyRequests => write!(f, "Too Many Requests"),
             Self::InternalServorError => write!(f, "Internal Servor Error"),
             Self::NotImplemented => write!(f, "Not Implemented"),
+            Self::BadGateway => write!(f, "Bad Gateway"),
             Self::ServiceUnavailable => write!(f, "Service Unavailable"),
+            Self::GatewayTimeout => write!(f, "Gateway Timeout"),
             Self::HTTPVersionNotSupported => write!(f, "HTTP Version Not Supported"),
+            Self::InsufficientStorage => write!(f, "Insufficient Storage"),
+            Self::Other(_) => Ok(()),
         }
     }
 }
@@ -75,14 +157,35 @@ impl From<HttpStatusCode> for u16 {
     fn from(status_code: HttpStatusCode) -> Self {
         match status_code {
             HttpStatusCode::Ok => 200,
+            HttpStatusCode::Created => 201,
+            HttpStatusCode::NoContent => 204,
+            HttpStatusCode::PartialContent => 206,
+            HttpStatusCode::MultiStatus => 207,
+            HttpStatusCode::MovedPermanently => 301,
+            HttpStatusCode::Found => 302,
+            HttpStatusCode::NotModified => 304,
+            HttpStatusCode::PermanentRedirect => 308,
             HttpStatusCode::BadRequest => 400,
+            HttpStatusCode::Unauthorized => 401,
+            HttpStatusCode::Forbidden => 403,
             HttpStatusCode::NotFound => 404,
+            HttpStatusCode::MethodNotAllowed => 405,
             HttpStatusCode::RequestTimeout => 408,
+            HttpStatusCode::Conflict => 409,
+            HttpStatusCode::PreconditionFailed => 412,
             HttpStatusCode::ContentTooLarge => 413,
+            HttpStatusCode::UnsupportedMediaType => 415,
+            HttpStatusCode::RangeNotSatisfiable => 416,
+            HttpStatusCode::Locked => 423,
+            HttpStatusCode::TooManyRequests => 429,
             HttpStatusCode::InternalServorError => 500,
             HttpStatusCode::NotImplemented => 501,
+            HttpStatusCode::BadGateway => 502,
             HttpStatusCode::ServiceUnavailable => 503,
+            HttpStatusCode::GatewayTimeout => 504,
             HttpStatusCode::HTTPVersionNotSupported => 505,
+            HttpStatusCode::InsufficientStorage => 507,
+            HttpStatusCode::Other(code) => code,
         }
     }
 }
@@ -93,23 +196,53 @@ impl TryFrom<u16> for HttpStatusCode {
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
             200 => Ok(Self::Ok),
+            201 => Ok(Self::Created),
+            204 => Ok(Self::NoContent),
+            206 => Ok(Self::PartialContent),
+            207 => Ok(Self::MultiStatus),
+            301 => Ok(Self::MovedPermanently),
+            302 => Ok(Self::Found),
+            304 => Ok(Self::NotModified),
+            308 => Ok(Self::PermanentRedirect),
             400 => Ok(Self::BadRequest),
+            401 => Ok(Self::Unauthorized),
+            403 => Ok(Self::Forbidden),
             404 => Ok(Self::NotFound),
+            405 => Ok(Self::MethodNotAllowed),
             408 => Ok(Self::RequestTimeout),
+            409 => Ok(Self::Conflict),
+            412 => Ok(Self::PreconditionFailed),
             413 => Ok(Self::ContentTooLarge),
+            415 => Ok(Self::UnsupportedMediaType),
+            416 => Ok(Self::RangeNotSatisfiable),
+            423 => Ok(Self::Locked),
+            429 => Ok(Self::TooManyRequests),
             500 => Ok(Self::InternalServorError),
             501 => Ok(Self::NotImplemented),
+            502 => Ok(Self::BadGateway),
+            503 => Ok(Self::ServiceUnavailable),
+            504 => Ok(Self::GatewayTimeout),
             505 => Ok(Self::HTTPVersionNotSupported),
+            507 => Ok(Self::InsufficientStorage),
             _ => Err(Error::UnsupportedStatusCode),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum HttpMethod {
     Get,
     Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+
+    /// Any other method, which only CGI scripts and upstream servers might know.
+    Extension(String),
 }
 
 impl Display for HttpMethod {
@@ -118,6 +251,13 @@ impl Display for HttpMethod {
             Self::Get => write!(f, "GET"),
             Self::Head => write!(f, "HEAD"),
             Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Connect => write!(f, "CONNECT"),
+            Self::Options => write!(f, "OPTIONS"),
+            Self::Trace => write!(f, "TRACE"),
+            Self::Patch => write!(f, "PATCH"),
+            Self::Extension(method) => write!(f, "{method}"),
         }
     }
 }
@@ -130,11 +270,25 @@ impl TryFrom<&str> for HttpMethod {
             "GET" => Ok(Self::Get),
             "HEAD" => Ok(Self::Head),
             "POST" => Ok(Self::Post),
-            _ => Err(Error::UnsupportedMethod),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "CONNECT" => Ok(Self::Connect),
+            "OPTIONS" => Ok(Self::Options),
+            "TRACE" => Ok(Self::Trace),
+            "PATCH" => Ok(Self::Patch),
+            _ if is_token(s) => Ok(Self::Extension(s.to_string())),
+            _ => Err(Error::Malformed),
         }
     }
 }
 
+/// Returns true if `s` is a token (RFC 9110, section 5.6.2), as methods must be.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
 #[derive(Debug)]
 pub enum HttpStartLine {
     Response(HttpStatusLine),
@@ -154,17 +308,18 @@ impl Display for HttpStartLine {
 pub struct HttpStatusLine {
     pub http_version: HttpVersion,
     pub status_code: HttpStatusCode,
+
+    /// Sent instead of the usual reason phrase for the code, if set.
+    pub reason_phrase: Option<String>,
 }
 
 impl Display for HttpStatusLine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{} {} {}",
-            self.http_version,
-            u16::from(self.status_code),
-            self.status_code
-        )
+        write!(f, "{} {} ", self.http_version, u16::from(self.status_code))?;
+        match &self.reason_phrase {
+            Some(reason_phrase) => write!(f, "{reason_phrase}"),
+            None => write!(f, "{}", self.status_code),
+        }
     }
 }
 
@@ -172,32 +327,30 @@ impl FromStr for HttpStatusLine {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut tokens = s.split(' ');
+        // Reason phrase may itself contain spaces, so it is everything after the status code
+        let mut tokens = s.splitn(3, ' ');
 
         let http_version: HttpVersion = tokens.next().ok_or(Error::Malformed)?.try_into()?;
-        let status_code: HttpStatusCode = tokens
-            .next()
-            .ok_or(Error::Malformed)?
-            .parse::<u16>()
-            .map_err(|_| Error::Malformed)?
-            .try_into()?;
-
-        /* Reason phrase is optional, and we don't really care about it,
-         * but there must be a space after the status code, thus
-         * this token must exist even if it's empty. Otherwise
-         * the response is malformed.
-         */
-        let _reason_phrase = tokens.next().ok_or(Error::Malformed)?;
 
-        // If we still have remaining tokens the response is malformed
-        if tokens.next().is_none() {
-            Ok(Self {
-                http_version,
-                status_code,
-            })
-        } else {
-            Err(Error::Malformed)
+        let status_code = tokens.next().ok_or(Error::Malformed)?;
+        if status_code.len() != 3 {
+            return Err(Error::Malformed);
         }
+        let status_code = HttpStatusCode::from_u16_or_other(
+            status_code.parse::<u16>().map_err(|_| Error::Malformed)?,
+        )?;
+
+        /* Reason phrase is optional, but there must be a space
+         * after the status code, thus this token must exist
+         * even if it's empty. Otherwise the response is malformed.
+         */
+        let reason_phrase = tokens.next().ok_or(Error::Malformed)?;
+
+        Ok(Self {
+            http_version,
+            status_code,
+            reason_phrase: Some(reason_phrase.to_string()),
+        })
     }
 }
 
@@ -265,10 +418,62 @@ impl FromStr for HttpField {
     }
 }
 
+/// Field lines of a header in the order they were added.
+///
+/// Names are compared case-insensitively, and may repeat since some
+/// fields (like Set-Cookie) can't be combined into a single line.
+#[derive(Debug, Default)]
+pub struct FieldLines(Vec<(String, String)>);
+
+impl FieldLines {
+    /// Returns the value of the first field named `name`.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets the field `name` to `value`, replacing any existing fields of that name.
+    pub fn insert(&mut self, name: String, value: String) {
+        self.remove(&name);
+        self.0.push((name, value));
+    }
+
+    /// Removes all fields named `name`, returning the value of the first.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let first = self.get(name).cloned();
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        first
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter().map(|(n, v)| (n, v))
+    }
+}
+
+impl Index<&str> for FieldLines {
+    type Output = String;
+
+    fn index(&self, name: &str) -> &Self::Output {
+        self.get(name).expect("Field not present")
+    }
+}
+
+impl FromIterator<(String, String)> for FieldLines {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpHeader {
     pub start_line: HttpStartLine,
-    pub field_lines: HashMap<String, String>,
+    pub field_lines: FieldLines,
 }
 
 impl HttpHeader {
@@ -285,14 +490,42 @@ impl HttpHeader {
             HttpVersion::HTTP11 => self
                 .field_lines
                 .get("connection")
-                .map_or(true, |v| v == "keep-alive"),
+                .is_none_or(|v| v == "keep-alive"),
+
+            // HTTP/2 connections are always persistent
+            HttpVersion::HTTP2 => true,
 
             // HTTP/1.0 is NOT persistent by default
             HttpVersion::HTTP10 => self
                 .field_lines
                 .get("connection")
-                .map_or(false, |v| v == "keep-alive"),
+                .is_some_and(|v| v == "keep-alive"),
+        }
+    }
+
+    /// Returns the length the Content-Length field gives, if any. The field may be
+    /// repeated (or be a list), but only with the same value each time, as anything
+    /// else leaves the message's framing in doubt (RFC 9112, section 6.3).
+    pub fn content_length(&self) -> Result<Option<u64>, Error> {
+        let mut length = None;
+        for (_, value) in self
+            .field_lines
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        {
+            for value in value.split(',').map(str::trim) {
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(Error::Malformed);
+                }
+                let value = value.parse().map_err(|_| Error::Malformed)?;
+                if length.is_some_and(|length| length != value) {
+                    return Err(Error::Malformed);
+                }
+                length = Some(value);
+            }
         }
+
+        Ok(length)
     }
 
     /// Returns true is header represents a request, false otherwise.
@@ -309,6 +542,26 @@ impl HttpHeader {
             panic!("Header is not an HTTP request.");
         }
     }
+
+    /// Returns a reference to the status line if response header,
+    /// panics otherwise.
+    pub fn status_line(&self) -> &HttpStatusLine {
+        if let HttpStartLine::Response(status_line) = &self.start_line {
+            status_line
+        } else {
+            panic!("Header is not an HTTP response.");
+        }
+    }
+
+    /// Returns a mutable reference to the status line if response header,
+    /// panics otherwise.
+    pub fn status_line_mut(&mut self) -> &mut HttpStatusLine {
+        if let HttpStartLine::Response(status_line) = &mut self.start_line {
+            status_line
+        } else {
+            panic!("Header is not an HTTP response.");
+        }
+    }
 }
 
 impl Display for HttpHeader {
@@ -353,7 +606,7 @@ impl FromStr for HttpHeader {
                 line.parse::<HttpField>()
                     .map(|field| (field.name.to_lowercase(), field.value))
             })
-            .collect::<Result<HashMap<String, String>, Error>>()?;
+            .collect::<Result<FieldLines, Error>>()?;
 
         Ok(HttpHeader {
             start_line,
@@ -362,9 +615,86 @@ impl FromStr for HttpHeader {
     }
 }
 
+pub enum HttpBody {
+    /// Body is held entirely in memory.
+    Full(Vec<u8>),
+
+    /// Body is read from `reader` as it is sent, with `len` being `None`
+    /// if the length is not known ahead of time.
+    Stream {
+        reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+        len: Option<u64>,
+    },
+}
+
+impl HttpBody {
+    /// Returns the length of the body if known.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Self::Full(data) => Some(data.len() as u64),
+            Self::Stream { len, .. } => *len,
+        }
+    }
+
+    /// Returns the body's bytes if it is held in memory.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Full(data) => Some(data),
+            Self::Stream { .. } => None,
+        }
+    }
+}
+
+/// Body read from chunks sent over a channel as they arrive, ending when the channel closes.
+pub struct ChannelReader {
+    chunks: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(chunks: mpsc::Receiver<std::io::Result<Vec<u8>>>) -> Self {
+        Self {
+            chunks,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.pos == self.buf.len() {
+            match ready!(self.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = out.remaining().min(self.buf.len() - self.pos);
+        out.put_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl From<Vec<u8>> for HttpBody {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Full(data)
+    }
+}
+
 pub struct HttpMessage {
     pub header: HttpHeader,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<HttpBody>,
 }
 
 impl HttpMessage {
@@ -372,11 +702,12 @@ impl HttpMessage {
         http_version: HttpVersion,
         status_code: HttpStatusCode,
         field_lines: &[(&str, &str)],
-        body: Option<Vec<u8>>,
+        body: Option<HttpBody>,
     ) -> Self {
         let start_line = HttpStartLine::Response(HttpStatusLine {
             http_version,
             status_code,
+            reason_phrase: None,
         });
 
         let field_lines = field_lines
@@ -390,18 +721,51 @@ impl HttpMessage {
         };
         Self { header, body }
     }
+
+    /// Returns true if the body must be sent using the chunked transfer coding.
+    pub fn is_chunked(&self) -> bool {
+        self.header
+            .field_lines
+            .get("Transfer-Encoding")
+            .is_some_and(|v| v == "chunked")
+    }
+}
+
+/// Returns the host of a Host field value, without any port.
+pub fn host_without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        // IPv6 addresses contain colons too, but are enclosed in brackets
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
 }
 
-impl From<HttpMessage> for Vec<u8> {
-    fn from(message: HttpMessage) -> Self {
-        message
-            .header
-            .to_string()
-            .into_bytes()
-            .into_iter()
-            .chain(message.body.unwrap_or_default())
-            .collect()
+/// Percent-decodes an absolute URL path, refusing any that could point somewhere other
+/// than it seems to: those with dot or empty segments (other than a trailing one),
+/// or with slashes that were percent-encoded.
+pub fn decode_path(path: &str) -> Result<String, ()> {
+    let path = path.strip_prefix('/').ok_or(())?;
+
+    let segments: Vec<&str> = path.split('/').collect();
+    let mut decoded = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let segment = percent_decode_str(segment).decode_utf8().map_err(|_| ())?;
+
+        // Only directories end with an empty segment
+        let last = i == segments.len() - 1;
+        if (segment.is_empty() && !last)
+            || segment == "."
+            || segment == ".."
+            || segment.contains(['/', '\\', '\0'])
+        {
+            return Err(());
+        }
+
+        decoded.push('/');
+        decoded.push_str(&segment);
     }
+
+    Ok(decoded)
 }
 
 pub struct Target {
@@ -421,12 +785,8 @@ impl FromStr for Target {
         let url = format!("http://localhost/{}", s.trim_start_matches('/'));
         let url = Url::parse(&url).map_err(|_| ())?;
 
-        let path = percent_decode_str(url.path())
-            .decode_utf8()
-            .map_err(|_| ())?
-            .into_owned()
-            .trim_start_matches('/')
-            .to_string();
+        // Decoding mustn't bring back the dot segments the URL crate did away with
+        let path = decode_path(url.path())?.trim_start_matches('/').to_string();
 
         let query_str = url.query().unwrap_or("").to_string();
 
@@ -462,8 +822,19 @@ mod tests {
         // Invalid (multiple spaces between tokens)
         assert!("HTTP/1.1  200 OK".parse::<HttpStatusLine>().is_err());
 
-        // Invalid (extra trailing tokens)
-        assert!("HTTP/1.1 200 OK WTF".parse::<HttpStatusLine>().is_err());
+        // Valid (description with spaces)
+        let status_line: HttpStatusLine = "HTTP/1.1 404 Not Found".parse().unwrap();
+        assert_eq!(status_line.status_code, HttpStatusCode::NotFound);
+
+        // Valid (unknown response code kept as is)
+        let status_line: HttpStatusLine = "HTTP/1.1 418 I'm a teapot".parse().unwrap();
+        assert_eq!(status_line.status_code, HttpStatusCode::Other(418));
+        assert_eq!(status_line.to_string(), "HTTP/1.1 418 I'm a teapot");
+
+        // Invalid (not a final response code)
+        assert!("HTTP/1.1 103 Early Hints"
+            .parse::<HttpStatusLine>()
+            .is_err());
 
         // Invalid (malformed)
         assert!("".parse::<HttpStatusLine>().is_err());
@@ -477,12 +848,18 @@ mod tests {
 
     #[test]
     fn test_status_line_to_str() {
-        let status_line = HttpStatusLine {
+        let mut status_line = HttpStatusLine {
             http_version: HttpVersion::HTTP11,
             status_code: HttpStatusCode::Ok,
+            reason_phrase: None,
         };
 
         assert_eq!(status_line.to_string(), "HTTP/1.1 200 OK");
+
+        status_line.status_code = HttpStatusCode::Other(299);
+        assert_eq!(status_line.to_string(), "HTTP/1.1 299 ");
+        status_line.reason_phrase = Some(String::from("Fine"));
+        assert_eq!(status_line.to_string(), "HTTP/1.1 299 Fine");
     }
 
     #[test]
@@ -493,8 +870,16 @@ mod tests {
         assert_eq!(request_line.target, String::from("/index.html"));
         assert_eq!(request_line.http_version, HttpVersion::HTTP11);
 
-        // Invalid (invalid method)
-        assert!("FOO / HTTP/1.1".parse::<HttpRequestLine>().is_err());
+        // Valid (extension method)
+        let request_line: HttpRequestLine = "PROPFIND / HTTP/1.1".parse().unwrap();
+        assert_eq!(
+            request_line.method,
+            HttpMethod::Extension(String::from("PROPFIND"))
+        );
+        assert_eq!(request_line.to_string(), "PROPFIND / HTTP/1.1");
+
+        // Invalid (method isn't a token)
+        assert!("G(E)T / HTTP/1.1".parse::<HttpRequestLine>().is_err());
 
         // Invalid (invalid HTTP version)
         assert!("GET / HTTP/4.2".parse::<HttpRequestLine>().is_err());
@@ -517,6 +902,41 @@ mod tests {
         assert!("Hack the planet!".parse::<HttpRequestLine>().is_err());
     }
 
+    #[test]
+    fn test_method_from_str() {
+        let method = |s: &str| {
+            format!("{s} / HTTP/1.1")
+                .parse::<HttpRequestLine>()
+                .map(|request_line| request_line.method)
+        };
+
+        // Standard methods
+        for (s, expected) in [
+            ("GET", HttpMethod::Get),
+            ("HEAD", HttpMethod::Head),
+            ("POST", HttpMethod::Post),
+            ("PUT", HttpMethod::Put),
+            ("DELETE", HttpMethod::Delete),
+            ("CONNECT", HttpMethod::Connect),
+            ("OPTIONS", HttpMethod::Options),
+            ("TRACE", HttpMethod::Trace),
+            ("PATCH", HttpMethod::Patch),
+        ] {
+            assert_eq!(method(s).unwrap(), expected);
+            assert_eq!(expected.to_string(), s);
+        }
+
+        // Extension methods, with names being case-sensitive
+        for s in ["MKCOL", "M-SEARCH", "get", "BREW!"] {
+            assert_eq!(method(s).unwrap(), HttpMethod::Extension(String::from(s)));
+        }
+
+        // Invalid (not a token)
+        assert!(method("GET/").is_err());
+        assert!(method("GÉT").is_err());
+        assert!(method("").is_err());
+    }
+
     #[test]
     fn test_request_line_to_str() {
         let request_line = HttpRequestLine {
@@ -632,21 +1052,85 @@ mod tests {
     #[test]
     fn test_header_to_str() {
         let start_line = HttpStartLine::Request("GET /index.html HTTP/1.1".parse().unwrap());
-        let field_lines = HashMap::from([
+        let field_lines = [
             (String::from("connection"), String::from("keep-alive")),
             (String::from("host"), String::from("localhost:42")),
-        ]);
+        ]
+        .into_iter()
+        .collect();
 
-        let _header = HttpHeader {
+        let header = HttpHeader {
             start_line,
             field_lines,
         };
 
-        // Difficult as the order elements are stored in hashmap is non-deterministic...
-        /*assert_eq!(
+        assert_eq!(
             header.to_string(),
             "GET /index.html HTTP/1.1\r\nconnection: keep-alive\r\nhost: localhost:42\r\n\r\n"
-        );*/
+        );
+    }
+
+    #[test]
+    fn test_field_lines() {
+        let mut field_lines: FieldLines = [
+            (String::from("Content-Type"), String::from("text/html")),
+            (String::from("Set-Cookie"), String::from("a=1")),
+            (String::from("set-cookie"), String::from("b=2")),
+        ]
+        .into_iter()
+        .collect();
+        let cookies = |field_lines: &FieldLines| -> Vec<String> {
+            field_lines
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+                .map(|(_, value)| value.clone())
+                .collect()
+        };
+
+        // Names are case-insensitive
+        assert_eq!(field_lines["content-type"], "text/html");
+        assert!(field_lines.contains_key("CONTENT-TYPE"));
+        assert!(field_lines.get("content-length").is_none());
+
+        // Repeated fields are kept
+        assert_eq!(cookies(&field_lines), ["a=1", "b=2"]);
+
+        // Insert replaces
+        field_lines.insert(String::from("SET-COOKIE"), String::from("c=3"));
+        assert_eq!(cookies(&field_lines), ["c=3"]);
+
+        // Remove returns the first value
+        assert_eq!(
+            field_lines.remove("Content-Type"),
+            Some(String::from("text/html"))
+        );
+        assert!(!field_lines.contains_key("Content-Type"));
+    }
+
+    #[test]
+    fn test_content_length() {
+        let length = |fields: &str| {
+            format!("POST / HTTP/1.1\r\n{fields}\r\n")
+                .parse::<HttpHeader>()
+                .unwrap()
+                .content_length()
+        };
+
+        assert_eq!(length("Content-Length: 42\r\n").unwrap(), Some(42));
+        assert_eq!(length("Host: x\r\n").unwrap(), None);
+
+        // Repeats are fine as long as they agree
+        assert_eq!(
+            length("Content-Length: 42\r\ncontent-length: 42\r\n").unwrap(),
+            Some(42)
+        );
+        assert_eq!(length("Content-Length: 42, 42\r\n").unwrap(), Some(42));
+        assert!(length("Content-Length: 42\r\nContent-Length: 43\r\n").is_err());
+        assert!(length("Content-Length: 42, 0\r\n").is_err());
+
+        assert!(length("Content-Length: +42\r\n").is_err());
+        assert!(length("Content-Length: \r\n").is_err());
+        assert!(length("Content-Length: 99999999999999999999999\r\n").is_err());
     }
 
     #[test]
@@ -675,5 +1159,15 @@ mod tests {
         let target: Target = "/some/folder/../../../secrets.lol".parse().unwrap();
         assert_eq!(target.path, "secrets.lol");
         assert_eq!(target.query_str, "");
+
+        // Thwart encoded hacker test
+        let target: Target = "/a%20b/c%3F".parse().unwrap();
+        assert_eq!(target.path, "a b/c?");
+        assert!("/.%2fprivate/secret".parse::<Target>().is_err());
+        assert!("/x%2f..%2fprivate/secret".parse::<Target>().is_err());
+        let target: Target = "/x/%2e%2e/secret".parse().unwrap();
+        assert_eq!(target.path, "secret");
+        assert!("/x//secret".parse::<Target>().is_err());
+        assert!("/x%5c..%5csecret".parse::<Target>().is_err());
     }
 }
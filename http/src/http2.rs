@@ -0,0 +1,1325 @@
+//! This speaks HTTP/2 (RFC 9113) to clients that asked for it with ALPN, or
+//! that start with its connection preface on the plain port (h2c)
+//!
+//! Each request is turned into a regular `HttpMessage` and handled by
+//! `process_request` in a task of its own, so streams are answered in
+//! whatever order they finish. Frames are all written by a single writer,
+//! which also keeps track of how much data each stream may send (flow control).
+//! Data we receive is acknowledged right away, since bodies are held in
+//! memory anyway and limited by `max_body_len`.
+
+use crate::config::Config;
+use crate::connection::ConnectionInfo;
+use crate::hpack;
+use crate::http::*;
+use crate::log::{self, debug, error};
+use crate::response::{create_error_response, permits_request, process_request};
+use chrono::Local;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Sent by clients before anything else.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Streams a client may have open at once.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// Frame size everyone supports, which we never ask clients to go beyond.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+/// Fields that only make sense for a single HTTP/1 connection (RFC 9113, section 8.2.2).
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Reasons for resetting a stream or closing the connection (RFC 9113, section 7).
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ErrorCode {
+    NoError = 0x0,
+    Protocol = 0x1,
+    FlowControl = 0x3,
+    StreamClosed = 0x5,
+    FrameSize = 0x6,
+    RefusedStream = 0x7,
+    Compression = 0x9,
+    EnhanceYourCalm = 0xB,
+}
+
+#[derive(Debug, PartialEq)]
+enum Error {
+    /// Client went away (or stopped talking to us).
+    Closed,
+
+    /// Only the stream is done for.
+    Stream(u32, ErrorCode),
+
+    /// Whole connection is done for.
+    Connection(ErrorCode),
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, flags: u8, stream: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            flags,
+            stream,
+            payload,
+        }
+    }
+
+    fn rst_stream(stream: u32, code: ErrorCode) -> Self {
+        Self::new(RST_STREAM, 0, stream, (code as u32).to_be_bytes().to_vec())
+    }
+
+    fn goaway(last_stream: u32, code: ErrorCode) -> Self {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        Self::new(GOAWAY, 0, 0, payload)
+    }
+
+    fn window_update(stream: u32, increment: usize) -> Self {
+        Self::new(
+            WINDOW_UPDATE,
+            0,
+            stream,
+            (increment as u32).to_be_bytes().to_vec(),
+        )
+    }
+}
+
+fn frame_header(len: usize, kind: u8, flags: u8, stream: u32) -> [u8; 9] {
+    let len = (len as u32).to_be_bytes();
+    let stream = stream.to_be_bytes();
+    [
+        len[1], len[2], len[3], kind, flags, stream[0], stream[1], stream[2], stream[3],
+    ]
+}
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Result<Frame, Error> {
+    let mut header = [0; 9];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| Error::Closed)?;
+
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(Error::Connection(ErrorCode::FrameSize));
+    }
+    let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7FFF_FFFF;
+
+    let mut payload = vec![0; len];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|_| Error::Closed)?;
+
+    Ok(Frame::new(header[3], header[4], stream, payload))
+}
+
+/// Strips the padding (and priority) off the payload of a DATA or HEADERS frame.
+fn unpad(frame: &Frame) -> Result<&[u8], Error> {
+    let mut payload = &frame.payload[..];
+    let mut padding = 0;
+    if frame.flags & PADDED != 0 {
+        let (&len, rest) = payload
+            .split_first()
+            .ok_or(Error::Connection(ErrorCode::FrameSize))?;
+        padding = len as usize;
+        payload = rest;
+    }
+    if frame.kind == HEADERS && frame.flags & PRIORITY_FLAG != 0 {
+        payload = payload
+            .get(5..)
+            .ok_or(Error::Connection(ErrorCode::FrameSize))?;
+    }
+
+    payload
+        .len()
+        .checked_sub(padding)
+        .map(|len| &payload[..len])
+        .ok_or(Error::Connection(ErrorCode::Protocol))
+}
+
+/// Turns the fields of a HEADERS frame into a request header.
+fn request_header(fields: Vec<(String, String)>) -> Result<HttpHeader, crate::http::Error> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut field_lines = Vec::new();
+    let mut cookies = Vec::new();
+
+    for (name, value) in fields {
+        let valid = |s: &str| !s.contains(['\r', '\n', '\0']);
+        if !valid(&name) || !valid(&value) {
+            return Err(crate::http::Error::Malformed);
+        }
+
+        // Pseudo-fields come first, each exactly once
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let field = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err(crate::http::Error::Malformed),
+            };
+            if !field_lines.is_empty() || !cookies.is_empty() || field.replace(value).is_some() {
+                return Err(crate::http::Error::Malformed);
+            }
+            continue;
+        }
+
+        if name.bytes().any(|b| b.is_ascii_uppercase())
+            || CONNECTION_SPECIFIC.contains(&name.as_str())
+            || (name == "te" && value != "trailers")
+        {
+            return Err(crate::http::Error::Malformed);
+        }
+
+        // Cookies may be split up to compress better (RFC 9113, section 8.2.3)
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            field_lines.push((name, value));
+        }
+    }
+
+    let (Some(method), Some(target), Some(_)) = (method, path, scheme) else {
+        return Err(crate::http::Error::Malformed);
+    };
+    if target.is_empty() {
+        return Err(crate::http::Error::Malformed);
+    }
+
+    if !cookies.is_empty() {
+        field_lines.push((String::from("cookie"), cookies.join("; ")));
+    }
+
+    // Authority takes the place of Host, which virtual hosts rely on
+    match authority {
+        Some(authority) if !field_lines.iter().any(|(name, _)| name == "host") => {
+            field_lines.push((String::from("host"), authority));
+        }
+        _ => (),
+    }
+
+    Ok(HttpHeader {
+        start_line: HttpStartLine::Request(HttpRequestLine {
+            method: method.as_str().try_into()?,
+            target,
+            http_version: HttpVersion::HTTP2,
+        }),
+        field_lines: field_lines.into_iter().collect(),
+    })
+}
+
+/// What the writer is asked to do.
+enum Command {
+    /// Write a frame as is.
+    Frame(Frame),
+
+    /// A stream was opened, and may now be sent data.
+    Open(u32),
+
+    /// Write a field block, split up into as many frames as it takes.
+    Headers {
+        stream: u32,
+        block: Vec<u8>,
+        end_stream: bool,
+    },
+
+    /// Write data once flow control allows it, then report back on `sent`.
+    Data {
+        stream: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+        sent: oneshot::Sender<()>,
+    },
+
+    /// Client allows us to send more data (on the connection if `stream` is 0),
+    /// reporting back on `accepted` whether the window can grow that much.
+    WindowUpdate {
+        stream: u32,
+        increment: u32,
+        accepted: oneshot::Sender<bool>,
+    },
+
+    /// Client changed its settings, reported back on `accepted` like window updates.
+    Settings {
+        initial_window_size: Option<u32>,
+        max_frame_size: Option<u32>,
+        accepted: oneshot::Sender<bool>,
+    },
+
+    /// Stream was reset, so anything left for it is dropped.
+    Reset(u32),
+}
+
+/// Data waiting for flow control to allow sending it.
+struct Pending {
+    stream: u32,
+    data: Vec<u8>,
+    offset: usize,
+    end_stream: bool,
+    sent: oneshot::Sender<()>,
+}
+
+struct Writer<W: AsyncWrite + Unpin> {
+    stream: BufWriter<W>,
+    max_frame_size: usize,
+
+    /// Flow control windows of the connection and of each open stream.
+    window: i64,
+    initial_window_size: i64,
+    windows: HashMap<u32, i64>,
+
+    pending: VecDeque<Pending>,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    fn new(stream: W) -> Self {
+        Self {
+            stream: BufWriter::new(stream),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            window: DEFAULT_WINDOW_SIZE,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            windows: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    async fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let header = frame_header(payload.len(), kind, flags, stream);
+        self.stream.write_all(&header).await?;
+        self.stream.write_all(payload).await
+    }
+
+    async fn handle(&mut self, command: Command) -> std::io::Result<()> {
+        match command {
+            Command::Frame(frame) => {
+                self.write_frame(frame.kind, frame.flags, frame.stream, &frame.payload)
+                    .await?;
+            }
+            Command::Open(stream) => {
+                self.windows.insert(stream, self.initial_window_size);
+            }
+            Command::Headers {
+                stream,
+                block,
+                end_stream,
+            } => {
+                let chunks: Vec<_> = block.chunks(self.max_frame_size).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let mut flags = 0;
+                    if i == chunks.len() - 1 {
+                        flags |= END_HEADERS;
+                    }
+                    let kind = if i == 0 {
+                        if end_stream {
+                            flags |= END_STREAM;
+                        }
+                        HEADERS
+                    } else {
+                        CONTINUATION
+                    };
+                    self.write_frame(kind, flags, stream, chunk).await?;
+                }
+
+                if end_stream {
+                    self.windows.remove(&stream);
+                }
+            }
+            Command::Data {
+                stream,
+                data,
+                end_stream,
+                sent,
+            } => self.pending.push_back(Pending {
+                stream,
+                data,
+                offset: 0,
+                end_stream,
+                sent,
+            }),
+            Command::WindowUpdate {
+                stream,
+                increment,
+                accepted,
+            } => {
+                let window = if stream == 0 {
+                    Some(&mut self.window)
+                } else {
+                    self.windows.get_mut(&stream)
+                };
+                let mut fits = true;
+                if let Some(window) = window {
+                    fits = *window + increment as i64 <= MAX_WINDOW_SIZE;
+                    if fits {
+                        *window += increment as i64;
+                    }
+                }
+                let _ = accepted.send(fits);
+            }
+            Command::Settings {
+                initial_window_size,
+                max_frame_size,
+                accepted,
+            } => {
+                // Changes to the initial window apply to open streams too (RFC 9113, section 6.9.2)
+                if let Some(size) = initial_window_size {
+                    let delta = size as i64 - self.initial_window_size;
+                    if self
+                        .windows
+                        .values()
+                        .any(|window| window + delta > MAX_WINDOW_SIZE)
+                    {
+                        let _ = accepted.send(false);
+                        return Ok(());
+                    }
+                    self.initial_window_size = size as i64;
+                    for window in self.windows.values_mut() {
+                        *window += delta;
+                    }
+                }
+                if let Some(size) = max_frame_size {
+                    self.max_frame_size = size as usize;
+                }
+                let _ = accepted.send(true);
+            }
+            Command::Reset(stream) => {
+                self.windows.remove(&stream);
+                self.pending.retain(|pending| pending.stream != stream);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends as much pending data as flow control allows.
+    async fn send_pending(&mut self) -> std::io::Result<()> {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let pending = &self.pending[i];
+            let stream = pending.stream;
+            let Some(&stream_window) = self.windows.get(&stream) else {
+                self.pending.remove(i);
+                continue;
+            };
+
+            let remaining = pending.data.len() - pending.offset;
+            let len = remaining
+                .min(self.max_frame_size)
+                .min(self.window.max(0) as usize)
+                .min(stream_window.max(0) as usize);
+            if len == 0 && remaining > 0 {
+                i += 1;
+                continue;
+            }
+
+            let done = len == remaining;
+            let flags = if done && pending.end_stream {
+                END_STREAM
+            } else {
+                0
+            };
+            let (start, end) = (pending.offset, pending.offset + len);
+            let header = frame_header(len, DATA, flags, stream);
+            self.stream.write_all(&header).await?;
+            self.stream
+                .write_all(&self.pending[i].data[start..end])
+                .await?;
+
+            self.window -= len as i64;
+            if let Some(window) = self.windows.get_mut(&stream) {
+                *window -= len as i64;
+            }
+            self.pending[i].offset = end;
+
+            if done {
+                let pending = self.pending.remove(i).expect("Index is in bounds");
+                if pending.end_stream {
+                    self.windows.remove(&stream);
+                }
+                let _ = pending.sent.send(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes whatever it is asked to until everyone is done asking, or the client goes away.
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        while let Some(command) = commands.recv().await {
+            // Take care of everything else already waiting before flushing
+            let mut result = self.handle(command).await;
+            while result.is_ok() {
+                let Ok(command) = commands.try_recv() else {
+                    break;
+                };
+                result = self.handle(command).await;
+            }
+
+            let result = match result {
+                Ok(_) => self.send_pending().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result.and(self.stream.flush().await) {
+                error!("Error writing to stream: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Sends a response on `stream`, returning how many bytes of body were sent,
+/// or early if the stream is reset.
+async fn send_response(
+    stream: u32,
+    response: HttpMessage,
+    commands: &mpsc::Sender<Command>,
+) -> Result<u64, ()> {
+    let HttpMessage { header, body } = response;
+    let HttpStartLine::Response(status_line) = &header.start_line else {
+        return Err(());
+    };
+
+    let status = u16::from(status_line.status_code).to_string();
+    let fields: Vec<(String, &str)> = header
+        .field_lines
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), value.as_str()))
+        .filter(|(name, _)| !CONNECTION_SPECIFIC.contains(&name.as_str()))
+        .collect();
+    let block = hpack::encode(
+        [(":status", status.as_str())]
+            .into_iter()
+            .chain(fields.iter().map(|(name, value)| (name.as_str(), *value))),
+    );
+
+    let body = body.filter(|body| body.len() != Some(0));
+    commands
+        .send(Command::Headers {
+            stream,
+            block,
+            end_stream: body.is_none(),
+        })
+        .await
+        .map_err(|_| ())?;
+
+    let send_data = |data: Vec<u8>, end_stream: bool| async move {
+        let (sent, done) = oneshot::channel();
+        commands
+            .send(Command::Data {
+                stream,
+                data,
+                end_stream,
+                sent,
+            })
+            .await
+            .map_err(|_| ())?;
+        done.await.map_err(|_| ())
+    };
+
+    let mut sent = 0;
+    match body {
+        None => (),
+        Some(HttpBody::Full(data)) => {
+            let chunks: Vec<_> = data.chunks(DEFAULT_MAX_FRAME_SIZE).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                send_data(chunk.to_vec(), i == chunks.len() - 1).await?;
+            }
+            sent = data.len() as u64;
+        }
+        Some(HttpBody::Stream { reader, len }) => {
+            let mut reader = reader.take(len.unwrap_or(u64::MAX));
+            loop {
+                let mut data = Vec::with_capacity(DEFAULT_MAX_FRAME_SIZE);
+                let end_stream = match (&mut reader)
+                    .take(DEFAULT_MAX_FRAME_SIZE as u64)
+                    .read_to_end(&mut data)
+                    .await
+                {
+                    Ok(n) => n == 0,
+                    Err(e) => {
+                        error!("Error reading response body: {e}");
+                        let _ = commands
+                            .send(Command::Frame(Frame::rst_stream(
+                                stream,
+                                ErrorCode::Protocol,
+                            )))
+                            .await;
+                        let _ = commands.send(Command::Reset(stream)).await;
+                        return Err(());
+                    }
+                };
+                sent += data.len() as u64;
+                send_data(data, end_stream).await?;
+                if end_stream {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Handles a request on `stream`, or answers with an error if it is already known to be bad.
+async fn respond(
+    config: Arc<Config>,
+    conn: Arc<ConnectionInfo>,
+    stream: u32,
+    request: Result<HttpMessage, HttpStatusCode>,
+    commands: mpsc::Sender<Command>,
+) {
+    let mut request = match request {
+        Ok(request) => request,
+        Err(status_code) => {
+            let (time, started) = (Local::now(), Instant::now());
+            let response = create_error_response(&config, status_code).await;
+            let sent = send_response(stream, response, &commands).await;
+            log::access(&log::Access {
+                addr: conn.addr.ip(),
+                header: None,
+                status: u16::from(status_code),
+                bytes: sent.unwrap_or(0),
+                time,
+                duration: started.elapsed(),
+            });
+            return;
+        }
+    };
+
+    let (time, started) = (Local::now(), Instant::now());
+    let response = process_request(&config, &conn, &mut request).await;
+    let status = u16::from(response.header.status_line().status_code);
+    let sent = send_response(stream, response, &commands).await;
+    log::access(&log::Access {
+        addr: conn.addr.ip(),
+        header: Some(&request.header),
+        status,
+        bytes: sent.unwrap_or(0),
+        time,
+        duration: started.elapsed(),
+    });
+}
+
+/// A stream whose request is still coming in.
+struct Incoming {
+    /// None if the request was already answered with an error, so the rest is ignored.
+    header: Option<HttpHeader>,
+    body: Vec<u8>,
+}
+
+/// Reads frames from a client, and starts handling requests as they come in.
+struct Reader {
+    config: Arc<Config>,
+    conn: Arc<ConnectionInfo>,
+    commands: mpsc::Sender<Command>,
+    decoder: hpack::Decoder,
+    incoming: HashMap<u32, Incoming>,
+    tasks: HashMap<u32, JoinHandle<()>>,
+
+    /// Highest stream the client opened so far.
+    last_stream: u32,
+}
+
+impl Reader {
+    async fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands.send(command).await.map_err(|_| Error::Closed)
+    }
+
+    fn spawn(&mut self, stream: u32, request: Result<HttpMessage, HttpStatusCode>) {
+        let task = tokio::spawn(respond(
+            Arc::clone(&self.config),
+            Arc::clone(&self.conn),
+            stream,
+            request,
+            self.commands.clone(),
+        ));
+        self.tasks.insert(stream, task);
+    }
+
+    /// Starts handling a request once it came in completely.
+    fn finish(&mut self, stream: u32) -> Result<(), Error> {
+        let Some(Incoming {
+            header: Some(header),
+            body,
+        }) = self.incoming.remove(&stream)
+        else {
+            return Ok(());
+        };
+
+        // Content-Length is optional, but has to be right
+        let length = header.field_lines.get("content-length");
+        if length.is_some_and(|length| length.parse() != Ok(body.len())) {
+            return Err(Error::Stream(stream, ErrorCode::Protocol));
+        }
+
+        let body = (length.is_some() || !body.is_empty()).then_some(HttpBody::Full(body));
+        self.spawn(stream, Ok(HttpMessage { header, body }));
+        Ok(())
+    }
+
+    async fn handle_headers(
+        &mut self,
+        frame: Frame,
+        reader: &mut (impl AsyncRead + Unpin),
+    ) -> Result<(), Error> {
+        let stream = frame.stream;
+        if stream == 0 {
+            return Err(Error::Connection(ErrorCode::Protocol));
+        }
+        let end_stream = frame.flags & END_STREAM != 0;
+
+        // Field block may continue in other frames, which have to follow right away
+        let mut block = unpad(&frame)?.to_vec();
+        let mut end_headers = frame.flags & END_HEADERS != 0;
+        while !end_headers {
+            let frame = read_frame(reader).await?;
+            if frame.kind != CONTINUATION || frame.stream != stream {
+                return Err(Error::Connection(ErrorCode::Protocol));
+            }
+            if block.len() + frame.payload.len() > self.config.max_header_len {
+                return Err(Error::Connection(ErrorCode::EnhanceYourCalm));
+            }
+            block.extend_from_slice(&frame.payload);
+            end_headers = frame.flags & END_HEADERS != 0;
+        }
+
+        // Always decoded, since it updates the dynamic table
+        let fields = self
+            .decoder
+            .decode(&block, self.config.max_header_len)
+            .map_err(|_| Error::Connection(ErrorCode::Compression))?;
+
+        // Trailers, which we have no use for
+        if self.incoming.contains_key(&stream) {
+            if !end_stream {
+                return Err(Error::Stream(stream, ErrorCode::Protocol));
+            }
+            return self.finish(stream);
+        }
+
+        if stream <= self.last_stream {
+            return Err(Error::Stream(stream, ErrorCode::StreamClosed));
+        }
+        if stream.is_multiple_of(2) {
+            return Err(Error::Connection(ErrorCode::Protocol));
+        }
+        self.last_stream = stream;
+
+        self.tasks.retain(|_, task| !task.is_finished());
+        if self.tasks.len() + self.incoming.len() >= MAX_CONCURRENT_STREAMS {
+            return Err(Error::Stream(stream, ErrorCode::RefusedStream));
+        }
+        self.send(Command::Open(stream)).await?;
+
+        // Too large headers are answered like they would be over HTTP/1
+        let header = match fields.map(request_header) {
+            None => Err(HttpStatusCode::ContentTooLarge),
+            Some(Ok(header)) => Ok(header),
+            Some(Err(_)) => return Err(Error::Stream(stream, ErrorCode::Protocol)),
+        };
+
+        // As with bodies that turn out too large, the rest of the request is ignored
+        let too_large = header.as_ref().is_ok_and(|header| {
+            header
+                .field_lines
+                .get("content-length")
+                .and_then(|length| length.parse::<usize>().ok())
+                .is_some_and(|length| length > self.config.max_body_len)
+        });
+        let header = match header {
+            Ok(_) if too_large => Err(HttpStatusCode::ContentTooLarge),
+            Ok(header) if !permits_request(&self.config, &header, self.conn.addr.ip()) => {
+                Err(HttpStatusCode::Forbidden)
+            }
+            header => header,
+        };
+
+        let header = match header {
+            Ok(header) => header,
+            Err(status_code) => {
+                self.spawn(stream, Err(status_code));
+                if !end_stream {
+                    let incoming = Incoming {
+                        header: None,
+                        body: Vec::new(),
+                    };
+                    self.incoming.insert(stream, incoming);
+                }
+                return Ok(());
+            }
+        };
+
+        if end_stream {
+            self.spawn(stream, Ok(HttpMessage { header, body: None }));
+            return Ok(());
+        }
+
+        // Clients uploading a body may wait for our go-ahead before sending it
+        let expects_continue = header
+            .field_lines
+            .get("expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        if expects_continue {
+            self.send(Command::Headers {
+                stream,
+                block: hpack::encode([(":status", "100")]),
+                end_stream: false,
+            })
+            .await?;
+        }
+
+        let incoming = Incoming {
+            header: Some(header),
+            body: Vec::new(),
+        };
+        self.incoming.insert(stream, incoming);
+        Ok(())
+    }
+
+    async fn handle_data(&mut self, frame: Frame) -> Result<(), Error> {
+        let stream = frame.stream;
+        let end_stream = frame.flags & END_STREAM != 0;
+        let data = unpad(&frame)?;
+
+        // Data counts against flow control no matter what, so we give it back right away
+        let len = frame.payload.len();
+        if len > 0 {
+            self.send(Command::Frame(Frame::window_update(0, len)))
+                .await?;
+        }
+
+        let Some(incoming) = self.incoming.get_mut(&stream) else {
+            return Err(if stream == 0 || stream > self.last_stream {
+                Error::Connection(ErrorCode::Protocol)
+            } else {
+                Error::Stream(stream, ErrorCode::StreamClosed)
+            });
+        };
+
+        if incoming.header.is_some() {
+            incoming.body.extend_from_slice(data);
+            if incoming.body.len() > self.config.max_body_len {
+                incoming.header = None;
+                incoming.body = Vec::new();
+                self.spawn(stream, Err(HttpStatusCode::ContentTooLarge));
+            }
+        }
+
+        if end_stream {
+            return self.finish(stream);
+        }
+        if len > 0 {
+            self.send(Command::Frame(Frame::window_update(stream, len)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_settings(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream != 0 {
+            return Err(Error::Connection(ErrorCode::Protocol));
+        }
+        if frame.flags & ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(Error::Connection(ErrorCode::FrameSize)),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(ErrorCode::FrameSize));
+        }
+
+        let (mut initial_window_size, mut max_frame_size) = (None, None);
+        for setting in frame.payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW_SIZE => {
+                    return Err(Error::Connection(ErrorCode::FlowControl));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => initial_window_size = Some(value),
+                SETTINGS_MAX_FRAME_SIZE
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=MAX_FRAME_SIZE).contains(&value) =>
+                {
+                    return Err(Error::Connection(ErrorCode::Protocol));
+                }
+                SETTINGS_MAX_FRAME_SIZE => max_frame_size = Some(value),
+
+                // We never push or add to the dynamic table, so the rest doesn't matter
+                _ => (),
+            }
+        }
+
+        let (accepted, answer) = oneshot::channel();
+        self.send(Command::Settings {
+            initial_window_size,
+            max_frame_size,
+            accepted,
+        })
+        .await?;
+        if !answer.await.map_err(|_| Error::Closed)? {
+            return Err(Error::Connection(ErrorCode::FlowControl));
+        }
+        self.send(Command::Frame(Frame::new(SETTINGS, ACK, 0, Vec::new())))
+            .await
+    }
+
+    /// Handles a frame, returning false once the client says goodbye.
+    async fn handle_frame(
+        &mut self,
+        frame: Frame,
+        reader: &mut (impl AsyncRead + Unpin),
+    ) -> Result<bool, Error> {
+        match frame.kind {
+            DATA => self.handle_data(frame).await?,
+            HEADERS => self.handle_headers(frame, reader).await?,
+            PRIORITY if frame.stream == 0 => return Err(Error::Connection(ErrorCode::Protocol)),
+            PRIORITY if frame.payload.len() != 5 => {
+                return Err(Error::Stream(frame.stream, ErrorCode::FrameSize));
+            }
+            RST_STREAM => {
+                if frame.stream == 0 {
+                    return Err(Error::Connection(ErrorCode::Protocol));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Error::Connection(ErrorCode::FrameSize));
+                }
+                self.incoming.remove(&frame.stream);
+                if let Some(task) = self.tasks.remove(&frame.stream) {
+                    task.abort();
+                }
+                self.send(Command::Reset(frame.stream)).await?;
+            }
+            SETTINGS => self.handle_settings(frame).await?,
+            PING => {
+                if frame.stream != 0 {
+                    return Err(Error::Connection(ErrorCode::Protocol));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Error::Connection(ErrorCode::FrameSize));
+                }
+                if frame.flags & ACK == 0 {
+                    self.send(Command::Frame(Frame::new(PING, ACK, 0, frame.payload)))
+                        .await?;
+                }
+            }
+            GOAWAY => return Ok(false),
+            WINDOW_UPDATE => {
+                let Ok(increment) = <[u8; 4]>::try_from(&frame.payload[..]) else {
+                    return Err(Error::Connection(ErrorCode::FrameSize));
+                };
+                let increment = u32::from_be_bytes(increment) & 0x7FFF_FFFF;
+                if increment == 0 {
+                    return Err(match frame.stream {
+                        0 => Error::Connection(ErrorCode::Protocol),
+                        stream => Error::Stream(stream, ErrorCode::Protocol),
+                    });
+                }
+                let (accepted, answer) = oneshot::channel();
+                self.send(Command::WindowUpdate {
+                    stream: frame.stream,
+                    increment,
+                    accepted,
+                })
+                .await?;
+
+                // Windows may not grow past what they can hold (RFC 9113, section 6.9.1)
+                if !answer.await.map_err(|_| Error::Closed)? {
+                    return Err(match frame.stream {
+                        0 => Error::Connection(ErrorCode::FlowControl),
+                        stream => Error::Stream(stream, ErrorCode::FlowControl),
+                    });
+                }
+            }
+            PUSH_PROMISE | CONTINUATION => return Err(Error::Connection(ErrorCode::Protocol)),
+
+            // Unknown frames (and priorities) are ignored
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    /// Reads frames until the connection is done, returning the streams still being answered.
+    async fn run(mut self, mut reader: impl AsyncRead + Unpin) -> Vec<JoinHandle<()>> {
+        let read_timeout = Duration::from_secs(self.config.max_timeout);
+        loop {
+            // Connection is closed once idle for too long, like with HTTP/1
+            self.tasks.retain(|_, task| !task.is_finished());
+            let frame = if self.tasks.is_empty() {
+                match timeout(read_timeout, read_frame(&mut reader)).await {
+                    Ok(frame) => frame,
+                    Err(_) => Err(Error::Connection(ErrorCode::NoError)),
+                }
+            } else {
+                read_frame(&mut reader).await
+            };
+
+            let result = match frame {
+                Ok(frame) => self.handle_frame(frame, &mut reader).await,
+                Err(e) => Err(e),
+            };
+
+            let code = match result {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(Error::Closed) => {
+                    // Nobody is left to answer, and the streams may be waiting on flow control
+                    for task in self.tasks.values() {
+                        task.abort();
+                    }
+                    break;
+                }
+                Err(Error::Stream(stream, code)) => {
+                    self.incoming.remove(&stream);
+                    let rst_stream = Command::Frame(Frame::rst_stream(stream, code));
+                    if self.send(rst_stream).await.is_err()
+                        || self.send(Command::Reset(stream)).await.is_err()
+                    {
+                        break;
+                    }
+                    continue;
+                }
+                Err(Error::Connection(code)) => code,
+            };
+
+            // Streams we already started on are still answered
+            let goaway = Frame::goaway(self.last_stream, code);
+            let _ = self.send(Command::Frame(goaway)).await;
+            break;
+        }
+
+        self.tasks.into_values().collect()
+    }
+}
+
+/// Handles an HTTP/2 connection until the client closes it, or there is an error.
+pub async fn handle_connection(
+    config: Arc<Config>,
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    conn: ConnectionInfo,
+) {
+    let (mut reader, writer) = tokio::io::split(stream);
+
+    let mut preface = [0; PREFACE.len()];
+    if reader.read_exact(&mut preface).await.is_err() || preface != PREFACE {
+        debug!("Invalid HTTP/2 preface, closing connection...");
+        return;
+    }
+
+    // Our own preface is our settings
+    let (commands, receiver) = mpsc::channel(64);
+    let mut settings = Vec::new();
+    for (id, value) in [
+        (
+            SETTINGS_MAX_CONCURRENT_STREAMS,
+            MAX_CONCURRENT_STREAMS as u32,
+        ),
+        (SETTINGS_MAX_HEADER_LIST_SIZE, config.max_header_len as u32),
+    ] {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+    let _ = commands
+        .send(Command::Frame(Frame::new(SETTINGS, 0, 0, settings)))
+        .await;
+
+    let write_timeout = Duration::from_secs(config.max_timeout);
+    let reading = Reader {
+        config,
+        conn: Arc::new(conn),
+        commands,
+        decoder: hpack::Decoder::new(),
+        incoming: HashMap::new(),
+        tasks: HashMap::new(),
+        last_stream: 0,
+    }
+    .run(reader);
+    let writing = Writer::new(writer).run(receiver);
+    tokio::pin!(writing);
+
+    // Once the client is done, we still finish answering what it asked for
+    let tasks = tokio::select! {
+        tasks = reading => tasks,
+        _ = &mut writing => return,
+    };
+    // But not for longer than we would wait on a client that stopped reading
+    if timeout(write_timeout, writing).await.is_err() {
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{duplex, DuplexStream};
+
+    async fn write_frame(stream: &mut DuplexStream, frame: Frame) {
+        let header = frame_header(frame.payload.len(), frame.kind, frame.flags, frame.stream);
+        stream.write_all(&header).await.unwrap();
+        stream.write_all(&frame.payload).await.unwrap();
+    }
+
+    /// Reads frames until one of `kind` comes along.
+    async fn expect_frame(stream: &mut DuplexStream, kind: u8) -> Frame {
+        loop {
+            let frame = read_frame(stream).await.unwrap();
+            if frame.kind == kind {
+                return frame;
+            }
+        }
+    }
+
+    fn request(stream: u32, path: &str, end_stream: bool) -> Frame {
+        let block = hpack::encode([
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ]);
+        let flags = END_HEADERS | if end_stream { END_STREAM } else { 0 };
+        Frame::new(HEADERS, flags, stream, block)
+    }
+
+    fn start() -> DuplexStream {
+        start_connection().0
+    }
+
+    fn start_connection() -> (DuplexStream, JoinHandle<()>) {
+        let (client, server) = duplex(1 << 20);
+        let config = Arc::new(Config {
+            server_root: String::from("/nonexistent"),
+            ..Config::default()
+        });
+        let conn = ConnectionInfo {
+            addr: "192.0.2.7:4242".parse::<SocketAddr>().unwrap(),
+            https: false,
+            client_cert: None,
+        };
+        let connection = tokio::spawn(handle_connection(config, server, conn));
+        (client, connection)
+    }
+
+    #[test]
+    fn test_request_header() {
+        let fields = |fields: &[(&str, &str)]| {
+            fields
+                .iter()
+                .map(|&(n, v)| (n.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let header = request_header(fields(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/form?a=b"),
+            ("cookie", "a=1"),
+            ("content-type", "text/plain"),
+            ("cookie", "b=2"),
+        ]))
+        .unwrap();
+        let request_line = header.request_line();
+        assert_eq!(request_line.method, HttpMethod::Post);
+        assert_eq!(request_line.target, "/form?a=b");
+        assert_eq!(request_line.http_version, HttpVersion::HTTP2);
+        assert_eq!(header.field_lines["host"], "example.com");
+        assert_eq!(header.field_lines["cookie"], "a=1; b=2");
+
+        let get = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+        assert!(request_header(fields(&get)).is_ok());
+
+        // Missing, repeated or late pseudo-fields
+        assert!(request_header(fields(&get[..2])).is_err());
+        assert!(request_header(fields(&[get[0], get[1], get[2], get[2]])).is_err());
+        assert!(request_header(fields(&[get[0], get[1], ("accept", "*/*"), get[2]])).is_err());
+
+        // Uppercase and connection-specific fields
+        assert!(request_header(fields(&[get[0], get[1], get[2], ("Accept", "*/*")])).is_err());
+        assert!(
+            request_header(fields(&[get[0], get[1], get[2], ("connection", "close")])).is_err()
+        );
+        assert!(request_header(fields(&[get[0], get[1], get[2], ("te", "trailers")])).is_ok());
+        assert!(request_header(fields(&[get[0], get[1], get[2], ("x", "a\r\nb: c")])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let mut client = start();
+        client.write_all(PREFACE).await.unwrap();
+        write_frame(&mut client, Frame::new(SETTINGS, 0, 0, Vec::new())).await;
+
+        // Server starts with its settings, and acknowledges ours
+        let settings = read_frame(&mut client).await.unwrap();
+        assert_eq!((settings.kind, settings.flags), (SETTINGS, 0));
+        let ack = expect_frame(&mut client, SETTINGS).await;
+        assert_eq!(ack.flags, ACK);
+
+        write_frame(&mut client, Frame::new(PING, 0, 0, b"helios!!".to_vec())).await;
+        let pong = expect_frame(&mut client, PING).await;
+        assert_eq!((pong.flags, &pong.payload[..]), (ACK, &b"helios!!"[..]));
+
+        // Two streams at once, both answered (with a 404, since there is nothing to serve)
+        write_frame(&mut client, request(1, "/a", true)).await;
+        write_frame(&mut client, request(3, "/b", true)).await;
+        let mut decoder = hpack::Decoder::new();
+        let mut answered = Vec::new();
+        while answered.len() < 2 {
+            let headers = expect_frame(&mut client, HEADERS).await;
+            let fields = decoder.decode(&headers.payload, 8192).unwrap().unwrap();
+            assert_eq!(fields[0], (String::from(":status"), String::from("404")));
+            assert!(!fields.iter().any(|(name, _)| name == "connection"));
+            answered.push(headers.stream);
+        }
+        answered.sort();
+        assert_eq!(answered, [1, 3]);
+
+        // Streams may not go backwards
+        write_frame(&mut client, request(1, "/a", true)).await;
+        let rst_stream = expect_frame(&mut client, RST_STREAM).await;
+        assert_eq!(rst_stream.stream, 1);
+        assert_eq!(
+            rst_stream.payload,
+            (ErrorCode::StreamClosed as u32).to_be_bytes()
+        );
+
+        // Nor be even
+        write_frame(&mut client, request(4, "/a", true)).await;
+        let goaway = expect_frame(&mut client, GOAWAY).await;
+        assert_eq!(goaway.payload[..4], 3u32.to_be_bytes());
+        assert_eq!(
+            goaway.payload[4..],
+            (ErrorCode::Protocol as u32).to_be_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let mut client = start();
+        client.write_all(PREFACE).await.unwrap();
+
+        // Client allows no data at all, at first
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        write_frame(&mut client, Frame::new(SETTINGS, 0, 0, settings)).await;
+        expect_frame(&mut client, SETTINGS).await;
+        expect_frame(&mut client, SETTINGS).await;
+
+        write_frame(&mut client, request(1, "/", true)).await;
+        let headers = expect_frame(&mut client, HEADERS).await;
+        assert_eq!(headers.flags & END_STREAM, 0);
+
+        // Then just 10 bytes
+        write_frame(&mut client, Frame::window_update(1, 10)).await;
+        let data = expect_frame(&mut client, DATA).await;
+        assert_eq!((data.payload.len(), data.flags), (10, 0));
+
+        // Then the rest
+        write_frame(&mut client, Frame::window_update(1, 1 << 20)).await;
+        let data = expect_frame(&mut client, DATA).await;
+        assert!(!data.payload.is_empty());
+        assert_eq!(data.flags, END_STREAM);
+
+        // Windows can't grow past 2^31 - 1, on a stream or the whole connection
+        // (the request not being over yet, so no data goes out in between)
+        write_frame(&mut client, request(3, "/", false)).await;
+        write_frame(
+            &mut client,
+            Frame::window_update(3, MAX_WINDOW_SIZE as usize),
+        )
+        .await;
+        write_frame(&mut client, Frame::window_update(3, 1)).await;
+        let rst_stream = expect_frame(&mut client, RST_STREAM).await;
+        assert_eq!(rst_stream.stream, 3);
+        assert_eq!(
+            rst_stream.payload,
+            (ErrorCode::FlowControl as u32).to_be_bytes()
+        );
+
+        write_frame(
+            &mut client,
+            Frame::window_update(0, MAX_WINDOW_SIZE as usize),
+        )
+        .await;
+        let goaway = expect_frame(&mut client, GOAWAY).await;
+        assert_eq!(
+            goaway.payload[4..],
+            (ErrorCode::FlowControl as u32).to_be_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closed_while_blocked() {
+        let (mut client, connection) = start_connection();
+        client.write_all(PREFACE).await.unwrap();
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        write_frame(&mut client, Frame::new(SETTINGS, 0, 0, settings)).await;
+        write_frame(&mut client, request(1, "/", true)).await;
+        expect_frame(&mut client, HEADERS).await;
+
+        // Response is stuck waiting for a window that will never come
+        drop(client);
+        timeout(Duration::from_secs(5), connection)
+            .await
+            .expect("connection should end with the client")
+            .unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+//! This keeps single clients from hogging the server
+//!
+//! Requests are rate limited per client IP with token buckets: each client
+//! gets a bucket of `burst` tokens that refills at the configured rate, and
+//! every request takes one. Clients with an empty bucket get a 429 until it
+//! refills. Clients may also only have so many connections open at once.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often buckets that filled up again are forgotten.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many requests clients may make, like `10/s` or `60/m 20`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Requests per second, on average.
+    pub rate: f64,
+
+    /// Requests that may be made at once, after a quiet spell.
+    pub burst: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (requests, per) = parts.next().ok_or(())?.split_once('/').ok_or(())?;
+        let requests: f64 = requests.parse().map_err(|_| ())?;
+        let seconds = match per {
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(()),
+        };
+
+        // Clients may use up a whole period's worth of requests at once, unless told otherwise
+        let burst = match parts.next() {
+            Some(burst) => burst.parse().map_err(|_| ())?,
+            None => requests,
+        };
+        if !(requests > 0.0 && burst >= 1.0) || parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(Self {
+            rate: requests / seconds,
+            burst,
+        })
+    }
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    /// Refills the bucket for the time since it was last used.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long until there is one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.rate,
+            ))
+        }
+    }
+}
+
+struct Buckets {
+    /// By client, the server (by its first name, empty for the top level) and the
+    /// path prefix the limit is for (empty for the whole server).
+    buckets: HashMap<(IpAddr, String, String), Bucket>,
+    purged: Instant,
+}
+
+static BUCKETS: LazyLock<Mutex<Buckets>> = LazyLock::new(|| {
+    Mutex::new(Buckets {
+        buckets: HashMap::new(),
+        purged: Instant::now(),
+    })
+});
+
+/// Counts a request from `ip` against `limit` (for `prefix` on `server`), returning
+/// how many seconds the client should wait before trying again if it's over.
+pub fn check_rate(ip: IpAddr, server: &str, prefix: &str, limit: RateLimit) -> Result<(), u64> {
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap_or_else(|e| e.into_inner());
+
+    // Full buckets are no different from new ones
+    if now.duration_since(buckets.purged) >= PURGE_INTERVAL {
+        buckets.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limit.burst
+        });
+        buckets.purged = now;
+    }
+
+    let bucket = buckets
+        .buckets
+        .entry((ip, server.to_string(), prefix.to_string()))
+        .or_insert_with(|| Bucket::new(limit, now));
+
+    // The limit may have changed since, if the config was reloaded
+    bucket.limit = limit;
+    bucket
+        .take(now)
+        .map_err(|wait| wait.as_secs_f64().ceil() as u64)
+}
+
+static CONNECTIONS: LazyLock<Mutex<HashMap<IpAddr, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// One of a client's open connections, which stops counting once dropped.
+pub struct Connection {
+    ip: IpAddr,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Counts a new connection from `ip`, or returns `None` if it already has `max` open.
+/// Connections aren't limited if `max` is 0.
+pub fn open_connection(ip: IpAddr, max: usize) -> Option<Connection> {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let count = connections.entry(ip).or_insert(0);
+    if max > 0 && *count >= max {
+        return None;
+    }
+
+    *count += 1;
+    Some(Connection { ip })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = |s: &str| s.parse::<RateLimit>();
+
+        assert_eq!(
+            limit("10/s"),
+            Ok(RateLimit {
+                rate: 10.0,
+                burst: 10.0
+            })
+        );
+        assert_eq!(
+            limit("60/m 5"),
+            Ok(RateLimit {
+                rate: 1.0,
+                burst: 5.0
+            })
+        );
+        assert!(limit("0.5/h").is_err());
+        assert!(limit("0.5/h 1").is_ok());
+        assert!(limit("10").is_err());
+        assert!(limit("10/d").is_err());
+        assert!(limit("0/s").is_err());
+        assert!(limit("10/s 0").is_err());
+        assert!(limit("10/s 20 30").is_err());
+    }
+
+    #[test]
+    fn test_bucket() {
+        let limit = RateLimit {
+            rate: 2.0,
+            burst: 3.0,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(limit, start);
+
+        // A burst, then waiting for the bucket to refill
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Ok(()));
+        }
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(later), Err(Duration::from_millis(250)));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(later), Ok(()));
+
+        // Never more than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), Ok(()));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let ip = "192.0.2.26".parse().unwrap();
+        let limit = RateLimit {
+            rate: 1.0 / 3600.0,
+            burst: 1.0,
+        };
+
+        assert_eq!(check_rate(ip, "", "", limit), Ok(()));
+        assert!(check_rate(ip, "", "", limit).is_err());
+
+        // Each server and path counts on its own
+        assert_eq!(check_rate(ip, "a.test", "", limit), Ok(()));
+        assert_eq!(check_rate(ip, "b.test", "", limit), Ok(()));
+        assert_eq!(check_rate(ip, "a.test", "/login", limit), Ok(()));
+        assert!(check_rate(ip, "a.test", "", limit).is_err());
+    }
+
+    #[test]
+    fn test_open_connection() {
+        let ip = "192.0.2.25".parse().unwrap();
+
+        let first = open_connection(ip, 2);
+        let second = open_connection(ip, 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(open_connection(ip, 2).is_none());
+
+        drop(first);
+        assert!(open_connection(ip, 2).is_some());
+        assert!(open_connection(ip, 0).is_some());
+    }
+}
@@ -0,0 +1,398 @@
+//! This writes the access log (one line per request) and the error log
+//!
+//! Both go to files set in the config, which are opened again on SIGUSR1 so
+//! they can be rotated (e.g. by logrotate), and whenever the config is reloaded.
+//! Without an error log file, messages go to stderr.
+
+use crate::config::Config;
+use crate::http::{HttpHeader, HttpRequestLine};
+use chrono::{DateTime, Local};
+use std::fmt::{Arguments, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+/// How bad a message in the error log is, from worst to most mundane.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Format of the access log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Common Log Format.
+    Common,
+
+    /// Common Log Format followed by referer and user agent.
+    Combined,
+
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A log file, remembering its path so it can be opened again.
+struct LogFile {
+    path: String,
+    file: File,
+}
+
+impl LogFile {
+    fn open(path: &str) -> Result<Self, ()> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(Self {
+                path: path.to_string(),
+                file,
+            }),
+            Err(e) => {
+                eprintln!("Error opening log file {path}: {e}");
+                Err(())
+            }
+        }
+    }
+}
+
+struct Logs {
+    access: Option<LogFile>,
+    format: Format,
+    error: Option<LogFile>,
+    level: Level,
+}
+
+static LOGS: LazyLock<Mutex<Logs>> = LazyLock::new(|| {
+    Mutex::new(Logs {
+        access: None,
+        format: Format::Combined,
+        error: None,
+        level: Level::Info,
+    })
+});
+
+/// Opens the log files set in `config`, keeping the current ones if any can't be opened.
+pub fn init(config: &Config) -> Result<(), ()> {
+    let open = |path: &str| match path {
+        "" => Ok(None),
+        path => LogFile::open(path).map(Some),
+    };
+    let access = open(&config.access_log)?;
+    let error = open(&config.error_log)?;
+
+    let mut logs = LOGS.lock().unwrap();
+    *logs = Logs {
+        access,
+        format: config.access_log_format,
+        error,
+        level: config.log_level,
+    };
+    Ok(())
+}
+
+/// Opens the log files again, after they have been moved away for rotation.
+pub fn reopen() {
+    let mut logs = LOGS.lock().unwrap();
+    let Logs { access, error, .. } = &mut *logs;
+    for log in [access, error].into_iter().flatten() {
+        if let Ok(reopened) = LogFile::open(&log.path) {
+            *log = reopened;
+        }
+    }
+}
+
+/// Writes a message to the error log, if it is at or above the configured level.
+/// Use the `error!`, `warning!`, `info!` and `debug!` macros instead.
+pub fn write(level: Level, args: Arguments) {
+    let mut logs = LOGS.lock().unwrap();
+    if level > logs.level {
+        return;
+    }
+
+    let time = Local::now().format("%Y/%m/%d %H:%M:%S");
+    let line = format!("{time} [{}] {args}\n", level.name());
+    match &mut logs.error {
+        Some(log) => {
+            let _ = log.file.write_all(line.as_bytes());
+        }
+        None => eprint!("{line}"),
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
+
+/// What the access log says about a request.
+pub struct Access<'a> {
+    pub addr: IpAddr,
+
+    /// None if the request was answered before we could make sense of it.
+    pub header: Option<&'a HttpHeader>,
+    pub status: u16,
+
+    /// Bytes of the body we sent.
+    pub bytes: u64,
+
+    /// When the request came in, and how long it took to answer.
+    pub time: DateTime<Local>,
+    pub duration: Duration,
+}
+
+/// Escapes a value for the quoted parts of Common Log Format, like Apache does.
+fn escape_clf(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_ascii_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u8);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns `value` as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn format_access(format: Format, access: &Access) -> String {
+    let request_line = access.header.map(HttpHeader::request_line);
+    let field = |name| {
+        access
+            .header
+            .and_then(|header| header.field_lines.get(name))
+            .map(String::as_str)
+    };
+
+    if format == Format::Json {
+        let optional = |value: Option<&str>| value.map_or(String::from("null"), json_string);
+        let part = |part: fn(&HttpRequestLine) -> String| {
+            request_line.map_or(String::from("null"), |line| json_string(&part(line)))
+        };
+        return format!(
+            "{{\"time\":{},\"remote_addr\":{},\"host\":{},\"method\":{},\"target\":{},\
+             \"protocol\":{},\"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\
+             \"duration_ms\":{:.3}}}",
+            json_string(&access.time.to_rfc3339()),
+            json_string(&access.addr.to_string()),
+            optional(field("host")),
+            part(|line| line.method.to_string()),
+            part(|line| line.target.clone()),
+            part(|line| line.http_version.to_string()),
+            access.status,
+            access.bytes,
+            optional(field("referer")),
+            optional(field("user-agent")),
+            access.duration.as_secs_f64() * 1000.0,
+        );
+    }
+
+    let bytes = match access.bytes {
+        0 => String::from("-"),
+        bytes => bytes.to_string(),
+    };
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {bytes}",
+        access.addr,
+        access.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        request_line.map_or(String::from("-"), |line| escape_clf(&line.to_string())),
+        access.status,
+    );
+    if format == Format::Combined {
+        let quoted = |value: Option<&str>| escape_clf(value.unwrap_or("-"));
+        let _ = write!(
+            line,
+            " \"{}\" \"{}\"",
+            quoted(field("referer")),
+            quoted(field("user-agent"))
+        );
+    }
+    line
+}
+
+/// Writes a request to the access log, if there is one.
+pub fn access(access: &Access) {
+    let mut logs = LOGS.lock().unwrap();
+    let format = logs.format;
+    if let Some(log) = &mut logs.access {
+        let line = format_access(format, access) + "\n";
+        if let Err(e) = log.file.write_all(line.as_bytes()) {
+            drop(logs);
+            error!("Error writing to access log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_format_access() {
+        let header = "GET /index.html?q=\"hi\" HTTP/1.1\r\n\
+                      Host: example.com\r\n\
+                      User-Agent: curl/8.0\r\n\r\n"
+            .parse()
+            .unwrap();
+        let access = Access {
+            addr: "192.0.2.7".parse().unwrap(),
+            header: Some(&header),
+            status: 200,
+            bytes: 1337,
+            time: Local.with_ymd_and_hms(2026, 10, 17, 13, 37, 0).unwrap(),
+            duration: Duration::from_micros(4200),
+        };
+        let time = access.time.format("%d/%b/%Y:%H:%M:%S %z");
+
+        assert_eq!(
+            format_access(Format::Common, &access),
+            format!("192.0.2.7 - - [{time}] \"GET /index.html?q=\\\"hi\\\" HTTP/1.1\" 200 1337")
+        );
+        assert_eq!(
+            format_access(Format::Combined, &access),
+            format!(
+                "192.0.2.7 - - [{time}] \"GET /index.html?q=\\\"hi\\\" HTTP/1.1\" 200 1337 \
+                 \"-\" \"curl/8.0\""
+            )
+        );
+        assert_eq!(
+            format_access(Format::Json, &access),
+            format!(
+                "{{\"time\":\"{}\",\"remote_addr\":\"192.0.2.7\",\"host\":\"example.com\",\
+                 \"method\":\"GET\",\"target\":\"/index.html?q=\\\"hi\\\"\",\"protocol\":\"HTTP/1.1\",\
+                 \"status\":200,\"bytes\":1337,\"referer\":null,\"user_agent\":\"curl/8.0\",\
+                 \"duration_ms\":4.200}}",
+                access.time.to_rfc3339()
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_rejected() {
+        // Requests turned away before (or for) not making sense
+        let header = "PUT /private HTTP/1.1\r\nHost: example.com\r\n\r\n"
+            .parse()
+            .unwrap();
+        let mut access = Access {
+            addr: "192.0.2.7".parse().unwrap(),
+            header: Some(&header),
+            status: 403,
+            bytes: 162,
+            time: Local.with_ymd_and_hms(2026, 10, 17, 13, 37, 0).unwrap(),
+            duration: Duration::ZERO,
+        };
+        let time = access.time.format("%d/%b/%Y:%H:%M:%S %z");
+        assert_eq!(
+            format_access(Format::Common, &access),
+            format!("192.0.2.7 - - [{time}] \"PUT /private HTTP/1.1\" 403 162")
+        );
+
+        access.header = None;
+        access.status = 400;
+        assert_eq!(
+            format_access(Format::Combined, &access),
+            format!("192.0.2.7 - - [{time}] \"-\" 400 162 \"-\" \"-\"")
+        );
+        assert_eq!(
+            format_access(Format::Json, &access),
+            format!(
+                "{{\"time\":\"{}\",\"remote_addr\":\"192.0.2.7\",\"host\":null,\
+                 \"method\":null,\"target\":null,\"protocol\":null,\"status\":400,\
+                 \"bytes\":162,\"referer\":null,\"user_agent\":null,\"duration_ms\":0.000}}",
+                access.time.to_rfc3339()
+            )
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_clf("a\"b\\c\x1b"), "a\\\"b\\\\c\\x1b");
+        assert_eq!(json_string("a\"b\\c\n\x1b"), "\"a\\\"b\\\\c\\n\\u001b\"");
+    }
+}
@@ -1,30 +1,55 @@
+mod acl;
+mod auth;
+mod autoindex;
 mod cgi;
+mod chunked;
+mod compression;
+mod conditional;
 mod config;
 mod connection;
+mod fastcgi;
+mod hpack;
 mod http;
+mod http2;
+mod limit;
+mod log;
+mod mime;
+mod proxy;
+mod range;
 mod response;
+mod tls;
+mod upstream;
+mod webdav;
+mod x509;
 
 use config::Config;
 use connection::handle_connections;
-use std::path::Path;
+use log::error;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 
 #[tokio::main]
 async fn main() {
-    let config = std::env::args()
-        .nth(1)
-        .map_or_else(Config::default, |path| {
-            Config::from_file(Path::new(&path)).unwrap_or_else(|_| {
-                eprintln!("Error: Could not retrieve configuration settings.");
-                std::process::exit(1);
-            })
-        });
+    let config_path = std::env::args().nth(1).map(PathBuf::from);
+    let config = config_path.as_deref().map_or_else(Config::default, |path| {
+        Config::from_file(path).unwrap_or_else(|_| {
+            error!("Error: Could not retrieve configuration settings.");
+            std::process::exit(1);
+        })
+    });
 
-    // We want config to have static lifetime so it can be shared among tokio tasks
-    let config = Box::leak(Box::new(config));
+    if log::init(&config).is_err() {
+        error!("Error: Could not open log files.");
+        std::process::exit(1);
+    }
+
+    // Config is shared among tokio tasks, and replaced whenever it is reloaded
+    let config = Arc::new(config);
     let conn_sem = Arc::new(Semaphore::new(config.max_connections));
 
+    upstream::spawn_health_checks(&config);
+
     // Will only return on unrecoverable error
-    handle_connections(config, conn_sem).await;
+    handle_connections(config_path, config, conn_sem).await;
 }
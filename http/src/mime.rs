@@ -0,0 +1,147 @@
+//! This maps file extensions to media types for the Content-Type header
+//!
+//! The built-in table only covers what you'd commonly find on a website,
+//! anything else can be added (or overridden) in the `mime_types` section
+//! of the config file.
+
+use crate::config::Config;
+use std::path::Path;
+
+/// Media type used when we have no idea what a file is.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+const MIME_TYPES: &[(&str, &str)] = &[
+    // Text
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("svg", "image/svg+xml"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    // Audio/video
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    // Other
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+];
+
+/// Returns true if the media type is textual, and hence needs a charset.
+fn is_text(mime_type: &str) -> bool {
+    mime_type.starts_with("text/")
+        || matches!(
+            mime_type,
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/manifest+json"
+                | "image/svg+xml"
+        )
+}
+
+/// Returns the value of the Content-Type header to send for the file at `path`.
+pub fn content_type(config: &Config, path: &Path) -> String {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+
+    let mime_type = config
+        .mime_types
+        .get(&ext)
+        .map(String::as_str)
+        .or_else(|| {
+            MIME_TYPES
+                .iter()
+                .find(|(e, _)| *e == ext)
+                .map(|(_, mime_type)| *mime_type)
+        })
+        .unwrap_or(DEFAULT_MIME_TYPE);
+
+    with_charset(config, mime_type)
+}
+
+/// Appends the configured charset to textual media types lacking one.
+pub fn with_charset(config: &Config, mime_type: &str) -> String {
+    if is_text(mime_type) && !mime_type.contains("charset=") {
+        format!("{mime_type}; charset={}", config.charset)
+    } else {
+        mime_type.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        let mut config = Config::default();
+        config
+            .mime_types
+            .insert(String::from("js"), String::from("application/javascript"));
+        config.mime_types.insert(
+            String::from("lol"),
+            String::from("text/x-lol; charset=ascii"),
+        );
+
+        // Built-in
+        assert_eq!(content_type(&config, Path::new("a/b.png")), "image/png");
+        assert_eq!(
+            content_type(&config, Path::new("index.HTML")),
+            "text/html; charset=utf-8"
+        );
+
+        // Overridden
+        assert_eq!(
+            content_type(&config, Path::new("app.js")),
+            "application/javascript; charset=utf-8"
+        );
+
+        // Charset already given
+        assert_eq!(
+            content_type(&config, Path::new("hack.lol")),
+            "text/x-lol; charset=ascii"
+        );
+
+        // Unknown
+        assert_eq!(
+            content_type(&config, Path::new("foo.bar")),
+            DEFAULT_MIME_TYPE
+        );
+        assert_eq!(
+            content_type(&config, Path::new("Makefile")),
+            DEFAULT_MIME_TYPE
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! This forwards requests to upstream HTTP servers (reverse proxying)
+//!
+//! Requests whose path falls under a prefix in the `proxy` section of the
+//! config are sent on to a server of the upstream configured for it (see
+//! the `upstream` module), with the target left as is. Each request gets its
+//! own upstream connection, and the upstream response is streamed back to
+//! the client as it arrives.
+
+use crate::chunked;
+use crate::config::{match_prefix, Config};
+use crate::connection::ConnectionInfo;
+use crate::http::*;
+use crate::log::error;
+use crate::response::create_response;
+use crate::upstream::{self, Tracked};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+/// Fields that only concern a single connection, which are never forwarded
+/// (RFC 9110, section 7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// How we identify ourselves in Via fields.
+const PSEUDONYM: &str = "helios";
+
+/// Returns the upstream to forward requests for `path` to, if any,
+/// preferring the longest matching prefix.
+pub fn find_upstream<'a>(config: &'a Config, path: &str) -> Option<&'a str> {
+    match_prefix(&config.proxy, path).map(|(_, upstream)| upstream.as_str())
+}
+
+/// Returns the fields of a message that may be forwarded, which excludes
+/// hop-by-hop fields and any others listed in Connection.
+fn end_to_end_fields(fields: &FieldLines) -> Vec<(String, String)> {
+    let listed: Vec<String> = fields
+        .get("connection")
+        .map(|v| v.split(',').map(|s| s.trim().to_lowercase()).collect())
+        .unwrap_or_default();
+
+    fields
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_lowercase();
+            !HOP_BY_HOP.contains(&name.as_str()) && !listed.contains(&name)
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Appends `value` to the comma separated list in field `name`.
+fn append_field(fields: &mut Vec<(String, String)>, name: &str, value: &str) {
+    match fields
+        .iter_mut()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        Some((_, existing)) => {
+            existing.push_str(", ");
+            existing.push_str(value);
+        }
+        None => fields.push((String::from(name), String::from(value))),
+    }
+}
+
+/// Protocol version as it appears in Via (e.g. "1.1").
+fn via(version: HttpVersion) -> String {
+    let version = version.to_string();
+    format!("{} {PSEUDONYM}", version.trim_start_matches("HTTP/"))
+}
+
+/// Creates the header of the request we send upstream.
+fn upstream_header(conn: &ConnectionInfo, request: &HttpMessage, upstream: &str) -> String {
+    let request_line = request.header.request_line();
+    let mut fields = end_to_end_fields(&request.header.field_lines);
+
+    // We already took care of any 100-continue, and have the whole body at hand
+    fields.retain(|(name, _)| !matches!(name.as_str(), "expect" | "content-length"));
+    if let Some(body) = &request.body {
+        fields.push((
+            String::from("content-length"),
+            body.len().unwrap_or(0).to_string(),
+        ));
+    }
+
+    // HTTP/1.0 clients might not send a Host
+    if !fields.iter().any(|(name, _)| name == "host") {
+        fields.push((String::from("host"), String::from(upstream)));
+    }
+    let host = fields
+        .iter()
+        .find(|(name, _)| name == "host")
+        .map(|(_, host)| host.clone())
+        .unwrap_or_default();
+
+    // Let the upstream know who it's really talking to
+    let ip = conn.addr.ip();
+    let proto = if conn.https { "https" } else { "http" };
+    let node = if ip.is_ipv6() {
+        format!("\"[{ip}]\"")
+    } else {
+        ip.to_string()
+    };
+    append_field(&mut fields, "X-Forwarded-For", &ip.to_string());
+    fields.retain(|(name, _)| name != "x-forwarded-proto");
+    fields.push((String::from("X-Forwarded-Proto"), String::from(proto)));
+    append_field(
+        &mut fields,
+        "Forwarded",
+        &format!("for={node};proto={proto};host=\"{host}\""),
+    );
+    append_field(&mut fields, "Via", &via(request_line.http_version));
+
+    // Upstream connections aren't reused, so the response ends when it closes
+    fields.push((String::from("Connection"), String::from("close")));
+
+    let mut header = format!(
+        "{} {} {}\r\n",
+        request_line.method,
+        request_line.target,
+        HttpVersion::HTTP11
+    );
+    for (name, value) in fields {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    header.push_str("\r\n");
+
+    header
+}
+
+/// Reads the header of the upstream's (final) response.
+async fn read_response_header(
+    config: &Config,
+    upstream: &mut BufReader<TcpStream>,
+) -> Result<HttpHeader, HttpStatusCode> {
+    loop {
+        let mut header = String::new();
+        while !header.ends_with("\r\n\r\n") {
+            let len = upstream
+                .read_line(&mut header)
+                .await
+                .map_err(|_| HttpStatusCode::BadGateway)?;
+            if len == 0 || header.len() > config.max_header_len {
+                return Err(HttpStatusCode::BadGateway);
+            }
+        }
+
+        // Skip any interim responses (such as 103 Early Hints)
+        if header
+            .split(' ')
+            .nth(1)
+            .is_some_and(|code| code.starts_with('1'))
+        {
+            continue;
+        }
+
+        let header: HttpHeader = header.parse().map_err(|_| HttpStatusCode::BadGateway)?;
+        return match header.start_line {
+            HttpStartLine::Response(_) => Ok(header),
+            HttpStartLine::Request(_) => Err(HttpStatusCode::BadGateway),
+        };
+    }
+}
+
+async fn connect(config: &Config, address: &str) -> Result<BufReader<TcpStream>, HttpStatusCode> {
+    let connect_timeout = Duration::from_secs(config.max_timeout);
+    match timeout(connect_timeout, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => Ok(BufReader::new(stream)),
+        Ok(Err(e)) => {
+            error!("Error connecting to upstream {address}: {e}");
+            Err(HttpStatusCode::BadGateway)
+        }
+        Err(_) => {
+            error!("Timeout connecting to upstream {address}");
+            Err(HttpStatusCode::GatewayTimeout)
+        }
+    }
+}
+
+/// Sends the request upstream and reads the header of its response.
+async fn forward(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &HttpMessage,
+    stream: &mut BufReader<TcpStream>,
+    address: &str,
+) -> Result<HttpHeader, HttpStatusCode> {
+    let header = upstream_header(conn, request, address);
+    let body = request.body.as_ref().and_then(HttpBody::bytes);
+    let sent = async {
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body.unwrap_or_default()).await?;
+        stream.flush().await
+    };
+    if let Err(e) = sent.await {
+        error!("Error writing to upstream {address}: {e}");
+        return Err(HttpStatusCode::BadGateway);
+    }
+
+    let response_timeout = Duration::from_secs(config.max_timeout);
+    match timeout(response_timeout, read_response_header(config, stream)).await {
+        Ok(Ok(header)) => Ok(header),
+        Ok(Err(status_code)) => {
+            error!("Invalid response from upstream {address}");
+            Err(status_code)
+        }
+        Err(_) => {
+            error!("Timeout waiting for upstream {address}");
+            Err(HttpStatusCode::GatewayTimeout)
+        }
+    }
+}
+
+/// Forwards the request to a server of `upstream` and returns its response,
+/// or the status code to respond with if no server could be reached
+/// (or the response didn't make sense).
+///
+/// Servers that can't be connected to are skipped for the next one in the pool,
+/// as they never got to see the request.
+pub async fn handle_proxy(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &HttpMessage,
+    upstream: &str,
+    send_body: bool,
+) -> Result<HttpMessage, HttpStatusCode> {
+    let pool = upstream::pool(config, upstream);
+    let mut tried = Vec::new();
+    let mut status_code = HttpStatusCode::BadGateway;
+
+    let (selected, mut stream) = loop {
+        let Some(selected) = pool.select(conn.addr.ip(), &tried) else {
+            error!("No upstream available in pool {upstream}");
+            return Err(status_code);
+        };
+
+        match connect(config, selected.address()).await {
+            Ok(stream) => break (selected, stream),
+            Err(e) => {
+                selected.report(false);
+                tried.push(String::from(selected.address()));
+                status_code = e;
+            }
+        }
+    };
+
+    let header = match forward(config, conn, request, &mut stream, selected.address()).await {
+        Ok(header) => header,
+        Err(status_code) => {
+            selected.report(false);
+            return Err(status_code);
+        }
+    };
+    selected.report(true);
+
+    let HttpStartLine::Response(status_line) = &header.start_line else {
+        return Err(HttpStatusCode::BadGateway);
+    };
+    let status_code = status_line.status_code;
+    let reason_phrase = status_line.reason_phrase.clone();
+
+    // Responses to HEAD, as well as 204 and 304 responses, never have content
+    let has_body = request.header.request_line().method != HttpMethod::Head
+        && !matches!(
+            status_code,
+            HttpStatusCode::NoContent | HttpStatusCode::NotModified
+        );
+
+    let fields = &header.field_lines;
+    let content_len = header
+        .content_length()
+        .map_err(|_| HttpStatusCode::BadGateway)?;
+    let chunked = fields
+        .get("transfer-encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+
+    let body = if !has_body {
+        HttpBody::Stream {
+            reader: Box::new(tokio::io::empty()),
+            len: content_len,
+        }
+    } else if chunked {
+        HttpBody::Stream {
+            reader: Box::new(Tracked::new(chunked::Decoder::new(stream), selected)),
+            len: None,
+        }
+    } else if let Some(len) = content_len {
+        HttpBody::Stream {
+            reader: Box::new(Tracked::new(stream.take(len), selected)),
+            len: Some(len),
+        }
+    } else {
+        // Body ends when the upstream closes the connection
+        HttpBody::Stream {
+            reader: Box::new(Tracked::new(stream, selected)),
+            len: None,
+        }
+    };
+
+    // We send our own framing, Server and Date
+    let mut field_lines = end_to_end_fields(fields);
+    field_lines.retain(|(name, _)| !matches!(name.as_str(), "content-length" | "server" | "date"));
+    append_field(&mut field_lines, "Via", &via(HttpVersion::HTTP11));
+
+    let field_lines: Vec<_> = field_lines
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let mut response = create_response(status_code, &field_lines, Some(body), send_body);
+    response.header.status_line_mut().reason_phrase = reason_phrase;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_find_upstream() {
+        let mut config = Config::default();
+        config
+            .proxy
+            .insert(String::from("/api"), String::from("127.0.0.1:8080"));
+        config
+            .proxy
+            .insert(String::from("/api/v2"), String::from("127.0.0.1:8081"));
+
+        assert_eq!(find_upstream(&config, "/api"), Some("127.0.0.1:8080"));
+        assert_eq!(find_upstream(&config, "/api/users"), Some("127.0.0.1:8080"));
+        assert_eq!(
+            find_upstream(&config, "/api/v2/users"),
+            Some("127.0.0.1:8081")
+        );
+
+        // Prefixes only match whole segments
+        assert_eq!(find_upstream(&config, "/apis"), None);
+        assert_eq!(find_upstream(&config, "/index.html"), None);
+
+        // Everything
+        config
+            .proxy
+            .insert(String::from("/"), String::from("127.0.0.1:8082"));
+        assert_eq!(
+            find_upstream(&config, "/index.html"),
+            Some("127.0.0.1:8082")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_proxy() {
+        // Stand-in upstream, which checks what we forwarded and answers in chunks
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut header = String::new();
+            while !header.ends_with("\r\n\r\n") {
+                stream.read_line(&mut header).await.unwrap();
+            }
+            let header: HttpHeader = header.parse().unwrap();
+            let fields = &header.field_lines;
+            assert_eq!(header.request_line().target, "/api/echo?x=1");
+            assert_eq!(fields["host"], "example.com");
+            assert_eq!(fields["x-forwarded-for"], "10.0.0.1, 192.0.2.7");
+            assert_eq!(fields["x-forwarded-proto"], "https");
+            assert_eq!(
+                fields["forwarded"],
+                "for=192.0.2.7;proto=https;host=\"example.com\""
+            );
+            assert_eq!(fields["via"], "1.1 helios");
+            assert_eq!(fields["connection"], "close");
+            assert!(!fields.contains_key("x-secret"));
+            assert!(!fields.contains_key("keep-alive"));
+
+            let mut body = vec![0; fields["content-length"].parse().unwrap()];
+            stream.read_exact(&mut body).await.unwrap();
+            assert_eq!(body, b"ping");
+
+            stream
+                .write_all(
+                    b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+                      HTTP/1.1 299 Created Mostly\r\nTransfer-Encoding: chunked\r\n\
+                      Server: upstream\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\r\n\
+                      4\r\npong\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let request = HttpMessage {
+            header: "POST /api/echo?x=1 HTTP/1.1\r\nHost: example.com\r\n\
+                     Connection: keep-alive, X-Secret\r\nKeep-Alive: timeout=5\r\n\
+                     X-Secret: hunter2\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n"
+                .parse()
+                .unwrap(),
+            body: Some(HttpBody::Full(b"ping".to_vec())),
+        };
+        let conn = ConnectionInfo {
+            addr: "192.0.2.7:4242".parse::<SocketAddr>().unwrap(),
+            https: true,
+            client_cert: None,
+        };
+
+        let response = handle_proxy(&Config::default(), &conn, &request, &upstream, true)
+            .await
+            .unwrap();
+        let HttpStartLine::Response(status_line) = &response.header.start_line else {
+            panic!("Not a response");
+        };
+        assert_eq!(status_line.status_code, HttpStatusCode::Other(299));
+        assert_eq!(status_line.to_string(), "HTTP/1.1 299 Created Mostly");
+
+        let fields = &response.header.field_lines;
+        assert_eq!(fields["server"], crate::response::SERVER_SOFTWARE);
+        assert_eq!(fields["via"], "1.1 helios");
+        assert_eq!(fields["transfer-encoding"], "chunked");
+        let cookies: Vec<_> = fields
+            .iter()
+            .filter(|(name, _)| *name == "set-cookie")
+            .collect();
+        assert_eq!(cookies.len(), 2);
+
+        let Some(HttpBody::Stream { mut reader, .. }) = response.body else {
+            panic!("Body not streamed");
+        };
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"pong");
+    }
+}
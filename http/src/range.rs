@@ -0,0 +1,230 @@
+//! This handles byte range requests (RFC 9110, section 14)
+//!
+//! Only the `bytes` range unit is supported. Requests for multiple
+//! ranges are answered with a `multipart/byteranges` body.
+
+use crate::http::{HttpBody, HttpMessage, HttpStatusCode};
+use crate::response::create_response;
+use std::io::{Cursor, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+/// More ranges than this in a single request and we just send the whole thing.
+const MAX_RANGES: usize = 16;
+
+/// Parses the value of a Range header for a representation `len` bytes long.
+///
+/// Returns `None` if the header should be ignored (unknown unit, invalid syntax,
+/// too many ranges), otherwise the satisfiable ranges sorted and with overlaps
+/// merged, which may be empty if none were satisfiable.
+pub fn parse_ranges(value: &str, len: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let is_num = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    let mut ranges = Vec::new();
+    let mut num_specs = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        num_specs += 1;
+        if num_specs > MAX_RANGES {
+            return None;
+        }
+
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first, last) {
+            // Suffix range (the last n bytes)
+            ("", suffix) if is_num(suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                len.saturating_sub(suffix)..len
+            }
+            (first, "") if is_num(first) => first.parse().ok()?..len,
+            (first, last) if is_num(first) && is_num(last) => {
+                let first: u64 = first.parse().ok()?;
+                let last: u64 = last.parse().ok()?;
+                if last < first {
+                    return None;
+                }
+                first..last.saturating_add(1).min(len)
+            }
+            _ => return None,
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if num_specs == 0 {
+        return None;
+    }
+
+    // Merge overlapping and adjacent ranges
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    Some(merged)
+}
+
+/// Opens the file at `path` and returns a reader over just the given range.
+async fn read_range(
+    path: &Path,
+    range: &Range<u64>,
+) -> std::io::Result<impl AsyncRead + Send + Sync + Unpin> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(file.take(range.end - range.start))
+}
+
+/// Creates a 206 response containing the given ranges of the file at `path`,
+/// which must be non-empty and within the file's length `len`.
+pub async fn create_range_response(
+    path: &Path,
+    len: u64,
+    ranges: &[Range<u64>],
+    content_type: &str,
+    field_lines: &[(&str, &str)],
+    send_body: bool,
+) -> std::io::Result<HttpMessage> {
+    let content_range =
+        |range: &Range<u64>| format!("bytes {}-{}/{len}", range.start, range.end - 1);
+
+    // Single part body
+    if let [range] = ranges {
+        let body = HttpBody::Stream {
+            reader: Box::new(read_range(path, range).await?),
+            len: Some(range.end - range.start),
+        };
+
+        let content_range = content_range(range);
+        let field_lines: Vec<_> = [
+            ("Content-Type", content_type),
+            ("Content-Range", &content_range),
+        ]
+        .into_iter()
+        .chain(field_lines.iter().copied())
+        .collect();
+
+        return Ok(create_response(
+            HttpStatusCode::PartialContent,
+            &field_lines,
+            Some(body),
+            send_body,
+        ));
+    }
+
+    // Multipart body, with each part preceded by its own little header
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    let boundary = format!("HELIOS{nanos:032x}");
+
+    let mut reader: Box<dyn AsyncRead + Send + Sync + Unpin> = Box::new(tokio::io::empty());
+    let mut body_len = 0;
+    for range in ranges {
+        let part_header = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range)
+        );
+        body_len += part_header.len() as u64 + (range.end - range.start);
+
+        let part = Cursor::new(part_header).chain(read_range(path, range).await?);
+        reader = Box::new(reader.chain(part));
+    }
+
+    let closing = format!("\r\n--{boundary}--\r\n");
+    body_len += closing.len() as u64;
+    reader = Box::new(reader.chain(Cursor::new(closing)));
+
+    let body = HttpBody::Stream {
+        reader,
+        len: Some(body_len),
+    };
+    let multipart_type = format!("multipart/byteranges; boundary={boundary}");
+    let field_lines: Vec<_> = [("Content-Type", multipart_type.as_str())]
+        .into_iter()
+        .chain(field_lines.iter().copied())
+        .collect();
+
+    Ok(create_response(
+        HttpStatusCode::PartialContent,
+        &field_lines,
+        Some(body),
+        send_body,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        parse_ranges(value, len).map(|ranges| ranges.iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        // Valid (single range)
+        assert_eq!(parse("bytes=0-499", 1000), Some(vec![(0, 500)]));
+
+        // Valid (open ended)
+        assert_eq!(parse("bytes=500-", 1000), Some(vec![(500, 1000)]));
+
+        // Valid (suffix)
+        assert_eq!(parse("bytes=-200", 1000), Some(vec![(800, 1000)]));
+
+        // Valid (suffix longer than representation)
+        assert_eq!(parse("bytes=-2000", 1000), Some(vec![(0, 1000)]));
+
+        // Valid (last position past end gets clamped)
+        assert_eq!(parse("bytes=900-5000", 1000), Some(vec![(900, 1000)]));
+
+        // Valid (multiple ranges, with whitespace, out of order)
+        assert_eq!(
+            parse("bytes=500-599, 0-99", 1000),
+            Some(vec![(0, 100), (500, 600)])
+        );
+
+        // Valid (overlapping and adjacent ranges get merged)
+        assert_eq!(
+            parse("bytes=0-99,50-149,150-199,-100", 1000),
+            Some(vec![(0, 200), (900, 1000)])
+        );
+
+        // Valid (unsatisfiable ranges are dropped)
+        assert_eq!(parse("bytes=0-9,5000-", 1000), Some(vec![(0, 10)]));
+
+        // Valid (nothing satisfiable)
+        assert_eq!(parse("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=0-", 0), Some(vec![]));
+
+        // Ignored (unknown unit)
+        assert_eq!(parse("lines=0-9", 1000), None);
+
+        // Ignored (last before first)
+        assert_eq!(parse("bytes=9-0", 1000), None);
+
+        // Ignored (malformed)
+        assert_eq!(parse("bytes=", 1000), None);
+        assert_eq!(parse("bytes=-", 1000), None);
+        assert_eq!(parse("bytes=a-b", 1000), None);
+        assert_eq!(parse("bytes=+1-2", 1000), None);
+        assert_eq!(parse("bytes 0-9", 1000), None);
+
+        // Ignored (too many ranges)
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse(&format!("bytes={many}"), 1000), None);
+    }
+}
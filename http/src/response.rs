@@ -1,81 +1,409 @@
-use crate::cgi::handle_php;
-use crate::config::Config;
+use crate::auth;
+use crate::autoindex;
+use crate::cgi;
+use crate::compression;
+use crate::conditional::{self, Validators};
+use crate::config::{ClientAuth, Config};
+use crate::connection::ConnectionInfo;
 use crate::http::*;
+use crate::limit;
+use crate::mime;
+use crate::proxy;
+use crate::range;
+use crate::webdav;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use tokio::{fs::File, io::AsyncReadExt};
 
-async fn handle_request(config: &Config, request: &HttpMessage, send_body: bool) -> HttpMessage {
+/// Sent as the Server field and to CGI scripts.
+pub const SERVER_SOFTWARE: &str = "Helios/13.37";
+
+/// Methods static files (and directory listings) can be requested with.
+const STATIC_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Methods some resource on the server might allow, depending on how it is handled.
+const SERVER_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH";
+
+/// Formats a timestamp as an HTTP-date (RFC 9110, section 5.6.7).
+pub fn http_date(time: impl Into<chrono::DateTime<chrono::Utc>>) -> String {
+    time.into().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+async fn handle_request(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &mut HttpMessage,
+    send_body: bool,
+) -> HttpMessage {
+    let request_line = request.header.request_line();
+    match (&request_line.method, request_line.target.as_str()) {
+        // We're no forward proxy, so there's nothing to connect to
+        (HttpMethod::Connect, _) => {
+            return create_error_response(config, HttpStatusCode::NotImplemented).await;
+        }
+
+        // Asks about the server as a whole, rather than any resource
+        (HttpMethod::Options, "*") => {
+            return create_response(
+                HttpStatusCode::NoContent,
+                &[("Allow", SERVER_METHODS)],
+                None,
+                false,
+            );
+        }
+        _ => (),
+    }
+
     // Check if the requested target is actually valid
     let Ok(target) = request.header.request_line().target.parse::<Target>() else {
         return create_error_response(config, HttpStatusCode::BadRequest).await;
     };
 
+    // And none may make requests faster than they're allowed to
+    if let Some((prefix, limit)) = config.rate_limit_for(&format!("/{}", target.path)) {
+        let server = config.server_names.first().map_or("", String::as_str);
+        if let Err(retry_after) = limit::check_rate(conn.addr.ip(), server, prefix, limit) {
+            let mut response = create_error_response(config, HttpStatusCode::TooManyRequests).await;
+            response
+                .header
+                .field_lines
+                .insert(String::from("Retry-After"), retry_after.to_string());
+            return response;
+        }
+    }
+
+    // Some paths are only for clients with a trusted certificate
+    let client_cert = conn.client_cert.as_ref();
+    let authorized = match config.client_auth_for(&format!("/{}", target.path)) {
+        ClientAuth::Off => true,
+        ClientAuth::Optional => client_cert.is_none_or(|cert| cert.verified),
+        ClientAuth::Required => client_cert.is_some_and(|cert| cert.verified),
+    };
+    if !authorized {
+        return create_error_response(config, HttpStatusCode::Forbidden).await;
+    }
+
+    // And some only for users who log in
+    let user = match auth::authenticate(config, conn, request, &format!("/{}", target.path)).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    // Requests for proxied paths are none of our business
+    if let Some(upstream) = proxy::find_upstream(config, &format!("/{}", target.path)) {
+        return match proxy::handle_proxy(config, conn, request, upstream, send_body).await {
+            Ok(response) => response,
+            Err(status_code) => create_error_response(config, status_code).await,
+        };
+    }
+
+    // WebDAV paths are only for clients we know, who may change what's there
+    let webdav = config.webdav_root(&format!("/{}", target.path)).is_some();
+    if webdav {
+        if !client_cert.is_some_and(|cert| cert.verified) && user.is_none() {
+            return create_error_response(config, HttpStatusCode::Forbidden).await;
+        }
+        if let Some(response) = webdav::handle_webdav(config, request).await {
+            return response;
+        }
+    }
+
     let mut path = PathBuf::from(format!("{}/public/{}", config.server_root, target.path));
 
-    // Open index if path points to a folder
+    // Open the first index file found if path points to a folder, or list its contents
     if path.is_dir() {
-        path.push("index.php");
+        // But make sure the client asked for the folder itself, or relative links would break
+        if !target.path.is_empty() && !target.path.ends_with('/') {
+            let location = directory_location(&target);
+            return create_response(
+                HttpStatusCode::MovedPermanently,
+                &[("Location", &location)],
+                None,
+                send_body,
+            );
+        }
+
+        let mut candidates = config.index.iter().map(|name| path.join(name));
+        match candidates.find(|index| index.is_file()) {
+            Some(index) => path = index,
+            None if config.autoindex => {
+                if let Some(response) = answer_static_method(config, request).await {
+                    return response;
+                }
+
+                let url_path = format!("/{}", target.path);
+                let listing = autoindex::create_listing(
+                    config,
+                    &path,
+                    &url_path,
+                    &target.query_str,
+                    send_body,
+                );
+                return match listing.await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        create_error_response(config, HttpStatusCode::InternalServorError).await
+                    }
+                };
+            }
+            None => return create_error_response(config, HttpStatusCode::NotFound).await,
+        }
+    }
+
+    // Handle CGI scripts (which might be followed by extra path info),
+    // though not uploaded ones
+    if let Some(script) = cgi::find_script(config, &path).filter(|_| !webdav) {
+        return match cgi::handle_cgi(
+            config,
+            conn,
+            request,
+            &target,
+            &script,
+            user.as_ref(),
+            send_body,
+        )
+        .await
+        {
+            Ok(msg) => msg,
+            Err(status_code) => create_error_response(config, status_code).await,
+        };
     }
 
     // Then check if it exists on the server
     if !path.exists() {
         return create_error_response(config, HttpStatusCode::NotFound).await;
     }
+    if let Some(response) = answer_static_method(config, request).await {
+        return response;
+    }
 
-    // Handle PHP files
-    if path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .map_or(false, |ext| ext == "php")
-    {
-        return if let Ok(msg) = handle_php(&path, &target.query_str, &request.body, send_body).await
-        {
-            msg
-        } else {
-            create_error_response(config, HttpStatusCode::InternalServorError).await
-        };
+    // Clients may get a precompressed copy of the file instead
+    let content_type = mime::content_type(config, &path);
+    let precompressed = if config.compression {
+        compression::precompressed(&path)
+    } else {
+        Vec::new()
+    };
+    let accept_encoding = request.header.field_lines.get("accept-encoding");
+    let encoding = compression::negotiate(accept_encoding.map(String::as_str), &precompressed);
+    if let Some(encoding) = encoding {
+        path = compression::sibling(&path, encoding);
     }
 
     // Try to open the requested file
-    let Ok(mut file) = File::open(path).await else {
+    let Ok(file) = File::open(&path).await else {
         return create_error_response(config, HttpStatusCode::InternalServorError).await;
     };
 
-    // And finally try to read it
-    let mut body = Vec::new();
-    if file.read_to_end(&mut body).await.is_err() {
+    // And finally stream it, rather than reading potentially huge files into memory
+    let Ok(metadata) = file.metadata().await else {
         return create_error_response(config, HttpStatusCode::InternalServorError).await;
+    };
+    let len = metadata.len();
+
+    let validators = Validators::new(&metadata);
+    let last_modified = validators.last_modified.map(http_date);
+    let mut field_lines = vec![("Accept-Ranges", "bytes"), ("ETag", &validators.etag)];
+    if let Some(last_modified) = &last_modified {
+        field_lines.push(("Last-Modified", last_modified));
+    }
+    if !precompressed.is_empty() {
+        field_lines.push(("Vary", "Accept-Encoding"));
+    }
+    if let Some(encoding) = encoding {
+        field_lines.push(("Content-Encoding", encoding.name()));
+    }
+
+    // Client might already have an up-to-date copy (or a mismatched one)
+    match conditional::evaluate_preconditions(request, &validators) {
+        Some(HttpStatusCode::NotModified) => {
+            return create_response(HttpStatusCode::NotModified, &field_lines, None, false);
+        }
+        Some(status_code) => return create_error_response(config, status_code).await,
+        None => (),
+    }
+
+    // Only GET requests may be answered with partial content
+    let ranges = request
+        .header
+        .field_lines
+        .get("range")
+        .filter(|_| request.header.request_line().method == HttpMethod::Get)
+        .filter(|_| conditional::if_range_matches(request, &validators))
+        .and_then(|value| range::parse_ranges(value, len));
+
+    match ranges {
+        Some(ranges) if ranges.is_empty() => {
+            let mut response =
+                create_error_response(config, HttpStatusCode::RangeNotSatisfiable).await;
+            response
+                .header
+                .field_lines
+                .insert(String::from("Content-Range"), format!("bytes */{len}"));
+            return response;
+        }
+        Some(ranges) => {
+            return match range::create_range_response(
+                &path,
+                len,
+                &ranges,
+                &content_type,
+                &field_lines,
+                send_body,
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(_) => create_error_response(config, HttpStatusCode::InternalServorError).await,
+            };
+        }
+        None => (),
     }
 
-    create_response(HttpStatusCode::Ok, Some(body), send_body)
+    let body = HttpBody::Stream {
+        reader: Box::new(file),
+        len: Some(len),
+    };
+
+    field_lines.push(("Content-Type", &content_type));
+    create_response(HttpStatusCode::Ok, &field_lines, Some(body), send_body)
 }
 
-pub async fn process_request(config: &Config, request: &HttpMessage) -> HttpMessage {
+/// Answers requests for static content with methods other than GET and HEAD,
+/// which can't do anything but read it.
+async fn answer_static_method(config: &Config, request: &HttpMessage) -> Option<HttpMessage> {
     match request.header.request_line().method {
-        HttpMethod::Get | HttpMethod::Post => handle_request(config, request, true).await,
-        HttpMethod::Head => handle_request(config, request, false).await,
+        HttpMethod::Get | HttpMethod::Head => None,
+        HttpMethod::Options => Some(create_response(
+            HttpStatusCode::NoContent,
+            &[("Allow", STATIC_METHODS)],
+            None,
+            false,
+        )),
+        _ => {
+            let mut response =
+                create_error_response(config, HttpStatusCode::MethodNotAllowed).await;
+            response
+                .header
+                .field_lines
+                .insert(String::from("Allow"), String::from(STATIC_METHODS));
+            Some(response)
+        }
     }
 }
 
+/// Returns true if the client at `ip` may make a request with `header`, as far as the
+/// address rules of the server and path it is for go. Checked before any body is read.
+pub fn permits_request(config: &Config, header: &HttpHeader, ip: IpAddr) -> bool {
+    let config = config.virtual_host(header.field_lines.get("host").map(String::as_str));
+
+    match header.request_line().target.parse::<Target>() {
+        Ok(target) => config.acl_permits(&format!("/{}", target.path), ip),
+
+        // Answered with a 400 later on
+        Err(_) => true,
+    }
+}
+
+/// Answers a request, taking its body if it needs to be read as it arrives.
+pub async fn process_request(
+    config: &Config,
+    conn: &ConnectionInfo,
+    request: &mut HttpMessage,
+) -> HttpMessage {
+    let config = config.virtual_host(request.header.field_lines.get("host").map(String::as_str));
+    let send_body = request.header.request_line().method != HttpMethod::Head;
+
+    // Plain HTTP may only be good for being sent over to HTTPS
+    if let (false, true, Some(status_code)) =
+        (conn.https, config.https_enabled, config.https_redirect)
+    {
+        let location = https_location(config, request);
+        return create_response(status_code, &[("Location", &location)], None, send_body);
+    }
+
+    let mut response = handle_request(config, conn, request, send_body).await;
+    if config.compression {
+        compression::compress_response(config, request, &mut response);
+    }
+
+    if conn.https && config.hsts_max_age > 0 {
+        let mut hsts = format!("max-age={}", config.hsts_max_age);
+        if config.hsts_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        response
+            .header
+            .field_lines
+            .insert(String::from("Strict-Transport-Security"), hsts);
+    }
+
+    response
+}
+
+/// Returns the HTTPS URL of the resource a plain HTTP request is for.
+fn https_location(config: &Config, request: &HttpMessage) -> String {
+    let host = request
+        .header
+        .field_lines
+        .get("host")
+        .map_or(config.ip.as_str(), |host| host_without_port(host));
+    let port = match config.port_https {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+
+    // Targets are normally just the path and query, but may also be a whole URL
+    let target = &request.header.request_line().target;
+    let target = match target.strip_prefix("http://") {
+        Some(url) => url.find('/').map_or("/", |i| &url[i..]),
+        None if target.starts_with('/') => target,
+        None => "/",
+    };
+
+    format!("https://{host}{port}{target}")
+}
+
+/// Returns where a request for a folder without its trailing slash is redirected to.
+fn directory_location(target: &Target) -> String {
+    let mut location = format!("/{}/", encode_path(&target.path));
+    if !target.query_str.is_empty() {
+        location.push('?');
+        location.push_str(&target.query_str);
+    }
+    location
+}
+
 pub async fn create_error_response(config: &Config, status_code: HttpStatusCode) -> HttpMessage {
     let path = match status_code {
         HttpStatusCode::BadRequest => "400.html",
+        HttpStatusCode::Unauthorized => "401.html",
+        HttpStatusCode::Forbidden => "403.html",
         HttpStatusCode::NotFound => "404.html",
+        HttpStatusCode::MethodNotAllowed => "405.html",
         HttpStatusCode::RequestTimeout => "408.html",
+        HttpStatusCode::PreconditionFailed => "412.html",
         HttpStatusCode::ContentTooLarge => "413.html",
+        HttpStatusCode::RangeNotSatisfiable => "416.html",
+        HttpStatusCode::TooManyRequests => "429.html",
         HttpStatusCode::NotImplemented => "501.html",
+        HttpStatusCode::BadGateway => "502.html",
         HttpStatusCode::ServiceUnavailable => "503.html",
+        HttpStatusCode::GatewayTimeout => "504.html",
         HttpStatusCode::HTTPVersionNotSupported => "505.html",
         _ => "500.html", // Internal servor error
     };
     let path = PathBuf::from(format!("{}/errors/{}", config.server_root, path));
 
     // Try to open and read the error file
-    let default_err = b"Unknown error occurred.".to_vec();
-    let body = if let Ok(mut file) = File::open(path).await {
+    let default_err = (
+        mime::with_charset(config, "text/plain"),
+        b"Unknown error occurred.".to_vec(),
+    );
+    let (content_type, body) = if let Ok(mut file) = File::open(&path).await {
         let mut body = Vec::new();
         if file.read_to_end(&mut body).await.is_ok() {
-            body
+            (mime::content_type(config, &path), body)
         } else {
             default_err
         }
@@ -83,25 +411,47 @@ pub async fn create_error_response(config: &Config, status_code: HttpStatusCode)
         default_err
     };
 
-    create_response(status_code, Some(body), true)
+    create_response(
+        status_code,
+        &[("Content-Type", &content_type)],
+        Some(body.into()),
+        true,
+    )
 }
 
+/// Creates a response with our standard field lines, plus any given in `field_lines`.
 pub fn create_response(
     status_code: HttpStatusCode,
-    body: Option<Vec<u8>>,
+    field_lines: &[(&str, &str)],
+    body: Option<HttpBody>,
     send_body: bool,
 ) -> HttpMessage {
-    let body = body.unwrap_or_default();
-    let date = chrono::Utc::now()
-        .format("%a, %d %b %Y %H:%M:%S GMT")
-        .to_string();
-
-    let field_lines = [
-        ("Server", "Helios/13.37"),
-        ("Content-Length", &body.len().to_string()),
+    let body = body.unwrap_or(HttpBody::Full(Vec::new()));
+    let date = http_date(chrono::Utc::now());
+
+    // Bodies of unknown length (such as CGI output) are sent in chunks
+    let len = body.len().map(|len| len.to_string());
+    let framing = match &len {
+        Some(len) => ("Content-Length", len.as_str()),
+        None => ("Transfer-Encoding", "chunked"),
+    };
+
+    let mut field_lines: Vec<_> = [
+        ("Server", SERVER_SOFTWARE),
         ("Date", &date),
         ("Connection", "keep-alive"),
-    ];
+    ]
+    .into_iter()
+    .chain(field_lines.iter().copied())
+    .collect();
+
+    // 204 and 304 responses never have content, so they don't describe its length
+    if !matches!(
+        status_code,
+        HttpStatusCode::NoContent | HttpStatusCode::NotModified
+    ) {
+        field_lines.push(framing);
+    }
 
     HttpMessage::new_response(
         HttpVersion::HTTP11,
@@ -110,3 +460,152 @@ pub fn create_response(
         if send_body { Some(body) } else { None },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthScheme;
+
+    #[test]
+    fn test_https_location() {
+        let request = |target: &str, host: Option<&str>| {
+            let host = host.map_or(String::new(), |host| format!("Host: {host}\r\n"));
+            let header = format!("GET {target} HTTP/1.1\r\n{host}\r\n");
+            HttpMessage {
+                header: header.parse().unwrap(),
+                body: None,
+            }
+        };
+        let mut config = Config {
+            port_https: 443,
+            ..Config::default()
+        };
+
+        let location = |target, host| https_location(&config, &request(target, host));
+        assert_eq!(
+            location("/a/b?c=d", Some("example.com:80")),
+            "https://example.com/a/b?c=d"
+        );
+        assert_eq!(location("/", Some("[::1]:8080")), "https://[::1]/");
+        assert_eq!(location("/", None), "https://127.0.0.1/");
+        assert_eq!(
+            location("http://example.com/x?y", Some("example.com")),
+            "https://example.com/x?y"
+        );
+        assert_eq!(location("*", Some("example.com")), "https://example.com/");
+
+        config.port_https = 8443;
+        let location = https_location(&config, &request("/a", Some("example.com")));
+        assert_eq!(location, "https://example.com:8443/a");
+    }
+
+    #[test]
+    fn test_directory_location() {
+        let location = |target: &str| directory_location(&target.parse().unwrap());
+        assert_eq!(location("/docs"), "/docs/");
+        assert_eq!(location("/a/my%20docs?x=1&y"), "/a/my%20docs/?x=1&y");
+        assert_eq!(location("/%3Cb%3E"), "/%3Cb%3E/");
+    }
+
+    #[tokio::test]
+    async fn test_protected_paths() {
+        // Server root with a file only users may see
+        let root = std::env::temp_dir().join(format!("helios-protected-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("public/private")).unwrap();
+        std::fs::create_dir_all(root.join("public/x")).unwrap();
+        std::fs::write(root.join("public/private/secret"), "s").unwrap();
+        std::fs::write(root.join("users"), "").unwrap();
+        let mut config = Config {
+            server_root: root.to_string_lossy().into_owned(),
+            auth_file: root.join("users").to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        config
+            .auth
+            .insert(String::from("/private"), AuthScheme::Digest);
+        let conn = ConnectionInfo {
+            addr: "192.0.2.7:4242".parse().unwrap(),
+            https: true,
+            client_cert: None,
+        };
+
+        // Encoded slashes mustn't make the path look like another
+        let mut answers = Vec::new();
+        for target in [
+            "/private/secret",
+            "/.%2fprivate/secret",
+            "/x%2f..%2fprivate/secret",
+            "//private/secret",
+        ] {
+            let mut request = HttpMessage {
+                header: format!("GET {target} HTTP/1.1\r\nHost: x\r\n\r\n")
+                    .parse()
+                    .unwrap(),
+                body: None,
+            };
+            let response = process_request(&config, &conn, &mut request).await;
+            answers.push(response.header.status_line().status_code);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            answers,
+            [
+                HttpStatusCode::Unauthorized,
+                HttpStatusCode::BadRequest,
+                HttpStatusCode::BadRequest,
+                HttpStatusCode::Unauthorized,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_static_methods() {
+        // Server root with a single static file
+        let root = std::env::temp_dir().join(format!("helios-static-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("public")).unwrap();
+        std::fs::write(root.join("public/a.txt"), "a").unwrap();
+        let config = Config {
+            server_root: root.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let conn = ConnectionInfo {
+            addr: "192.0.2.7:4242".parse().unwrap(),
+            https: true,
+            client_cert: None,
+        };
+
+        let mut answers = Vec::new();
+        for (method, target) in [
+            ("GET", "/a.txt"),
+            ("DELETE", "/a.txt"),
+            ("BREW", "/a.txt"),
+            ("OPTIONS", "/a.txt"),
+            ("DELETE", "/b.txt"),
+        ] {
+            let mut request = HttpMessage {
+                header: format!("{method} {target} HTTP/1.1\r\nHost: x\r\n\r\n")
+                    .parse()
+                    .unwrap(),
+                body: None,
+            };
+            let response = process_request(&config, &conn, &mut request).await;
+            let status_code = response.header.status_line().status_code;
+            let allow = response.header.field_lines.get("allow").cloned();
+            answers.push((status_code, allow));
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let allow = Some(String::from(STATIC_METHODS));
+        assert_eq!(
+            answers,
+            [
+                (HttpStatusCode::Ok, None),
+                (HttpStatusCode::MethodNotAllowed, allow.clone()),
+                (HttpStatusCode::MethodNotAllowed, allow.clone()),
+                (HttpStatusCode::NoContent, allow),
+                (HttpStatusCode::NotFound, None),
+            ]
+        );
+    }
+}
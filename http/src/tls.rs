@@ -0,0 +1,296 @@
+//! This picks the TLS certificate for each connection by the name the
+//! client asked for (SNI)
+//!
+//! Certificates are looked for in the `crypt` folder of each virtual host
+//! (for all of its exact and `*.` wildcard names), and in subfolders of
+//! `cert_dir` named after the host they are for. Certificates are expected
+//! as `public.pem` and `private.pem`, just like in the top-level `crypt`
+//! folder, whose certificate is used whenever no other one matches.
+//!
+//! If `client_ca` is set, clients are also asked for a certificate. Since
+//! whether one is needed depends on the host and path of each request, the
+//! handshake goes through either way, and the certificate is verified
+//! afterwards for `client_auth` to decide on.
+
+use crate::config::Config;
+use crate::log::error;
+use crate::x509::{self, CertInfo};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+/// Loads the certificate chain and private key in `dir`.
+fn load_cert(dir: &Path, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, ()> {
+    let certs = match CertificateDer::pem_file_iter(dir.join("public.pem")) {
+        Ok(certs) => certs.collect::<Result<Vec<_>, _>>(),
+        Err(e) => {
+            error!("Error opening certificate file in {}: {e}", dir.display());
+            return Err(());
+        }
+    };
+    let certs = match certs {
+        Ok(certs) => certs,
+        Err(e) => {
+            error!("Error collecting certificates in {}: {e}", dir.display());
+            return Err(());
+        }
+    };
+
+    let key = match PrivateKeyDer::from_pem_file(dir.join("private.pem")) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Error opening private key file in {}: {e}", dir.display());
+            return Err(());
+        }
+    };
+
+    match CertifiedKey::from_der(certs, key, provider) {
+        Ok(cert) => Ok(Arc::new(cert)),
+        Err(e) => {
+            error!("Error loading certificate in {}: {e}", dir.display());
+            Err(())
+        }
+    }
+}
+
+/// Looks up `name` in `certs`, falling back to a `*.` wildcard for its parent domain.
+fn lookup<'a, T>(certs: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    certs.get(&name).or_else(|| {
+        let (_, parent) = name.split_once('.')?;
+        certs.get(&format!("*.{parent}"))
+    })
+}
+
+#[derive(Debug)]
+pub struct CertResolver {
+    /// Certificates by host name, which may be a `*.` wildcard.
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(config: &Config, provider: &CryptoProvider) -> Result<Self, ()> {
+        let default_dir = Path::new(&config.server_root).join("crypt");
+        let default = load_cert(&default_dir, provider)?;
+        let mut certs = HashMap::new();
+
+        // Named folders in cert_dir
+        if !config.cert_dir.is_empty() {
+            let entries = match std::fs::read_dir(&config.cert_dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("Error opening certificate folder {}: {e}", config.cert_dir);
+                    return Err(());
+                }
+            };
+
+            for entry in entries.map_while(Result::ok) {
+                let path = entry.path();
+                if let (true, Some(name)) = (path.is_dir(), entry.file_name().to_str()) {
+                    certs.insert(name.to_lowercase(), load_cert(&path, provider)?);
+                }
+            }
+        }
+
+        // Virtual hosts with their own crypt folder (which take precedence)
+        for server in &config.servers {
+            let dir = Path::new(&server.server_root).join("crypt");
+            if dir == default_dir || !dir.join("public.pem").exists() {
+                continue;
+            }
+
+            let cert = load_cert(&dir, provider)?;
+            for name in &server.server_names {
+                // Certificates can't be for any top-level domain
+                if !name.ends_with('*') {
+                    certs.insert(name.clone(), Arc::clone(&cert));
+                }
+            }
+        }
+
+        Ok(Self { certs, default })
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let cert = client_hello
+            .server_name()
+            .and_then(|name| lookup(&self.certs, name))
+            .unwrap_or(&self.default);
+
+        Some(Arc::clone(cert))
+    }
+}
+
+/// Loads the CA bundle client certificates are verified against.
+fn load_client_verifier(
+    path: &str,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, ()> {
+    let certs = match CertificateDer::pem_file_iter(path) {
+        Ok(certs) => certs.collect::<Result<Vec<_>, _>>(),
+        Err(e) => {
+            error!("Error opening client CA file {path}: {e}");
+            return Err(());
+        }
+    };
+    let certs = match certs {
+        Ok(certs) => certs,
+        Err(e) => {
+            error!("Error collecting client CA certificates in {path}: {e}");
+            return Err(());
+        }
+    };
+
+    let mut roots = RootCertStore::empty();
+    let (_, ignored) = roots.add_parsable_certificates(certs);
+    if ignored > 0 || roots.is_empty() {
+        error!("Error loading client CA certificates in {path}");
+        return Err(());
+    }
+
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .allow_unauthenticated()
+        .build()
+        .map_err(|e| error!("Error creating client certificate verifier: {e}"))
+}
+
+/// Asks clients for a certificate, but leaves it to us to decide what happens
+/// if it doesn't check out.
+///
+/// The client still has to prove it holds the certificate's private key.
+#[derive(Debug)]
+struct DeferredClientVerifier(Arc<dyn ClientCertVerifier>);
+
+impl ClientCertVerifier for DeferredClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.0.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// A certificate presented by a client.
+pub struct ClientCert {
+    /// Whether it was issued by a CA in `client_ca` (and is still valid).
+    pub verified: bool,
+    pub info: CertInfo,
+}
+
+/// Everything needed to accept TLS connections.
+pub struct Tls {
+    pub acceptor: TlsAcceptor,
+
+    /// Verifies client certificates against `client_ca`, if set.
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+impl Tls {
+    pub fn new(config: &Config) -> Result<Self, ()> {
+        let builder = rustls::ServerConfig::builder();
+        let provider = Arc::clone(builder.crypto_provider());
+        let resolver = CertResolver::new(config, &provider)?;
+
+        let (builder, client_verifier) = if config.client_ca.is_empty() {
+            (builder.with_no_client_auth(), None)
+        } else {
+            let verifier = load_client_verifier(&config.client_ca, provider)?;
+            let deferred = Arc::new(DeferredClientVerifier(Arc::clone(&verifier)));
+            (builder.with_client_cert_verifier(deferred), Some(verifier))
+        };
+
+        let mut config_s = builder.with_cert_resolver(Arc::new(resolver));
+        config_s.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config_s)),
+            client_verifier,
+        })
+    }
+
+    /// Verifies the certificate chain a client presented during the handshake.
+    pub fn client_cert(&self, chain: Option<&[CertificateDer<'_>]>) -> Option<ClientCert> {
+        let verifier = self.client_verifier.as_ref()?;
+        let (end_entity, intermediates) = chain?.split_first()?;
+
+        let verified = verifier
+            .verify_client_cert(end_entity, intermediates, UnixTime::now())
+            .is_ok();
+        Some(ClientCert {
+            verified,
+            info: x509::parse(end_entity).unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let certs = HashMap::from([
+            (String::from("example.com"), "exact"),
+            (String::from("*.example.com"), "wildcard"),
+            (String::from("*.blog.example.com"), "blog"),
+        ]);
+
+        assert_eq!(lookup(&certs, "example.com"), Some(&"exact"));
+        assert_eq!(lookup(&certs, "Example.COM."), Some(&"exact"));
+        assert_eq!(lookup(&certs, "www.example.com"), Some(&"wildcard"));
+        assert_eq!(lookup(&certs, "me.blog.example.com"), Some(&"blog"));
+
+        // Wildcards only cover a single label
+        assert_eq!(lookup(&certs, "a.b.example.com"), None);
+        assert_eq!(lookup(&certs, "example.org"), None);
+        assert_eq!(lookup(&certs, "com"), None);
+    }
+}
@@ -0,0 +1,436 @@
+//! This balances proxied requests across pools of upstream servers
+//!
+//! Servers are marked down for a while after too many errors in a row
+//! (passive checks), and pools with a `health_check` path configured
+//! also have each server probed periodically (active checks). Servers
+//! only get requests while neither check considers them down.
+
+use crate::config::{Balance, Config, Upstream};
+use crate::log::{info, warning};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, Duration, Instant};
+
+struct Server {
+    address: String,
+
+    /// Requests currently being handled.
+    active: AtomicUsize,
+
+    /// Errors in a row.
+    fails: AtomicU32,
+
+    /// Set once `fails` reaches the pool's limit.
+    down_until: Mutex<Option<Instant>>,
+
+    /// Result of the last health check.
+    healthy: AtomicBool,
+}
+
+impl Server {
+    fn is_available(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+            && self
+                .down_until
+                .lock()
+                .unwrap()
+                .is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+pub struct Pool {
+    name: String,
+    servers: Vec<Server>,
+    balance: Balance,
+    max_fails: u32,
+    fail_timeout: Duration,
+
+    /// Where round robin continues from.
+    next: AtomicUsize,
+}
+
+/// A server picked to handle a request, which counts as active until dropped.
+pub struct Selected {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Selected {
+    pub fn address(&self) -> &str {
+        &self.pool.servers[self.index].address
+    }
+
+    /// Records the outcome of a request to the server.
+    pub fn report(&self, ok: bool) {
+        let server = &self.pool.servers[self.index];
+
+        if ok {
+            if server.fails.swap(0, Ordering::SeqCst) >= self.pool.max_fails {
+                info!(
+                    "Upstream {} in pool {} is back up",
+                    server.address, self.pool.name
+                );
+            }
+            *server.down_until.lock().unwrap() = None;
+            return;
+        }
+
+        let fails = server.fails.fetch_add(1, Ordering::SeqCst) + 1;
+        if fails >= self.pool.max_fails {
+            *server.down_until.lock().unwrap() = Some(Instant::now() + self.pool.fail_timeout);
+            if fails == self.pool.max_fails {
+                warning!(
+                    "Upstream {} in pool {} marked down after {fails} errors",
+                    server.address,
+                    self.pool.name
+                );
+            }
+        }
+    }
+}
+
+impl Drop for Selected {
+    fn drop(&mut self) {
+        self.pool.servers[self.index]
+            .active
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wraps a response body, keeping its server counted as active until the body is dropped.
+pub struct Tracked<R> {
+    reader: R,
+    _selected: Selected,
+}
+
+impl<R> Tracked<R> {
+    pub fn new(reader: R, selected: Selected) -> Self {
+        Self {
+            reader,
+            _selected: selected,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tracked<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl Pool {
+    fn new(name: &str, upstream: &Upstream) -> Self {
+        let servers = upstream
+            .servers
+            .iter()
+            .map(|address| Server {
+                address: address.clone(),
+                active: AtomicUsize::new(0),
+                fails: AtomicU32::new(0),
+                down_until: Mutex::new(None),
+                healthy: AtomicBool::new(true),
+            })
+            .collect();
+
+        Self {
+            name: String::from(name),
+            servers,
+            balance: upstream.balance,
+            max_fails: upstream.max_fails.max(1),
+            fail_timeout: Duration::from_secs(upstream.fail_timeout),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks an available server for a request from `client`,
+    /// skipping those whose addresses are in `tried`.
+    pub fn select(self: &Arc<Self>, client: IpAddr, tried: &[String]) -> Option<Selected> {
+        let available: Vec<usize> = (0..self.servers.len())
+            .filter(|&i| {
+                let server = &self.servers[i];
+                !tried.contains(&server.address) && server.is_available()
+            })
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+
+        let index = match self.balance {
+            Balance::RoundRobin => {
+                available[self.next.fetch_add(1, Ordering::SeqCst) % available.len()]
+            }
+            Balance::LeastConnections => *available
+                .iter()
+                .min_by_key(|&&i| self.servers[i].active.load(Ordering::SeqCst))?,
+
+            // Rendezvous hashing, so a server going down only moves its own clients
+            Balance::IpHash => *available.iter().max_by_key(|&&i| {
+                let mut hasher = DefaultHasher::new();
+                (client, &self.servers[i].address).hash(&mut hasher);
+                hasher.finish()
+            })?,
+        };
+
+        self.servers[index].active.fetch_add(1, Ordering::SeqCst);
+        Some(Selected {
+            pool: Arc::clone(self),
+            index,
+        })
+    }
+
+    /// Checks each server's health every `every`, by requesting `path`
+    /// and expecting a 2xx or 3xx response.
+    async fn check_health(self: Arc<Self>, path: String, every: Duration) {
+        let mut ticks = interval(every);
+        loop {
+            ticks.tick().await;
+
+            // Pool was replaced by a config reload
+            let current = POOLS.lock().unwrap().get(&self.name).cloned();
+            if !current.is_some_and(|pool| Arc::ptr_eq(&pool, &self)) {
+                return;
+            }
+
+            for server in &self.servers {
+                let healthy = timeout(every, probe(&server.address, &path))
+                    .await
+                    .is_ok_and(|healthy| healthy);
+
+                if server.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+                    if healthy {
+                        info!(
+                            "Upstream {} in pool {} passed health check",
+                            server.address, self.name
+                        );
+                    } else {
+                        warning!(
+                            "Upstream {} in pool {} failed health check",
+                            server.address,
+                            self.name
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns true if `address` answers a GET for `path` with a 2xx or 3xx status.
+async fn probe(address: &str, path: &str) -> bool {
+    let Ok(mut stream) = TcpStream::connect(address).await else {
+        return false;
+    };
+
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n");
+    if stream.write_all(request.as_bytes()).await.is_err() {
+        return false;
+    }
+
+    let mut status_line = String::new();
+    if BufReader::new(stream)
+        .read_line(&mut status_line)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    status_line
+        .split(' ')
+        .nth(1)
+        .is_some_and(|code| code.starts_with('2') || code.starts_with('3'))
+}
+
+/// Pools by name (or address, for upstreams that are just a single server).
+static POOLS: LazyLock<Mutex<HashMap<String, Arc<Pool>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the pool for `upstream`, which is either the name of a configured
+/// pool or the address of a single server.
+pub fn pool(config: &Config, upstream: &str) -> Arc<Pool> {
+    let mut pools = POOLS.lock().unwrap();
+    if let Some(pool) = pools.get(upstream) {
+        return Arc::clone(pool);
+    }
+
+    let pool = match config.upstreams.get(upstream) {
+        Some(pool) => Pool::new(upstream, pool),
+        None => {
+            let single = Upstream {
+                servers: vec![String::from(upstream)],
+                ..Upstream::default()
+            };
+            Pool::new(upstream, &single)
+        }
+    };
+
+    let pool = Arc::new(pool);
+    pools.insert(String::from(upstream), Arc::clone(&pool));
+    pool
+}
+
+/// Replaces all pools with those of a newly loaded config.
+pub fn reload(config: &Config) {
+    // Running health checks notice their pool is gone and stop
+    POOLS.lock().unwrap().clear();
+    spawn_health_checks(config);
+}
+
+/// Starts health checks for every pool that has them configured.
+pub fn spawn_health_checks(config: &Config) {
+    for (name, upstream) in &config.upstreams {
+        if upstream.health_check.is_empty() {
+            continue;
+        }
+
+        info!(
+            "Checking health of upstream pool {name} every {}s",
+            upstream.health_interval
+        );
+        let every = Duration::from_secs(upstream.health_interval.max(1));
+        tokio::spawn(pool(config, name).check_health(upstream.health_check.clone(), every));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pool(balance: Balance) -> Arc<Pool> {
+        let upstream = Upstream {
+            servers: vec![
+                String::from("10.0.0.1:80"),
+                String::from("10.0.0.2:80"),
+                String::from("10.0.0.3:80"),
+            ],
+            balance,
+            max_fails: 2,
+            ..Upstream::default()
+        };
+        Arc::new(Pool::new("test", &upstream))
+    }
+
+    fn client(n: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, n])
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = test_pool(Balance::RoundRobin);
+        let picked: Vec<_> = (0..4)
+            .map(|_| pool.select(client(1), &[]).unwrap().index)
+            .collect();
+        assert_eq!(picked, [0, 1, 2, 0]);
+
+        // Already tried servers are skipped
+        let tried = [String::from("10.0.0.1:80"), String::from("10.0.0.3:80")];
+        let selected = pool.select(client(1), &tried).unwrap();
+        assert_eq!(selected.address(), "10.0.0.2:80");
+
+        let tried = [
+            tried[0].clone(),
+            tried[1].clone(),
+            String::from("10.0.0.2:80"),
+        ];
+        assert!(pool.select(client(1), &tried).is_none());
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = test_pool(Balance::LeastConnections);
+        let first = pool.select(client(1), &[]).unwrap();
+        let second = pool.select(client(1), &[]).unwrap();
+        assert_ne!(first.index, second.index);
+
+        // Dropping a selection frees up its server again
+        let freed = first.index;
+        drop(first);
+        let third = pool.select(client(1), &[]).unwrap();
+        assert_eq!(third.index, freed);
+    }
+
+    #[test]
+    fn test_ip_hash() {
+        let pool = test_pool(Balance::IpHash);
+
+        // Clients stick to their server
+        let picks: Vec<_> = (0..16)
+            .map(|n| pool.select(client(n), &[]).unwrap().index)
+            .collect();
+        for n in 0..16 {
+            assert_eq!(
+                pool.select(client(n), &[]).unwrap().index,
+                picks[n as usize]
+            );
+        }
+
+        // Taking a server out only moves the clients it had
+        for n in 0..16 {
+            let tried = [String::from("10.0.0.2:80")];
+            let index = pool.select(client(n), &tried).unwrap().index;
+            if picks[n as usize] != 1 {
+                assert_eq!(index, picks[n as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_passive_checks() {
+        let pool = test_pool(Balance::RoundRobin);
+        let selected = pool.select(client(1), &[]).unwrap();
+        assert_eq!(selected.index, 0);
+
+        // Down once errors in a row reach max_fails
+        selected.report(false);
+        assert!(pool.servers[0].is_available());
+        selected.report(true);
+        selected.report(false);
+        assert!(pool.servers[0].is_available());
+        selected.report(false);
+        assert!(!pool.servers[0].is_available());
+
+        let picked: Vec<_> = (0..4)
+            .map(|_| pool.select(client(1), &[]).unwrap().index)
+            .collect();
+        assert!(!picked.contains(&0));
+
+        // Back up after a success
+        selected.report(true);
+        assert!(pool.servers[0].is_available());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            for response in ["HTTP/1.1 204 No Content\r\n\r\n", "HTTP/1.1 503 \r\n\r\n"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&mut stream);
+                while !request.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut request).await.unwrap();
+                }
+                assert!(request.starts_with("GET /health HTTP/1.1\r\n"));
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        assert!(probe(&address, "/health").await);
+        assert!(!probe(&address, "/health").await);
+
+        // Nobody listening anymore
+        assert!(!probe(&address, "/health").await);
+    }
+}
@@ -0,0 +1,1194 @@
+//! This manages files over WebDAV (RFC 4918) on paths set in the `webdav` section
+//!
+//! Files can be uploaded with PUT, deleted, copied and moved, collections (folders)
+//! created with MKCOL, and both described with PROPFIND. Uploads over HTTP/1.1 are
+//! written to disk as they arrive. Locks (class 2) are only kept in memory, so they
+//! are forgotten on restart. Dead properties aren't stored, so PROPPATCH always
+//! fails. GET and HEAD are left to the usual static file handling.
+
+use crate::autoindex::escape_html;
+use crate::conditional::Validators;
+use crate::config::Config;
+use crate::http::{
+    self, encode_path, host_without_port, HttpBody, HttpHeader, HttpMessage, HttpMethod,
+    HttpStatusCode, Target,
+};
+use crate::log::error;
+use crate::mime;
+use crate::proxy;
+use crate::response::{create_error_response, create_response, http_date};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Methods WebDAV paths can be requested with.
+const METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Seconds a lock lasts if the client doesn't ask for longer, and at most.
+const LOCK_TIMEOUT: u64 = 600;
+const MAX_LOCK_TIMEOUT: u64 = 3600;
+
+const MULTISTATUS_TYPE: &str = "application/xml; charset=utf-8";
+
+struct Lock {
+    token: String,
+
+    /// The locked resource, and the URL path it was locked by.
+    path: PathBuf,
+    root: String,
+
+    exclusive: bool,
+
+    /// Whether everything below a locked collection is locked too.
+    infinite: bool,
+
+    /// Whoever holds the lock, as XML sent by the client.
+    owner: String,
+
+    timeout: u64,
+    expires: Instant,
+}
+
+impl Lock {
+    /// Returns true if the lock applies to the resource at `path`.
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.infinite && path.starts_with(&self.path))
+    }
+
+    fn to_xml(&self) -> String {
+        let scope = if self.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        };
+        let depth = if self.infinite { "infinity" } else { "0" };
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:{scope}/></D:lockscope><D:depth>{depth}</D:depth>\
+             <D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            self.owner,
+            self.timeout,
+            self.token,
+            encode_path(&self.root),
+        )
+    }
+}
+
+static LOCKS: LazyLock<Mutex<Vec<Lock>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// Returns the locks still in effect, having forgotten the expired ones.
+fn locks() -> std::sync::MutexGuard<'static, Vec<Lock>> {
+    let mut locks = LOCKS.lock().unwrap();
+    let now = Instant::now();
+    locks.retain(|lock| lock.expires > now);
+    locks
+}
+
+/// Creates a new lock token, unique enough for a server that forgets them on restart.
+fn new_token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(nanos);
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let random = hasher.finish();
+
+    format!(
+        "opaquelocktoken:{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        nanos as u32,
+        (nanos >> 32) as u16,
+        (random >> 52) as u16 & 0xfff,
+        0x8000 | (random >> 36) as u16 & 0x3fff,
+        random & 0xffff_ffff_ffff,
+    )
+}
+
+/// Returns the lock tokens submitted in an If field (RFC 4918, section 10.4).
+///
+/// The conditions themselves aren't evaluated, having the token is all that matters.
+fn submitted_tokens(value: &str) -> Vec<&str> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|s| s.split_once('>'))
+        .map(|(token, _)| token)
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .collect()
+}
+
+/// An element of an XML request body, with its namespace resolved.
+#[derive(Debug, Default, PartialEq)]
+struct Element {
+    ns: String,
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    /// Returns true if this is the element `name` in the DAV: namespace.
+    fn is(&self, name: &str) -> bool {
+        self.ns == "DAV:" && self.name == name
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(name))
+    }
+
+    /// Writes the element out again, to echo it back to the client.
+    fn to_xml(&self) -> String {
+        let children: String = self.children.iter().map(Element::to_xml).collect();
+        format!(
+            "<{name} xmlns=\"{}\">{}{children}</{name}>",
+            escape_html(&self.ns),
+            escape_html(&self.text),
+            name = self.name,
+        )
+    }
+}
+
+fn unescape_xml(text: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        unescaped.push_str(&rest[..i]);
+        let end = i + rest[i..].find(';')?;
+        let c = match &rest[i + 1..end] {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            entity => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => entity.strip_prefix('#')?.parse().ok()?,
+                };
+                char::from_u32(code)?
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+/// Splits the inside of a start tag into its name and attributes.
+fn parse_tag(tag: &str) -> Option<(&str, Vec<(&str, String)>)> {
+    let tag = tag.trim();
+    let (name, mut rest) = tag.split_at(tag.find(char::is_whitespace).unwrap_or(tag.len()));
+
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Some((name, attributes));
+        }
+
+        let (attribute, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let (value, after) = value[1..].split_once(quote)?;
+        attributes.push((attribute.trim(), unescape_xml(value)?));
+        rest = after;
+    }
+}
+
+/// Parses just enough XML for WebDAV request bodies, returning the root element.
+/// Document type declarations are refused, so there are no entities to expand.
+fn parse_xml(xml: &str) -> Option<Element> {
+    // Open elements, each with the namespace prefixes in scope
+    let mut open: Vec<(Element, HashMap<String, String>)> = Vec::new();
+    let mut root = None;
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        match open.last_mut() {
+            Some((element, _)) => element.text.push_str(&unescape_xml(text)?),
+            None if !text.trim().is_empty() => return None,
+            None => (),
+        }
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("<?") {
+            rest = &after[after.find("?>")? + 2..];
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = &after[after.find("-->")? + 3..];
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>")?;
+            open.last_mut()?.0.text.push_str(&after[..end]);
+            rest = &after[end + 3..];
+            continue;
+        }
+        if rest.starts_with("<!") || root.is_some() {
+            return None;
+        }
+
+        // Attribute values may well contain a '>'
+        let mut quote = None;
+        let (end, _) = rest.char_indices().find(|&(_, c)| {
+            match (quote, c) {
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), c) if q == c => quote = None,
+                _ => (),
+            }
+            quote.is_none() && c == '>'
+        })?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('/') {
+            let (element, _) = open.pop()?;
+            match open.last_mut() {
+                Some((parent, _)) => parent.children.push(element),
+                None => root = Some(element),
+            }
+            continue;
+        }
+
+        let (tag, empty) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let (name, attributes) = parse_tag(tag)?;
+
+        let mut namespaces = open.last().map_or_else(HashMap::new, |(_, ns)| ns.clone());
+        for (attribute, value) in attributes {
+            if attribute == "xmlns" {
+                namespaces.insert(String::new(), value);
+            } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+                namespaces.insert(prefix.to_string(), value);
+            }
+        }
+
+        let (prefix, name) = name.split_once(':').unwrap_or(("", name));
+        let ns = match namespaces.get(prefix) {
+            Some(ns) => ns.clone(),
+            None if prefix.is_empty() => String::new(),
+            None => return None,
+        };
+        let element = Element {
+            ns,
+            name: name.to_string(),
+            ..Element::default()
+        };
+
+        if empty {
+            match open.last_mut() {
+                Some((parent, _)) => parent.children.push(element),
+                None => root = Some(element),
+            }
+        } else {
+            open.push((element, namespaces));
+        }
+    }
+
+    if !open.is_empty() || !rest.trim().is_empty() {
+        return None;
+    }
+    root
+}
+
+/// Returns the properties of a resource, as XML elements in the DAV: namespace.
+fn properties(config: &Config, path: &Path, metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut properties = Vec::new();
+    if let Some(name) = path.file_name() {
+        let name = escape_html(&name.to_string_lossy());
+        properties.push((
+            "displayname",
+            format!("<D:displayname>{name}</D:displayname>"),
+        ));
+    }
+
+    if let Ok(created) = metadata.created() {
+        let created = DateTime::<Utc>::from(created).to_rfc3339_opts(SecondsFormat::Secs, true);
+        properties.push((
+            "creationdate",
+            format!("<D:creationdate>{created}</D:creationdate>"),
+        ));
+    }
+
+    if let Ok(modified) = metadata.modified() {
+        properties.push((
+            "getlastmodified",
+            format!(
+                "<D:getlastmodified>{}</D:getlastmodified>",
+                http_date(modified)
+            ),
+        ));
+    }
+
+    if metadata.is_dir() {
+        properties.push((
+            "resourcetype",
+            String::from("<D:resourcetype><D:collection/></D:resourcetype>"),
+        ));
+    } else {
+        properties.push(("resourcetype", String::from("<D:resourcetype/>")));
+        properties.push((
+            "getcontentlength",
+            format!(
+                "<D:getcontentlength>{}</D:getcontentlength>",
+                metadata.len()
+            ),
+        ));
+        properties.push((
+            "getcontenttype",
+            format!(
+                "<D:getcontenttype>{}</D:getcontenttype>",
+                escape_html(&mime::content_type(config, path))
+            ),
+        ));
+        properties.push((
+            "getetag",
+            format!(
+                "<D:getetag>{}</D:getetag>",
+                escape_html(&Validators::new(metadata).etag)
+            ),
+        ));
+    }
+
+    let lockentry = |scope| {
+        format!(
+            "<D:lockentry><D:lockscope><D:{scope}/></D:lockscope>\
+             <D:locktype><D:write/></D:locktype></D:lockentry>"
+        )
+    };
+    properties.push((
+        "supportedlock",
+        format!(
+            "<D:supportedlock>{}{}</D:supportedlock>",
+            lockentry("exclusive"),
+            lockentry("shared")
+        ),
+    ));
+
+    let active: String = locks()
+        .iter()
+        .filter(|lock| lock.covers(path))
+        .map(Lock::to_xml)
+        .collect();
+    properties.push((
+        "lockdiscovery",
+        format!("<D:lockdiscovery>{active}</D:lockdiscovery>"),
+    ));
+
+    properties
+}
+
+/// Returns an empty element named like a property, for when only its name matters.
+fn property_name(ns: &str, name: &str) -> String {
+    if ns == "DAV:" {
+        format!("<D:{name}/>")
+    } else {
+        format!("<{name} xmlns=\"{}\"/>", escape_html(ns))
+    }
+}
+
+/// Returns a `response` element of a multistatus body, with a `propstat` for each status.
+fn multistatus_response(href: &str, propstats: &[(HttpStatusCode, String)]) -> String {
+    let mut response = format!("<D:response><D:href>{}</D:href>", escape_html(href));
+    for (status_code, properties) in propstats {
+        response.push_str(&format!(
+            "<D:propstat><D:prop>{properties}</D:prop>\
+             <D:status>HTTP/1.1 {} {status_code}</D:status></D:propstat>",
+            u16::from(*status_code)
+        ));
+    }
+    response.push_str("</D:response>");
+    response
+}
+
+fn multistatus(responses: &[String]) -> HttpMessage {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>\n",
+        responses.concat()
+    );
+    create_response(
+        HttpStatusCode::MultiStatus,
+        &[("Content-Type", MULTISTATUS_TYPE)],
+        Some(body.into_bytes().into()),
+        true,
+    )
+}
+
+/// Which properties a PROPFIND asks for.
+enum Find {
+    All,
+    Names,
+    Properties(Vec<(String, String)>),
+}
+
+/// Parses a Timeout field (RFC 4918, section 10.7) into seconds.
+fn parse_timeout(value: Option<&str>) -> u64 {
+    value
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .find_map(|timeout| match timeout.trim() {
+            "Infinite" => Some(MAX_LOCK_TIMEOUT),
+            timeout => timeout.strip_prefix("Second-")?.parse().ok(),
+        })
+        .unwrap_or(LOCK_TIMEOUT)
+        .min(MAX_LOCK_TIMEOUT)
+}
+
+/// Returns a status code for a failed file system operation.
+fn io_status(e: &std::io::Error) -> HttpStatusCode {
+    match e.kind() {
+        ErrorKind::NotFound => HttpStatusCode::NotFound,
+        ErrorKind::PermissionDenied => HttpStatusCode::Forbidden,
+        ErrorKind::StorageFull => HttpStatusCode::InsufficientStorage,
+
+        // Uploads failing for the client's sake, rather than ours
+        ErrorKind::FileTooLarge => HttpStatusCode::ContentTooLarge,
+        ErrorKind::TimedOut => HttpStatusCode::RequestTimeout,
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => HttpStatusCode::BadRequest,
+        _ => {
+            error!("Error in WebDAV request: {e}");
+            HttpStatusCode::InternalServorError
+        }
+    }
+}
+
+/// Copies a file, or a collection with everything in it unless `shallow`.
+async fn copy_tree(from: &Path, to: &Path, shallow: bool) -> std::io::Result<()> {
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        if !fs::metadata(&from).await?.is_dir() {
+            fs::copy(&from, &to).await?;
+            continue;
+        }
+
+        fs::create_dir(&to).await?;
+        if shallow {
+            continue;
+        }
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            pending.push((entry.path(), to.join(entry.file_name())));
+        }
+    }
+    Ok(())
+}
+
+async fn remove(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path).await?.is_dir() {
+        fs::remove_dir_all(path).await
+    } else {
+        fs::remove_file(path).await
+    }
+}
+
+/// Decodes the path of a request target or Destination (which may be a whole URL),
+/// like any other target but without the URL crate's normalization, so anything
+/// that could be resolved differently is refused outright.
+fn decode_path(target: &str) -> Result<String, HttpStatusCode> {
+    let path = match target.split_once("://") {
+        Some((_, url)) => url.find('/').map_or("/", |i| &url[i..]),
+        None => target,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    http::decode_path(path).map_err(|_| HttpStatusCode::BadRequest)
+}
+
+/// Returns where the resource at `url_path` is on disk, which must be within its share.
+fn fs_path(config: &Config, url_path: &str) -> Result<PathBuf, HttpStatusCode> {
+    let root = config
+        .webdav_root(url_path)
+        .ok_or(HttpStatusCode::Forbidden)?;
+    let public = PathBuf::from(format!("{}/public", config.server_root));
+    let root = public.join(root.trim_start_matches('/'));
+    let path = public.join(url_path.trim_start_matches('/'));
+
+    let escapes = path
+        .components()
+        .any(|component| matches!(component, Component::ParentDir | Component::CurDir));
+    if escapes || !path.starts_with(&root) {
+        return Err(HttpStatusCode::Forbidden);
+    }
+    Ok(path)
+}
+
+/// A request for a resource on a WebDAV path.
+struct Dav<'a> {
+    config: &'a Config,
+    request: &'a HttpMessage,
+
+    /// URL path of the resource (decoded), and where it is on disk.
+    url_path: String,
+    path: PathBuf,
+}
+
+impl Dav<'_> {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.request
+            .header
+            .field_lines
+            .get(name)
+            .map(|value| value.trim())
+    }
+
+    fn body(&self) -> &[u8] {
+        self.request
+            .body
+            .as_ref()
+            .and_then(HttpBody::bytes)
+            .unwrap_or_default()
+    }
+
+    /// Returns true if `url_path` is the root of its WebDAV share, which can't go anywhere.
+    fn is_root(&self, url_path: &str) -> bool {
+        self.config
+            .webdav_root(url_path)
+            .is_some_and(|root| root.trim_end_matches('/') == url_path.trim_end_matches('/'))
+    }
+
+    /// Makes sure the client holds the locks on `path` (and its parent, whose
+    /// members may change), and with `descendants` on anything below it.
+    fn check_locks(&self, path: &Path, descendants: bool) -> Result<(), HttpStatusCode> {
+        let tokens = self.field("if").map(submitted_tokens).unwrap_or_default();
+        let parent = path.parent();
+        let locked = locks().iter().any(|lock| {
+            let applies = lock.covers(path)
+                || parent.is_some_and(|parent| lock.path == parent)
+                || (descendants && lock.path.starts_with(path));
+            applies && !tokens.contains(&lock.token.as_str())
+        });
+
+        if locked {
+            Err(HttpStatusCode::Locked)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn put(&self, body: Option<HttpBody>) -> Result<HttpMessage, HttpStatusCode> {
+        if self.path.is_dir() {
+            return Err(HttpStatusCode::MethodNotAllowed);
+        }
+        let (Some(parent), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Err(HttpStatusCode::Conflict);
+        };
+        if !parent.is_dir() {
+            return Err(HttpStatusCode::Conflict);
+        }
+
+        // Partial uploads aren't supported, and mustn't be mistaken for whole ones
+        if self.field("content-range").is_some() {
+            return Err(HttpStatusCode::BadRequest);
+        }
+        self.check_locks(&self.path, false)?;
+        let existed = self.path.exists();
+
+        // Written next to the file first, so nobody sees it half-uploaded
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let temp = parent.join(format!(".{}.{nanos:x}.tmp", name.to_string_lossy()));
+        let result = async {
+            let mut file = fs::File::create(&temp).await?;
+            match body {
+                Some(HttpBody::Full(data)) => file.write_all(&data).await?,
+                Some(HttpBody::Stream { mut reader, len }) => {
+                    let written = tokio::io::copy(&mut reader, &mut file).await?;
+
+                    // Clients that hang up early mustn't leave a truncated file behind
+                    if len.is_some_and(|len| written != len) {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                None => (),
+            }
+            file.sync_all().await?;
+            fs::rename(&temp, &self.path).await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp).await;
+            return Err(io_status(&e));
+        }
+
+        let status_code = if existed {
+            HttpStatusCode::NoContent
+        } else {
+            HttpStatusCode::Created
+        };
+        Ok(create_response(status_code, &[], None, true))
+    }
+
+    async fn delete(&self) -> Result<HttpMessage, HttpStatusCode> {
+        if self.is_root(&self.url_path) {
+            return Err(HttpStatusCode::Forbidden);
+        }
+        if fs::symlink_metadata(&self.path).await.is_err() {
+            return Err(HttpStatusCode::NotFound);
+        }
+        self.check_locks(&self.path, true)?;
+
+        remove(&self.path).await.map_err(|e| io_status(&e))?;
+        locks().retain(|lock| !lock.path.starts_with(&self.path));
+        Ok(create_response(HttpStatusCode::NoContent, &[], None, true))
+    }
+
+    async fn mkcol(&self) -> Result<HttpMessage, HttpStatusCode> {
+        if !self.body().is_empty() {
+            return Err(HttpStatusCode::UnsupportedMediaType);
+        }
+        if fs::symlink_metadata(&self.path).await.is_ok() {
+            return Err(HttpStatusCode::MethodNotAllowed);
+        }
+        if !self.path.parent().is_some_and(Path::is_dir) {
+            return Err(HttpStatusCode::Conflict);
+        }
+        self.check_locks(&self.path, false)?;
+
+        fs::create_dir(&self.path)
+            .await
+            .map_err(|e| io_status(&e))?;
+        Ok(create_response(HttpStatusCode::Created, &[], None, true))
+    }
+
+    /// Returns the URL path of the Destination field, which must be on this server.
+    fn destination(&self) -> Result<String, HttpStatusCode> {
+        let destination = self
+            .field("destination")
+            .ok_or(HttpStatusCode::BadRequest)?;
+
+        let path = match destination.split_once("://") {
+            Some((_, url)) => {
+                let (authority, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+                let host = self.field("host").map(host_without_port);
+                if !host.is_some_and(|host| host.eq_ignore_ascii_case(host_without_port(authority)))
+                {
+                    return Err(HttpStatusCode::BadGateway);
+                }
+                path
+            }
+            None => destination,
+        };
+
+        decode_path(path)
+    }
+
+    async fn copy_or_move(&self, moving: bool) -> Result<HttpMessage, HttpStatusCode> {
+        let destination = self.destination()?;
+        let to = fs_path(self.config, &destination)?;
+
+        if fs::symlink_metadata(&self.path).await.is_err() {
+            return Err(HttpStatusCode::NotFound);
+        }
+        if to.starts_with(&self.path) || (moving && self.is_root(&self.url_path)) {
+            return Err(HttpStatusCode::Forbidden);
+        }
+        if !to.parent().is_some_and(Path::is_dir) {
+            return Err(HttpStatusCode::Conflict);
+        }
+
+        let overwrite = !self
+            .field("overwrite")
+            .is_some_and(|value| value.eq_ignore_ascii_case("F"));
+        let existed = fs::symlink_metadata(&to).await.is_ok();
+        if existed && !overwrite {
+            return Err(HttpStatusCode::PreconditionFailed);
+        }
+
+        // Copies of collections are either complete or of the collection alone
+        let shallow = match self.field("depth") {
+            None | Some("infinity") => false,
+            Some("0") if !moving => true,
+            Some(_) => return Err(HttpStatusCode::BadRequest),
+        };
+
+        if moving {
+            self.check_locks(&self.path, true)?;
+        }
+        self.check_locks(&to, true)?;
+
+        if existed {
+            remove(&to).await.map_err(|e| io_status(&e))?;
+            locks().retain(|lock| !lock.path.starts_with(&to));
+        }
+        if moving {
+            fs::rename(&self.path, &to)
+                .await
+                .map_err(|e| io_status(&e))?;
+            locks().retain(|lock| !lock.path.starts_with(&self.path));
+        } else {
+            copy_tree(&self.path, &to, shallow)
+                .await
+                .map_err(|e| io_status(&e))?;
+        }
+
+        let status_code = if existed {
+            HttpStatusCode::NoContent
+        } else {
+            HttpStatusCode::Created
+        };
+        Ok(create_response(status_code, &[], None, true))
+    }
+
+    async fn propfind(&self) -> Result<HttpMessage, HttpStatusCode> {
+        // Walking whole trees is a bit much to ask
+        let depth_one = match self.field("depth") {
+            Some("0") => false,
+            Some("1") => true,
+            _ => {
+                let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                            <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n";
+                return Ok(create_response(
+                    HttpStatusCode::Forbidden,
+                    &[("Content-Type", MULTISTATUS_TYPE)],
+                    Some(body.as_bytes().to_vec().into()),
+                    true,
+                ));
+            }
+        };
+
+        let find = if self.body().is_empty() {
+            Find::All
+        } else {
+            let body = std::str::from_utf8(self.body()).map_err(|_| HttpStatusCode::BadRequest)?;
+            let propfind = parse_xml(body)
+                .filter(|root| root.is("propfind"))
+                .ok_or(HttpStatusCode::BadRequest)?;
+
+            if let Some(prop) = propfind.child("prop") {
+                let names = prop.children.iter();
+                Find::Properties(names.map(|p| (p.ns.clone(), p.name.clone())).collect())
+            } else if propfind.child("propname").is_some() {
+                Find::Names
+            } else {
+                Find::All
+            }
+        };
+
+        let metadata = fs::metadata(&self.path).await.map_err(|e| io_status(&e))?;
+        let mut resources = vec![(self.url_path.clone(), self.path.clone(), metadata)];
+        if depth_one && resources[0].2.is_dir() {
+            let base = format!("{}/", self.url_path.trim_end_matches('/'));
+            let mut entries = fs::read_dir(&self.path).await.map_err(|e| io_status(&e))?;
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let (Ok(name), Ok(metadata)) = (
+                    entry.file_name().into_string(),
+                    fs::metadata(entry.path()).await,
+                ) else {
+                    continue;
+                };
+                resources.push((format!("{base}{name}"), entry.path(), metadata));
+            }
+        }
+
+        let mut responses = Vec::new();
+        for (url_path, path, metadata) in resources {
+            let properties = properties(self.config, &path, &metadata);
+            let propstats = match &find {
+                Find::All => vec![(
+                    HttpStatusCode::Ok,
+                    properties.into_iter().map(|p| p.1).collect(),
+                )],
+                Find::Names => vec![(
+                    HttpStatusCode::Ok,
+                    properties
+                        .iter()
+                        .map(|(name, _)| property_name("DAV:", name))
+                        .collect(),
+                )],
+                Find::Properties(names) => {
+                    let (mut found, mut missing) = (String::new(), String::new());
+                    for (ns, name) in names {
+                        match properties.iter().find(|p| ns == "DAV:" && p.0 == name) {
+                            Some((_, value)) => found.push_str(value),
+                            None => missing.push_str(&property_name(ns, name)),
+                        }
+                    }
+                    [
+                        (HttpStatusCode::Ok, found),
+                        (HttpStatusCode::NotFound, missing),
+                    ]
+                    .into_iter()
+                    .filter(|(_, properties)| !properties.is_empty())
+                    .collect()
+                }
+            };
+
+            let slash = if metadata.is_dir() && !url_path.ends_with('/') {
+                "/"
+            } else {
+                ""
+            };
+            let href = format!("{}{slash}", encode_path(&url_path));
+            responses.push(multistatus_response(&href, &propstats));
+        }
+
+        Ok(multistatus(&responses))
+    }
+
+    async fn proppatch(&self) -> Result<HttpMessage, HttpStatusCode> {
+        let body = std::str::from_utf8(self.body()).map_err(|_| HttpStatusCode::BadRequest)?;
+        let update = parse_xml(body)
+            .filter(|root| root.is("propertyupdate"))
+            .ok_or(HttpStatusCode::BadRequest)?;
+        if fs::symlink_metadata(&self.path).await.is_err() {
+            return Err(HttpStatusCode::NotFound);
+        }
+        self.check_locks(&self.path, false)?;
+
+        // Properties are only what the file system says, so none can be changed
+        let names: String = update
+            .children
+            .iter()
+            .filter(|child| child.is("set") || child.is("remove"))
+            .filter_map(|child| child.child("prop"))
+            .flat_map(|prop| &prop.children)
+            .map(|p| property_name(&p.ns, &p.name))
+            .collect();
+        let href = encode_path(&self.url_path);
+        Ok(multistatus(&[multistatus_response(
+            &href,
+            &[(HttpStatusCode::Forbidden, names)],
+        )]))
+    }
+
+    fn lock_response(lock: &Lock, status_code: HttpStatusCode) -> HttpMessage {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+            lock.to_xml()
+        );
+        let token = format!("<{}>", lock.token);
+        create_response(
+            status_code,
+            &[("Content-Type", MULTISTATUS_TYPE), ("Lock-Token", &token)],
+            Some(body.into_bytes().into()),
+            true,
+        )
+    }
+
+    async fn lock(&self) -> Result<HttpMessage, HttpStatusCode> {
+        let timeout = parse_timeout(self.field("timeout"));
+        let expires = Instant::now() + Duration::from_secs(timeout);
+
+        // Without a body, this refreshes a lock the client already has
+        if self.body().is_empty() {
+            let tokens = self.field("if").map(submitted_tokens).unwrap_or_default();
+            let mut locks = locks();
+            let lock = locks
+                .iter_mut()
+                .find(|lock| lock.covers(&self.path) && tokens.contains(&lock.token.as_str()))
+                .ok_or(HttpStatusCode::PreconditionFailed)?;
+            lock.timeout = timeout;
+            lock.expires = expires;
+            return Ok(Self::lock_response(lock, HttpStatusCode::Ok));
+        }
+
+        let body = std::str::from_utf8(self.body()).map_err(|_| HttpStatusCode::BadRequest)?;
+        let info = parse_xml(body)
+            .filter(|root| root.is("lockinfo"))
+            .ok_or(HttpStatusCode::BadRequest)?;
+        let exclusive = match info.child("lockscope") {
+            Some(scope) if scope.child("exclusive").is_some() => true,
+            Some(scope) if scope.child("shared").is_some() => false,
+            _ => return Err(HttpStatusCode::BadRequest),
+        };
+        if info
+            .child("locktype")
+            .and_then(|locktype| locktype.child("write"))
+            .is_none()
+        {
+            return Err(HttpStatusCode::BadRequest);
+        }
+        let owner = info.child("owner").map_or(String::new(), |owner| {
+            let children: String = owner.children.iter().map(Element::to_xml).collect();
+            escape_html(&owner.text) + &children
+        });
+
+        let infinite = match self.field("depth") {
+            None | Some("infinity") => true,
+            Some("0") => false,
+            Some(_) => return Err(HttpStatusCode::BadRequest),
+        };
+        if !self.path.parent().is_some_and(Path::is_dir) {
+            return Err(HttpStatusCode::Conflict);
+        }
+
+        let conflict = |locks: &[Lock]| {
+            locks.iter().any(|lock| {
+                (lock.covers(&self.path) || (infinite && lock.path.starts_with(&self.path)))
+                    && (exclusive || lock.exclusive)
+            })
+        };
+        if conflict(&locks()) {
+            return Err(HttpStatusCode::Locked);
+        }
+
+        // Locking a path that doesn't exist yet reserves it with an empty file
+        let mut status_code = HttpStatusCode::Ok;
+        if fs::symlink_metadata(&self.path).await.is_err() {
+            fs::File::create(&self.path)
+                .await
+                .map_err(|e| io_status(&e))?;
+            status_code = HttpStatusCode::Created;
+        }
+
+        // Someone else might have been quicker meanwhile
+        let mut locks = locks();
+        if conflict(&locks) {
+            return Err(HttpStatusCode::Locked);
+        }
+
+        let lock = Lock {
+            token: new_token(),
+            path: self.path.clone(),
+            root: self.url_path.clone(),
+            exclusive,
+            infinite,
+            owner,
+            timeout,
+            expires,
+        };
+        let response = Self::lock_response(&lock, status_code);
+        locks.push(lock);
+        Ok(response)
+    }
+
+    fn unlock(&self) -> Result<HttpMessage, HttpStatusCode> {
+        let token = self
+            .field("lock-token")
+            .and_then(|token| token.strip_prefix('<')?.strip_suffix('>'))
+            .ok_or(HttpStatusCode::BadRequest)?;
+
+        let mut locks = locks();
+        let i = locks
+            .iter()
+            .position(|lock| lock.token == token && lock.covers(&self.path))
+            .ok_or(HttpStatusCode::Conflict)?;
+        locks.remove(i);
+        Ok(create_response(HttpStatusCode::NoContent, &[], None, true))
+    }
+}
+
+/// Handles a request for a WebDAV path, except GET and HEAD requests,
+/// which are served like any other file (`None`).
+/// Returns true if the body of a request is to be handed to `handle_webdav` as it
+/// arrives, rather than read first, which is the case for uploads.
+pub fn streams_body(config: &Config, header: &HttpHeader) -> bool {
+    let request_line = header.request_line();
+    let Ok(target) = request_line.target.parse::<Target>() else {
+        return false;
+    };
+    let path = format!("/{}", target.path);
+
+    request_line.method == HttpMethod::Put
+        && config.webdav_root(&path).is_some()
+        && proxy::find_upstream(config, &path).is_none()
+}
+
+pub async fn handle_webdav(config: &Config, request: &mut HttpMessage) -> Option<HttpMessage> {
+    // The target is decoded again, more strictly, as it may be written to
+    let located = decode_path(&request.header.request_line().target)
+        .and_then(|url_path| Ok((fs_path(config, &url_path)?, url_path)));
+    let (path, url_path) = match located {
+        Ok(located) => located,
+        Err(status_code) => return Some(create_error_response(config, status_code).await),
+    };
+
+    // Uploads may be streamed, so they are read only once
+    let upload = match request.header.request_line().method {
+        HttpMethod::Put => request.body.take(),
+        _ => None,
+    };
+    let request = &*request;
+    let dav = Dav {
+        config,
+        request,
+        url_path,
+        path,
+    };
+
+    let result = match &request.header.request_line().method {
+        HttpMethod::Get | HttpMethod::Head => return None,
+        HttpMethod::Options => Ok(create_response(
+            HttpStatusCode::NoContent,
+            &[("Allow", METHODS), ("DAV", "1, 2")],
+            None,
+            false,
+        )),
+        HttpMethod::Put => dav.put(upload).await,
+        HttpMethod::Delete => dav.delete().await,
+        HttpMethod::Extension(method) => match method.as_str() {
+            "MKCOL" => dav.mkcol().await,
+            "COPY" => dav.copy_or_move(false).await,
+            "MOVE" => dav.copy_or_move(true).await,
+            "PROPFIND" => dav.propfind().await,
+            "PROPPATCH" => dav.proppatch().await,
+            "LOCK" => dav.lock().await,
+            "UNLOCK" => dav.unlock(),
+            _ => Err(HttpStatusCode::MethodNotAllowed),
+        },
+        _ => Err(HttpStatusCode::MethodNotAllowed),
+    };
+
+    Some(match result {
+        Ok(response) => response,
+        Err(status_code) => {
+            let mut response = create_error_response(config, status_code).await;
+            if status_code == HttpStatusCode::MethodNotAllowed {
+                response
+                    .header
+                    .field_lines
+                    .insert(String::from("Allow"), String::from(METHODS));
+            }
+            response
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xml() {
+        let xml = "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n\
+                   <D:lockinfo xmlns:D='DAV:'>\n\
+                     <D:lockscope><D:exclusive/></D:lockscope>\n\
+                     <!-- comment -->\n\
+                     <D:owner><href xmlns=\"DAV:\">mailto:a&amp;b@example.com</href></D:owner>\n\
+                     <x:custom xmlns:x=\"urn:x\" attr=\"a>b\"/>\n\
+                   </D:lockinfo>";
+        let root = parse_xml(xml).unwrap();
+        assert!(root.is("lockinfo"));
+        assert!(root
+            .child("lockscope")
+            .unwrap()
+            .child("exclusive")
+            .is_some());
+
+        let owner = root.child("owner").unwrap();
+        assert_eq!(owner.children[0].text, "mailto:a&b@example.com");
+        assert_eq!(
+            owner.children[0].to_xml(),
+            "<href xmlns=\"DAV:\">mailto:a&amp;b@example.com</href>"
+        );
+        assert_eq!(root.children[2].ns, "urn:x");
+        assert_eq!(root.children[2].name, "custom");
+
+        // Unbound prefixes, unclosed elements, entities and trailing junk
+        assert!(parse_xml("<D:propfind/>").is_none());
+        assert!(parse_xml("<propfind><prop></propfind>").is_none());
+        assert!(parse_xml("<!DOCTYPE x [<!ENTITY a \"b\">]><x>&a;</x>").is_none());
+        assert!(parse_xml("<x/><y/>").is_none());
+    }
+
+    #[test]
+    fn test_submitted_tokens() {
+        let value = "</dav/a> (<opaquelocktoken:1234> [\"etag\"]) (Not <DAV:no-lock>) \
+                     (<opaquelocktoken:5678>)";
+        assert_eq!(
+            submitted_tokens(value),
+            ["opaquelocktoken:1234", "opaquelocktoken:5678"]
+        );
+        assert!(new_token().starts_with("opaquelocktoken:"));
+        assert_ne!(new_token(), new_token());
+    }
+
+    #[test]
+    fn test_decode_path() {
+        assert_eq!(
+            decode_path("/dav/a%20b.txt"),
+            Ok(String::from("/dav/a b.txt"))
+        );
+        assert_eq!(decode_path("/dav/dir/?x=1"), Ok(String::from("/dav/dir/")));
+        assert_eq!(
+            decode_path("https://example.com/dav/a"),
+            Ok(String::from("/dav/a"))
+        );
+
+        for target in [
+            "/dav/..%2f..%2fsecret",
+            "/dav/%2f../secret",
+            "/dav/%2e%2e/secret",
+            "/dav/./a",
+            "/dav//a",
+            "/dav/a%5cb",
+            "/dav/a%00",
+            "dav/a",
+        ] {
+            assert_eq!(
+                decode_path(target),
+                Err(HttpStatusCode::BadRequest),
+                "{target}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traversal() {
+        let mut config = Config::default();
+        config.webdav.insert(String::from("/dav"), true);
+        let new_request = |target: &str, destination: &str| HttpMessage {
+            header: format!(
+                "MOVE {target} HTTP/1.1\r\nHost: example.com\r\nDestination: {destination}\r\n"
+            )
+            .parse()
+            .unwrap(),
+            body: None,
+        };
+        let dav = |request| Dav {
+            config: &config,
+            request,
+            url_path: String::from("/dav/a"),
+            path: PathBuf::new(),
+        };
+
+        assert!(fs_path(&config, "/dav/a").is_ok());
+        assert_eq!(fs_path(&config, "/other"), Err(HttpStatusCode::Forbidden));
+        assert_eq!(
+            fs_path(&config, "/dav/../x"),
+            Err(HttpStatusCode::Forbidden)
+        );
+
+        let request = new_request("/dav/a", "http://example.com/dav/%2f..%2f..%2fsecret");
+        assert_eq!(dav(&request).destination(), Err(HttpStatusCode::BadRequest));
+        let request = new_request("/dav/a", "/dav/b");
+        assert_eq!(dav(&request).destination(), Ok(String::from("/dav/b")));
+
+        let request = new_request("/dav/%2f..%2f..%2fsecret", "/dav/b");
+        let mut request = request;
+        let response = handle_webdav(&config, &mut request).await.unwrap();
+        assert_eq!(
+            response.header.status_line().status_code,
+            HttpStatusCode::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(None), LOCK_TIMEOUT);
+        assert_eq!(parse_timeout(Some("Second-60")), 60);
+        assert_eq!(parse_timeout(Some("Infinite, Second-60")), MAX_LOCK_TIMEOUT);
+        assert_eq!(parse_timeout(Some("Second-99999")), MAX_LOCK_TIMEOUT);
+        assert_eq!(parse_timeout(Some("Minute-5")), LOCK_TIMEOUT);
+    }
+}
@@ -0,0 +1,319 @@
+//! This does just enough X.509 certificate parsing (RFC 5280) to describe
+//! client certificates to CGI scripts
+//!
+//! Nothing here is verified, that is left to rustls.
+
+/// What we tell scripts about a certificate.
+#[derive(Debug, Default)]
+pub struct CertInfo {
+    /// Distinguished names in RFC 4514 format (e.g. `CN=Alice,O=Example,C=NL`).
+    pub subject: String,
+    pub issuer: String,
+
+    /// Subject common name, if any.
+    pub common_name: Option<String>,
+
+    /// Serial number in uppercase hex.
+    pub serial: String,
+
+    /// Subject alternative names.
+    pub dns_names: Vec<String>,
+    pub emails: Vec<String>,
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+const BOOLEAN: u8 = 0x01;
+const VERSION: u8 = 0xA0;
+const EXTENSIONS: u8 = 0xA3;
+
+// Tags of general names within a subject alternative name
+const SAN_EMAIL: u8 = 0x81;
+const SAN_DNS: u8 = 0x82;
+
+const OID_SUBJECT_ALT_NAME: &str = "2.5.29.17";
+const OID_COMMON_NAME: &str = "2.5.4.3";
+
+/// Short names of the attributes commonly found in distinguished names.
+const ATTRIBUTE_NAMES: &[(&str, &str)] = &[
+    (OID_COMMON_NAME, "CN"),
+    ("2.5.4.5", "serialNumber"),
+    ("2.5.4.6", "C"),
+    ("2.5.4.7", "L"),
+    ("2.5.4.8", "ST"),
+    ("2.5.4.9", "STREET"),
+    ("2.5.4.10", "O"),
+    ("2.5.4.11", "OU"),
+    ("0.9.2342.19200300.100.1.1", "UID"),
+    ("0.9.2342.19200300.100.1.25", "DC"),
+    ("1.2.840.113549.1.9.1", "emailAddress"),
+];
+
+/// Splits off the first DER element of `data`, returning its tag, contents and whatever follows.
+fn read_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        // Long form, with the length in the next few bytes
+        let num_bytes = (first & 0x7F) as usize;
+        if num_bytes == 0 || num_bytes > 4 {
+            return None;
+        }
+        let (bytes, after) = rest.split_at_checked(num_bytes)?;
+        rest = after;
+        bytes.iter().fold(0, |len, &b| len << 8 | b as usize)
+    };
+
+    let (contents, rest) = rest.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// Like `read_element`, but fails unless the element has the given tag.
+fn expect_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match read_element(data)? {
+        (t, contents, rest) if t == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Returns all elements of a SEQUENCE or SET's contents.
+fn elements(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let (tag, contents, rest) = read_element(data)?;
+        elements.push((tag, contents));
+        data = rest;
+    }
+    Some(elements)
+}
+
+/// Formats an object identifier in dotted form.
+fn decode_oid(data: &[u8]) -> Option<String> {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for &b in data {
+        arc = arc.checked_mul(128)? | (b & 0x7F) as u64;
+        if b & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+
+    // First two arcs are packed into one
+    let first = *arcs.first()?;
+    let (a, b) = match first {
+        0..40 => (0, first),
+        40..80 => (1, first - 40),
+        _ => (2, first - 80),
+    };
+
+    let mut oid = format!("{a}.{b}");
+    for arc in &arcs[1..] {
+        oid.push_str(&format!(".{arc}"));
+    }
+    Some(oid)
+}
+
+/// Decodes any of the string types used in certificates.
+fn decode_string(tag: u8, data: &[u8]) -> String {
+    match tag {
+        // BMPString (UTF-16)
+        0x1E => {
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+
+        // UTF8String, PrintableString, IA5String and the like
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+/// Escapes an attribute value as per RFC 4514, section 2.4.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == value.chars().count() - 1 && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Formats a Name as an RFC 4514 string, also returning its common name.
+fn decode_name(data: &[u8]) -> Option<(String, Option<String>)> {
+    let mut rdns = Vec::new();
+    let mut common_name = None;
+
+    for (tag, rdn) in elements(data)? {
+        if tag != SET {
+            return None;
+        }
+
+        let mut attributes = Vec::new();
+        for (tag, attribute) in elements(rdn)? {
+            if tag != SEQUENCE {
+                return None;
+            }
+            let (oid, rest) = expect_element(attribute, OID)?;
+            let (value_tag, value, _) = read_element(rest)?;
+
+            let oid = decode_oid(oid)?;
+            let value = decode_string(value_tag, value);
+            if oid == OID_COMMON_NAME {
+                common_name = Some(value.clone());
+            }
+
+            let name = ATTRIBUTE_NAMES
+                .iter()
+                .find(|(o, _)| *o == oid)
+                .map_or(oid.as_str(), |(_, name)| name);
+            attributes.push(format!("{name}={}", escape_value(&value)));
+        }
+        rdns.push(attributes.join("+"));
+    }
+
+    // Most significant RDN (e.g. the country) comes last
+    rdns.reverse();
+    Some((rdns.join(","), common_name))
+}
+
+/// Collects the DNS names and email addresses of a SubjectAltName extension.
+fn decode_alt_names(data: &[u8], info: &mut CertInfo) -> Option<()> {
+    let (names, _) = expect_element(data, SEQUENCE)?;
+    for (tag, name) in elements(names)? {
+        match tag {
+            SAN_DNS => info
+                .dns_names
+                .push(String::from_utf8_lossy(name).into_owned()),
+            SAN_EMAIL => info.emails.push(String::from_utf8_lossy(name).into_owned()),
+            _ => (),
+        }
+    }
+    Some(())
+}
+
+/// Parses a DER encoded certificate, returning `None` if malformed.
+pub fn parse(der: &[u8]) -> Option<CertInfo> {
+    let (cert, _) = expect_element(der, SEQUENCE)?;
+    let (tbs, _) = expect_element(cert, SEQUENCE)?;
+
+    // Version is optional (defaulting to v1)
+    let mut rest = tbs;
+    if let Some((VERSION, _, after)) = read_element(rest) {
+        rest = after;
+    }
+
+    let (serial, rest) = expect_element(rest, INTEGER)?;
+    let (_signature, rest) = expect_element(rest, SEQUENCE)?;
+    let (issuer, rest) = expect_element(rest, SEQUENCE)?;
+    let (_validity, rest) = expect_element(rest, SEQUENCE)?;
+    let (subject, rest) = expect_element(rest, SEQUENCE)?;
+    let (_public_key, mut rest) = expect_element(rest, SEQUENCE)?;
+
+    let (subject, common_name) = decode_name(subject)?;
+    let (issuer, _) = decode_name(issuer)?;
+
+    // Leading zero only keeps the integer positive
+    let serial = match serial {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        serial => serial,
+    };
+
+    let mut info = CertInfo {
+        subject,
+        issuer,
+        common_name,
+        serial: serial.iter().map(|b| format!("{b:02X}")).collect(),
+        ..CertInfo::default()
+    };
+
+    // Skip unique identifiers until we get to the extensions (v3 only)
+    while let Some((tag, contents, after)) = read_element(rest) {
+        rest = after;
+        if tag != EXTENSIONS {
+            continue;
+        }
+
+        let (extensions, _) = expect_element(contents, SEQUENCE)?;
+        for (_, extension) in elements(extensions)? {
+            let (oid, mut value) = expect_element(extension, OID)?;
+            if let Some((BOOLEAN, _, after)) = read_element(value) {
+                value = after;
+            }
+            let (value, _) = expect_element(value, OCTET_STRING)?;
+
+            if decode_oid(oid)? == OID_SUBJECT_ALT_NAME {
+                decode_alt_names(value, &mut info)?;
+            }
+        }
+    }
+
+    Some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls;
+
+    /// Issued to "CN=Alice\, Admin,OU=Admins+OU=Ops,O=Helios Test,C=NL",
+    /// with alternative names alice.example.com, alice@example.com and 10.0.0.1.
+    const ALICE: &str = "-----BEGIN CERTIFICATE-----
+MIIB/jCCAaWgAwIBAgIFEzfA/+4wCgYIKoZIzj0EAwIwPDELMAkGA1UEBhMCTkwx
+FDASBgNVBAoMC0hlbGlvcyBUZXN0MRcwFQYDVQQDDA5IZWxpb3MgVGVzdCBDQTAe
+Fw0yNjEwMTcwMjM4MjFaFw0zNjEwMTQwMjM4MjFaMFcxCzAJBgNVBAYTAk5MMRQw
+EgYDVQQKDAtIZWxpb3MgVGVzdDEbMAoGA1UECwwDT3BzMA0GA1UECwwGQWRtaW5z
+MRUwEwYDVQQDDAxBbGljZSwgQWRtaW4wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNC
+AARriKiq/7pGVPPIor+00ANJS2kmx8Q4uA+0FQOyNeu7KucKICPxFKSKt8KhLJKO
+yH6EE7ZRKzQHF4Hx0Sttk3Zpo3kwdzA1BgNVHREELjAsghFhbGljZS5leGFtcGxl
+LmNvbYERYWxpY2VAZXhhbXBsZS5jb22HBAoAAAEwHQYDVR0OBBYEFP/ArIqivndh
+sjGMLNdAbBCPg+XVMB8GA1UdIwQYMBaAFKLwza/TTEzg2eK9V9OfWvzOWt5UMAoG
+CCqGSM49BAMCA0cAMEQCIAoZm9LoG6rsgLjmftBeVcgdUAFKQdJOElAlskeyGfOX
+AiB490gFeYNTnCuXJ+sEYkckdncEubsSbyIxMxoPvYD15A==
+-----END CERTIFICATE-----";
+
+    #[test]
+    fn test_parse() {
+        let der = CertificateDer::from_pem_slice(ALICE.as_bytes()).unwrap();
+        let info = parse(&der).unwrap();
+
+        assert_eq!(
+            info.subject,
+            "CN=Alice\\, Admin,OU=Ops+OU=Admins,O=Helios Test,C=NL"
+        );
+        assert_eq!(info.common_name.as_deref(), Some("Alice, Admin"));
+        assert_eq!(info.issuer, "CN=Helios Test CA,O=Helios Test,C=NL");
+        assert_eq!(info.serial, "1337C0FFEE");
+        assert_eq!(info.dns_names, ["alice.example.com"]);
+        assert_eq!(info.emails, ["alice@example.com"]);
+
+        // Truncated
+        assert!(parse(&der[..der.len() / 2]).is_none());
+        assert!(parse(b"Hack the planet!").is_none());
+    }
+
+    #[test]
+    fn test_decode_oid() {
+        assert_eq!(decode_oid(&[0x55, 0x04, 0x03]).as_deref(), Some("2.5.4.3"));
+        assert_eq!(
+            decode_oid(&[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x01]).as_deref(),
+            Some("1.2.840.113549.1.9.1")
+        );
+        assert_eq!(decode_oid(&[]), None);
+    }
+}